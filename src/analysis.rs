@@ -0,0 +1,256 @@
+use instruction::*;
+use interconnect::*;
+
+use std::collections::BTreeMap;
+
+pub const RESET_VECTOR: u32 = 0xfffffff0;
+
+pub const INTERRUPT_VECTORS: [u32; 12] = [
+    0xfffffe00, // Game pad
+    0xfffffe10, // Timer
+    0xfffffe20, // Game pak
+    0xfffffe30, // Communication
+    0xfffffe40, // VIP
+    0xffffff60, // Floating-point exception
+    0xffffff80, // Zero division
+    0xffffff90, // Illegal opcode
+    0xffffffa0, // TRAP 0-15
+    0xffffffb0, // TRAP 16-31
+    0xffffffc0, // Address trap
+    0xffffffd0, // Duplexed exception
+];
+
+const ROM_BASE: u32 = 0x07000000;
+
+// What unused ROM is filled with.
+const ERASED_HALFWORD: u16 = 0xffff;
+
+// Values the analyser knows registers to hold along one path, which lets it
+// resolve the `movhi`/`movea`/`jmp [reg]` sequences used for far jumps.
+type KnownRegs = [Option<u32>; 32];
+
+pub struct CodeMap {
+    rom_mask: u32,
+    instructions: BTreeMap<u32, Instruction>,
+}
+
+impl CodeMap {
+    // Games leave the vectors of interrupts they never enable as fill, which
+    // would otherwise be disassembled as code, so only vectors holding
+    // something are followed.
+    pub fn analyse(interconnect: &Interconnect) -> CodeMap {
+        let mut entry_points = vec![RESET_VECTOR];
+        entry_points.extend(INTERRUPT_VECTORS.iter()
+            .filter(|&&addr| interconnect.peek_halfword(addr) != ERASED_HALFWORD));
+
+        CodeMap::analyse_from(interconnect, &entry_points)
+    }
+
+    pub fn analyse_from(interconnect: &Interconnect, entry_points: &[u32]) -> CodeMap {
+        let mut code_map = CodeMap {
+            rom_mask: (interconnect.rom().size() - 1) as u32,
+            instructions: BTreeMap::new(),
+        };
+
+        let mut pending = entry_points.iter()
+            .filter(|&&addr| is_rom_addr(addr))
+            .map(|&addr| (code_map.canonical(addr), unknown_regs()))
+            .collect::<Vec<_>>();
+
+        while let Some((addr, regs)) = pending.pop() {
+            code_map.follow(interconnect, addr, regs, &mut pending);
+        }
+
+        code_map
+    }
+
+    // Is the byte at addr part of an instruction reached from an entry point?
+    pub fn is_code(&self, addr: u32) -> bool {
+        if !is_rom_addr(addr) {
+            return false;
+        }
+
+        let addr = self.canonical(addr) & !1;
+
+        self.instructions.contains_key(&addr) ||
+            self.instructions.get(&addr.wrapping_sub(2)).is_some_and(|i| i.length == 4)
+    }
+
+    // Code is only tracked in ROM; anything else may have been put there at
+    // run time, so it's never considered data.
+    pub fn is_data(&self, addr: u32) -> bool {
+        is_rom_addr(addr) && !self.is_code(addr)
+    }
+
+    pub fn instructions(&self) -> &BTreeMap<u32, Instruction> {
+        &self.instructions
+    }
+
+    fn canonical(&self, addr: u32) -> u32 {
+        ROM_BASE | (addr & self.rom_mask)
+    }
+
+    fn follow(&mut self, interconnect: &Interconnect, mut addr: u32, mut regs: KnownRegs, pending: &mut Vec<(u32, KnownRegs)>) {
+        loop {
+            if self.instructions.contains_key(&addr) {
+                return;
+            }
+
+            let instruction = match decode(addr, interconnect) {
+                Ok(instruction) => instruction,
+                Err(_) => return,
+            };
+
+            self.instructions.insert(addr, instruction);
+
+            let next_addr = self.canonical(instruction.next_addr());
+
+            match instruction.opcode {
+                Opcode::Bcond(Condition::T) | Opcode::Jr => {
+                    addr = self.canonical(instruction.branch_target().unwrap());
+                }
+                Opcode::Bcond(Condition::F) => {
+                    addr = next_addr;
+                }
+                Opcode::Bcond(_) => {
+                    pending.push((self.canonical(instruction.branch_target().unwrap()), regs));
+                    addr = next_addr;
+                }
+                Opcode::Jal => {
+                    pending.push((self.canonical(instruction.branch_target().unwrap()), unknown_regs()));
+
+                    // The callee may clobber anything.
+                    regs = unknown_regs();
+                    addr = next_addr;
+                }
+                Opcode::Jmp => {
+                    if let Operands::I { reg1, .. } = instruction.operands {
+                        if let Some(target) = regs[reg1] {
+                            if is_rom_addr(target) {
+                                pending.push((self.canonical(target), regs));
+                            }
+                        }
+                    }
+                    return;
+                }
+                Opcode::Reti => return,
+                Opcode::Trap => {
+                    regs = unknown_regs();
+                    addr = next_addr;
+                }
+                _ => {
+                    track_registers(&mut regs, &instruction);
+                    addr = next_addr;
+                }
+            }
+        }
+    }
+}
+
+fn is_rom_addr(addr: u32) -> bool {
+    addr & 0x07ffffff >= ROM_BASE
+}
+
+fn unknown_regs() -> KnownRegs {
+    let mut regs = [None; 32];
+    regs[0] = Some(0);
+    regs
+}
+
+fn track_registers(regs: &mut KnownRegs, instruction: &Instruction) {
+    match (instruction.opcode, instruction.operands) {
+        (Opcode::MovImm, Operands::II { imm5, reg2 }) => {
+            regs[reg2] = Some(sign_extend_imm5(imm5));
+        }
+        (Opcode::MovReg, Operands::I { reg1, reg2 }) => {
+            regs[reg2] = regs[reg1];
+        }
+        (Opcode::Movhi, Operands::V { reg1, reg2, imm16 }) => {
+            regs[reg2] = regs[reg1].map(|value| value.wrapping_add((imm16 as u32) << 16));
+        }
+        (Opcode::Movea, Operands::V { reg1, reg2, imm16 }) |
+        (Opcode::Addi, Operands::V { reg1, reg2, imm16 }) => {
+            regs[reg2] = regs[reg1].map(|value| value.wrapping_add((imm16 as i16) as u32));
+        }
+        (Opcode::Ori, Operands::V { reg1, reg2, imm16 }) => {
+            regs[reg2] = regs[reg1].map(|value| value | imm16 as u32);
+        }
+        (Opcode::Cmp, _) | (Opcode::CmpImm, _) | (Opcode::Cli, _) | (Opcode::Sei, _) |
+        (Opcode::Ldsr, _) | (Opcode::Halt, _) |
+        (Opcode::Stb, _) | (Opcode::Sth, _) | (Opcode::Stw, _) |
+        (Opcode::Outb, _) | (Opcode::Outh, _) | (Opcode::Outw, _) => (),
+        (opcode, _) if is_bit_string(opcode) => {
+            for reg in regs[26..31].iter_mut() {
+                *reg = None;
+            }
+        }
+        (Opcode::Mul, Operands::I { reg2, .. }) | (Opcode::Mulu, Operands::I { reg2, .. }) |
+        (Opcode::Div, Operands::I { reg2, .. }) | (Opcode::Divu, Operands::I { reg2, .. }) => {
+            regs[reg2] = None;
+            regs[30] = None;
+        }
+        (_, Operands::I { reg2, .. }) |
+        (_, Operands::II { reg2, .. }) |
+        (_, Operands::V { reg2, .. }) |
+        (_, Operands::VI { reg2, .. }) |
+        (_, Operands::VII { reg2, .. }) => {
+            regs[reg2] = None;
+        }
+        _ => (),
+    }
+
+    regs[0] = Some(0);
+}
+
+fn is_bit_string(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::Sch0bsu | Opcode::Sch0bsd | Opcode::Sch1bsu | Opcode::Sch1bsd |
+        Opcode::Orbsu | Opcode::Andbsu | Opcode::Xorbsu | Opcode::Movbsu |
+        Opcode::Ornbsu | Opcode::Andnbsu | Opcode::Xornbsu | Opcode::Notbsu
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM_SIZE: usize = 1024;
+
+    fn put(rom: &mut [u8], addr: u32, opcode: Opcode, operands: Operands) {
+        let offset = (addr as usize) & (ROM_SIZE - 1);
+        let (first_halfword, second_halfword) = encode(opcode, operands);
+        rom[offset..offset + 2].copy_from_slice(&first_halfword.to_le_bytes());
+        if let Some(second_halfword) = second_halfword {
+            rom[offset + 2..offset + 4].copy_from_slice(&second_halfword.to_le_bytes());
+        }
+    }
+
+    // A blank ROM whose reset vector spins and whose TRAP 0-15 handler
+    // returns straight away; every other vector is left as fill.
+    fn interconnect() -> Interconnect {
+        let mut rom = vec![0xff; ROM_SIZE];
+        put(&mut rom, RESET_VECTOR, Opcode::Jr, Operands::IV { disp26: 0 });
+        put(&mut rom, 0xffffffa0, Opcode::Reti, Operands::II { imm5: 0, reg2: 0 });
+        Interconnect::with_rom_bytes(rom)
+    }
+
+    #[test]
+    fn filled_vectors_are_not_followed() {
+        let code_map = CodeMap::analyse(&interconnect());
+
+        assert!(code_map.is_code(RESET_VECTOR));
+        assert!(code_map.is_code(0xffffffa0));
+        for &addr in INTERRUPT_VECTORS.iter().filter(|&&addr| addr != 0xffffffa0) {
+            assert!(code_map.is_data(addr), "0x{:08x}", addr);
+        }
+        assert_eq!(code_map.instructions().len(), 2);
+    }
+
+    #[test]
+    fn explicit_entry_points_are_always_followed() {
+        let code_map = CodeMap::analyse_from(&interconnect(), &[0xfffffe00]);
+        assert!(code_map.is_code(0xfffffe00));
+        assert!(code_map.is_data(RESET_VECTOR));
+    }
+}
@@ -0,0 +1,161 @@
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use std::io::{self, Read, Cursor, Error, ErrorKind};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+const ROM_EXTENSION: &str = ".vb";
+
+pub fn is_gzip(bytes: &[u8]) -> bool {
+    bytes.starts_with(GZIP_MAGIC)
+}
+
+pub fn is_zip(bytes: &[u8]) -> bool {
+    bytes.starts_with(ZIP_MAGIC)
+}
+
+// At most max_size + 1 bytes are inflated, which is enough for the size
+// check to reject an oversized ROM without unpacking all of it.
+pub fn gunzip(bytes: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(bytes).take(max_size as u64 + 1).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+// Extracts the named entry, or without a name the first one ending in .vb.
+// Returns None if there's no such entry.
+pub fn unzip(bytes: &[u8], entry_name: Option<&str>, max_size: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+
+    let index = match entry_name {
+        Some(name) => archive.index_for_name(name),
+        None => (0..archive.len()).find(|&index| {
+            archive.name_for_index(index).is_some_and(|name| name.to_ascii_lowercase().ends_with(ROM_EXTENSION))
+        }),
+    };
+    let index = match index {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+
+    let entry = archive.by_index(index)?;
+    if entry.is_dir() {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("'{}' is a directory", entry.name())));
+    }
+
+    let mut decompressed = Vec::new();
+    entry.take(max_size as u64 + 1).read_to_end(&mut decompressed)?;
+    Ok(Some(decompressed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rom::*;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use zip::{ZipWriter, CompressionMethod};
+    use zip::write::SimpleFileOptions;
+
+    use std::io::Write;
+    use std::fs::{self, File};
+    use std::env;
+    use std::process;
+
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        for &(name, bytes) in entries {
+            if name.ends_with('/') {
+                writer.add_directory(name, options).unwrap();
+            } else {
+                writer.start_file(name, options).unwrap();
+                writer.write_all(bytes).unwrap();
+            }
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn archives_are_told_by_their_magic() {
+        let zipped = zip(&[("game.vb", b"rom")]);
+        assert!(is_zip(&zipped));
+        assert!(!is_gzip(&zipped));
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"rom").unwrap();
+        let gzipped = encoder.finish().unwrap();
+        assert!(is_gzip(&gzipped));
+        assert!(!is_zip(&gzipped));
+
+        assert!(!is_zip(b"rom") && !is_gzip(b"rom"));
+    }
+
+    #[test]
+    fn gunzip_stops_past_the_maximum_size() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0; 64]).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        assert_eq!(gunzip(&gzipped, 64).unwrap().len(), 64);
+        assert_eq!(gunzip(&gzipped, 16).unwrap().len(), 17);
+    }
+
+    #[test]
+    fn first_vb_entry_is_found() {
+        let zipped = zip(&[("readme.txt", b"text"), ("dir/", b""), ("dir/GAME.VB", b"rom"), ("other.vb", b"other")]);
+        assert_eq!(unzip(&zipped, None, 1024).unwrap(), Some(b"rom".to_vec()));
+    }
+
+    #[test]
+    fn zip_without_a_vb_entry_has_no_rom() {
+        let zipped = zip(&[("readme.txt", b"text"), ("game.vb.txt", b"not a rom")]);
+        assert_eq!(unzip(&zipped, None, 1024).unwrap(), None);
+    }
+
+    #[test]
+    fn named_entries_are_extracted() {
+        let zipped = zip(&[("game.vb", b"rom"), ("patched.bin", b"patched")]);
+        assert_eq!(unzip(&zipped, Some("patched.bin"), 1024).unwrap(), Some(b"patched".to_vec()));
+    }
+
+    #[test]
+    fn missing_named_entry_has_no_rom() {
+        let zipped = zip(&[("game.vb", b"rom")]);
+        assert_eq!(unzip(&zipped, Some("missing.vb"), 1024).unwrap(), None);
+    }
+
+    #[test]
+    fn named_directories_are_rejected() {
+        let zipped = zip(&[("dir/", b""), ("dir/game.vb", b"rom")]);
+        assert_eq!(unzip(&zipped, Some("dir/"), 1024).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn corrupt_zips_are_rejected() {
+        let zipped = zip(&[("game.vb", b"rom")]);
+        assert!(unzip(&zipped[..zipped.len() / 2], None, 1024).is_err());
+    }
+
+    #[test]
+    fn loading_reports_what_the_archive_is_missing() {
+        let path = env::temp_dir().join(format!("avb-archive-test-{}.zip", process::id()));
+        File::create(&path).unwrap().write_all(&zip(&[("readme.txt", b"text")])).unwrap();
+
+        let no_rom = Rom::load(&path);
+        let no_entry = Rom::load_entry(&path, Some("game.vb"));
+        fs::remove_file(&path).unwrap();
+
+        match no_rom {
+            Err(RomError::NoRomInArchive) => (),
+            _ => panic!("a zip without a .vb file loaded"),
+        }
+        match no_entry {
+            Err(RomError::EntryNotFound(name)) => assert_eq!(name, "game.vb"),
+            _ => panic!("a missing entry loaded"),
+        }
+    }
+}
@@ -0,0 +1,985 @@
+use instruction::*;
+
+use nom::{IResult, eof, space, digit, hex_digit};
+
+use std::fmt;
+use std::str::{self, FromStr};
+use std::collections::HashMap;
+
+const ROM_BASE: u32 = 0x07000000;
+const MIN_ROM_SIZE: u64 = 0x400;
+const MAX_ROM_SIZE: u64 = 16777216;
+
+// Header, plus the interrupt and reset vectors above it.
+const HEADER_AREA_SIZE: u64 = 0x220;
+const RESET_VECTOR: u32 = 0xfffffff0;
+
+const DEFAULT_TITLE: &[u8] = b"AURORA VB TEST      ";
+const DEFAULT_MAKER_CODE: &[u8] = b"AV";
+const DEFAULT_GAME_CODE: &[u8] = b"AVBT";
+
+const OPCODES: [Opcode; 75] = [
+    Opcode::MovReg, Opcode::Add, Opcode::Sub, Opcode::Cmp, Opcode::Shl, Opcode::Shr,
+    Opcode::Jmp, Opcode::Sar, Opcode::Mul, Opcode::Div, Opcode::Mulu, Opcode::Divu,
+    Opcode::Or, Opcode::And, Opcode::Xor, Opcode::Not,
+    Opcode::MovImm, Opcode::AddImm, Opcode::Setf, Opcode::CmpImm, Opcode::ShlImm,
+    Opcode::ShrImm, Opcode::Cli, Opcode::SarImm, Opcode::Trap, Opcode::Reti, Opcode::Halt,
+    Opcode::Ldsr, Opcode::Stsr, Opcode::Sei,
+    Opcode::Sch0bsu, Opcode::Sch0bsd, Opcode::Sch1bsu, Opcode::Sch1bsd, Opcode::Orbsu,
+    Opcode::Andbsu, Opcode::Xorbsu, Opcode::Movbsu, Opcode::Ornbsu, Opcode::Andnbsu,
+    Opcode::Xornbsu, Opcode::Notbsu,
+    Opcode::Movea, Opcode::Addi, Opcode::Jr, Opcode::Jal, Opcode::Ori, Opcode::Andi,
+    Opcode::Xori, Opcode::Movhi,
+    Opcode::Ldb, Opcode::Ldh, Opcode::Ldw, Opcode::Stb, Opcode::Sth, Opcode::Stw,
+    Opcode::Inb, Opcode::Inh, Opcode::Caxi, Opcode::Inw, Opcode::Outb, Opcode::Outh,
+    Opcode::Outw,
+    Opcode::Cmpf, Opcode::Cvtws, Opcode::Cvtsw, Opcode::Addf, Opcode::Subf, Opcode::Mulf,
+    Opcode::Divf, Opcode::Xb, Opcode::Xh, Opcode::Rev, Opcode::Trnc, Opcode::Mpyhw,
+];
+
+#[derive(Debug)]
+pub struct AssemblerError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "Line {}: {}", self.line, self.message)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String),
+    Hi(Box<Expr>),
+    Lo(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, labels: &HashMap<String, u32>) -> Result<i64, String> {
+        match *self {
+            Expr::Number(value) => Ok(value),
+            Expr::Symbol(ref name) => labels.get(name)
+                .map(|&addr| addr as i64)
+                .ok_or_else(|| format!("Unknown label: {}", name)),
+            // movea sign extends, so hi() rounds up when lo() will come out negative.
+            Expr::Hi(ref expr) => {
+                let value = expr.eval(labels)?;
+                value.checked_add(0x8000)
+                    .map(|value| (value >> 16) & 0xffff)
+                    .ok_or_else(|| format!("Value {} overflows hi()", value))
+            }
+            Expr::Lo(ref expr) => expr.eval(labels).map(|value| ((value & 0xffff) as u16 as i16) as i64),
+            Expr::Add(ref lhs, ref rhs) => {
+                let (lhs, rhs) = (lhs.eval(labels)?, rhs.eval(labels)?);
+                lhs.checked_add(rhs).ok_or_else(|| format!("{} + {} overflows", lhs, rhs))
+            }
+            Expr::Sub(ref lhs, ref rhs) => {
+                let (lhs, rhs) = (lhs.eval(labels)?, rhs.eval(labels)?);
+                lhs.checked_sub(rhs).ok_or_else(|| format!("{} - {} overflows", lhs, rhs))
+            }
+        }
+    }
+
+    fn symbol(&self) -> Option<&str> {
+        match *self {
+            Expr::Symbol(ref name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Expr(Expr),
+    Indirect(Option<Expr>, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Org(Expr),
+    Word(Vec<Expr>),
+    Halfword(Vec<Expr>),
+    Byte(Vec<Expr>),
+    Ascii(Vec<u8>),
+    Instruction(String, Vec<Operand>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub labels: Vec<String>,
+    pub statement: Option<Statement>,
+}
+
+impl FromStr for Line {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match line(s.as_bytes()) {
+            IResult::Done(_, l) => Ok(l),
+            _ => Err(format!("Syntax error: {}", s.trim())),
+        }
+    }
+}
+
+// Assembles a complete program into a ROM image, adding a header and a reset
+// vector jumping to `start` (or the lowest address) if the source has none.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssemblerError> {
+    let mut lines = Vec::new();
+    for (index, text) in source.lines().enumerate() {
+        let parsed = text.parse::<Line>().map_err(|message| AssemblerError { line: index + 1, message })?;
+        lines.push((index + 1, parsed));
+    }
+
+    let mut labels = HashMap::new();
+    let mut addr = ROM_BASE;
+
+    for &(line_number, ref line) in lines.iter() {
+        let error = |message| AssemblerError { line: line_number, message };
+
+        for name in line.labels.iter() {
+            if labels.insert(name.clone(), addr).is_some() {
+                return Err(error(format!("Label defined twice: {}", name)));
+            }
+        }
+
+        if let Some(ref statement) = line.statement {
+            addr = match *statement {
+                Statement::Org(ref expr) => expr.eval(&labels).map_err(error)? as u32,
+                _ => addr.wrapping_add(statement_len(statement).map_err(error)?),
+            };
+        }
+    }
+
+    let mut chunks = Vec::new();
+    let mut addr = ROM_BASE;
+
+    for &(line_number, ref line) in lines.iter() {
+        let error = |message| AssemblerError { line: line_number, message };
+
+        if let Some(ref statement) = line.statement {
+            if let Statement::Org(ref expr) = *statement {
+                addr = expr.eval(&labels).map_err(error)? as u32;
+                continue;
+            }
+
+            let bytes = assemble_statement(statement, addr, &labels).map_err(error)?;
+            let len = bytes.len() as u32;
+            chunks.push((addr, bytes));
+            addr = addr.wrapping_add(len);
+        }
+    }
+
+    let entry = labels.get("start")
+        .or_else(|| labels.get("_start"))
+        .cloned()
+        .or_else(|| chunks.iter().map(|chunk| chunk.0).filter(|&addr| addr < RESET_VECTOR).min())
+        .unwrap_or(ROM_BASE);
+
+    build_rom(&chunks, entry)
+}
+
+// Assembles a single instruction or data directive at addr, as used when
+// patching code from the debugger.
+pub fn assemble_line(text: &str, addr: u32, labels: &HashMap<String, u32>) -> Result<Vec<u8>, String> {
+    let line = text.parse::<Line>()?;
+
+    if !line.labels.is_empty() {
+        return Err("Labels can't be defined here".into());
+    }
+
+    match line.statement {
+        Some(Statement::Org(_)) => Err(".org can't be used here".into()),
+        Some(ref statement) => assemble_statement(statement, addr, labels),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn statement_len(statement: &Statement) -> Result<u32, String> {
+    let len = match *statement {
+        Statement::Org(_) => 0,
+        Statement::Word(ref values) => values.len() * 4,
+        Statement::Halfword(ref values) => values.len() * 2,
+        Statement::Byte(ref values) => values.len(),
+        Statement::Ascii(ref bytes) => bytes.len(),
+        Statement::Instruction(ref mnemonic, ref operands) => {
+            let opcode = select_opcode(mnemonic, operands)?;
+            if opcode.instruction_format().has_second_halfword() { 4 } else { 2 }
+        }
+    };
+
+    Ok(len as u32)
+}
+
+fn assemble_statement(statement: &Statement, addr: u32, labels: &HashMap<String, u32>) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+
+    match *statement {
+        Statement::Org(_) => (),
+        Statement::Word(ref values) => {
+            for value in values.iter() {
+                let value = check_range(value.eval(labels)?, -0x80000000, 0xffffffff, ".word")? as u32;
+                bytes.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+            }
+        }
+        Statement::Halfword(ref values) => {
+            for value in values.iter() {
+                let value = check_range(value.eval(labels)?, -0x8000, 0xffff, ".halfword")? as u16;
+                bytes.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+            }
+        }
+        Statement::Byte(ref values) => {
+            for value in values.iter() {
+                bytes.push(check_range(value.eval(labels)?, -0x80, 0xff, ".byte")? as u8);
+            }
+        }
+        Statement::Ascii(ref text) => bytes.extend_from_slice(text),
+        Statement::Instruction(ref mnemonic, ref operands) => {
+            if addr & 1 != 0 {
+                return Err(format!("Instruction at odd address 0x{:08x}", addr));
+            }
+
+            let opcode = select_opcode(mnemonic, operands)?;
+            let operands = encode_operands(opcode, operands, addr, labels)?;
+
+            let (first_halfword, second_halfword) = encode(opcode, operands);
+            bytes.extend_from_slice(&[first_halfword as u8, (first_halfword >> 8) as u8]);
+
+            if let Some(second_halfword) = second_halfword {
+                bytes.extend_from_slice(&[second_halfword as u8, (second_halfword >> 8) as u8]);
+            }
+        }
+    }
+
+    Ok(bytes)
+}
+
+// Several mnemonics (mov, add, cmp and the shifts) exist in both a register
+// and an immediate form; the first operand decides which is meant.
+fn select_opcode(mnemonic: &str, operands: &[Operand]) -> Result<Opcode, String> {
+    let mnemonic = mnemonic.to_lowercase();
+
+    if let Some(condition) = branch_condition(&mnemonic) {
+        return Ok(Opcode::Bcond(condition));
+    }
+
+    let candidates = OPCODES.iter()
+        .filter(|opcode| opcode.to_string() == mnemonic)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    match candidates.len() {
+        0 => Err(format!("Unknown mnemonic: {}", mnemonic)),
+        1 => Ok(candidates[0]),
+        _ => {
+            let first_is_register = operands.first().is_some_and(|operand| register(operand).is_ok());
+            let format = if first_is_register { InstructionFormat::I } else { InstructionFormat::II };
+
+            candidates.into_iter()
+                .find(|opcode| opcode.instruction_format() == format)
+                .ok_or_else(|| format!("Invalid operands for {}", mnemonic))
+        }
+    }
+}
+
+fn branch_condition(mnemonic: &str) -> Option<Condition> {
+    // Aliases for the conditions with two names in the manual.
+    let mnemonic = match mnemonic {
+        "bl" => "bc",
+        "be" => "bz",
+        "bnl" => "bnc",
+        "bne" => "bnz",
+        m => m,
+    };
+
+    (0..16).map(Condition::from_bits).find(|condition| condition.branch_mnemonic() == mnemonic)
+}
+
+fn encode_operands(opcode: Opcode, operands: &[Operand], addr: u32, labels: &HashMap<String, u32>) -> Result<Operands, String> {
+    let expect = |count: usize| {
+        if operands.len() == count {
+            Ok(())
+        } else {
+            Err(format!("{} takes {} operand(s), found {}", opcode, count, operands.len()))
+        }
+    };
+
+    let value = |operand: &Operand| match *operand {
+        Operand::Expr(ref expr) => expr.eval(labels),
+        Operand::Indirect(..) => Err(format!("Unexpected memory operand for {}", opcode)),
+    };
+
+    let encoded = match opcode.instruction_format() {
+        InstructionFormat::I if opcode == Opcode::Jmp => {
+            expect(1)?;
+            match operands[0] {
+                Operand::Indirect(None, ref reg) => Operands::I { reg1: register_number(reg)?, reg2: 0 },
+                _ => return Err("jmp takes a register operand, like [r31]".into()),
+            }
+        }
+        InstructionFormat::I => {
+            expect(2)?;
+            Operands::I { reg1: register(&operands[0])?, reg2: register(&operands[1])? }
+        }
+        InstructionFormat::II => match opcode {
+            _ if opcode.takes_no_operands() => {
+                expect(0)?;
+                Operands::II { imm5: 0, reg2: 0 }
+            }
+            Opcode::Trap => {
+                expect(1)?;
+                Operands::II { imm5: check_range(value(&operands[0])?, 0, 31, "trap vector")? as usize, reg2: 0 }
+            }
+            Opcode::Setf => {
+                expect(2)?;
+                Operands::II { imm5: condition(&operands[0])?.bits() as usize, reg2: register(&operands[1])? }
+            }
+            Opcode::Ldsr => {
+                expect(2)?;
+                Operands::II { imm5: system_register(&operands[1], labels)?, reg2: register(&operands[0])? }
+            }
+            Opcode::Stsr => {
+                expect(2)?;
+                Operands::II { imm5: system_register(&operands[0], labels)?, reg2: register(&operands[1])? }
+            }
+            Opcode::MovImm | Opcode::AddImm | Opcode::CmpImm => {
+                expect(2)?;
+                let imm5 = check_range(value(&operands[0])?, -16, 15, "imm5")?;
+                Operands::II { imm5: (imm5 & 0x1f) as usize, reg2: register(&operands[1])? }
+            }
+            _ => {
+                expect(2)?;
+                let imm5 = check_range(value(&operands[0])?, 0, 31, "imm5")?;
+                Operands::II { imm5: imm5 as usize, reg2: register(&operands[1])? }
+            }
+        },
+        InstructionFormat::III => {
+            if opcode == Opcode::Bcond(Condition::F) {
+                expect(0)?;
+                Operands::III { disp9: 0 }
+            } else {
+                expect(1)?;
+                let disp = value(&operands[0])? - addr as i64;
+                let disp = branch_displacement(disp, 9)?;
+                Operands::III { disp9: disp as i32 }
+            }
+        }
+        InstructionFormat::IV => {
+            expect(1)?;
+            let disp = value(&operands[0])? - addr as i64;
+            let disp = branch_displacement(disp, 26)?;
+            Operands::IV { disp26: disp as i32 }
+        }
+        InstructionFormat::V => {
+            expect(3)?;
+            let imm16 = check_range(value(&operands[0])?, -0x8000, 0xffff, "imm16")?;
+            Operands::V { reg1: register(&operands[1])?, reg2: register(&operands[2])?, imm16: imm16 as u16 }
+        }
+        InstructionFormat::VI => {
+            expect(2)?;
+
+            // Stores are listed like loads, memory operand first, but the
+            // manual writes them source first, st.w r2, 0[r1]; either works.
+            let is_store = matches!(opcode, Opcode::Stb | Opcode::Sth | Opcode::Stw | Opcode::Outb | Opcode::Outh | Opcode::Outw);
+            let (disp, reg, data) = match (&operands[0], &operands[1]) {
+                (Operand::Indirect(disp, reg), data) => (disp, reg, data),
+                (data, Operand::Indirect(disp, reg)) if is_store => (disp, reg, data),
+                _ => return Err(format!("{} takes a memory operand, like 0[r1]", opcode)),
+            };
+
+            let disp16 = match *disp {
+                Some(ref expr) => check_range(expr.eval(labels)?, -0x8000, 0xffff, "disp16")?,
+                None => 0,
+            };
+            Operands::VI { reg1: register_number(reg)?, reg2: register(data)?, disp16: disp16 as u16 as i16 }
+        }
+        InstructionFormat::VII if opcode == Opcode::Xb || opcode == Opcode::Xh => {
+            expect(1)?;
+            Operands::VII { reg1: 0, reg2: register(&operands[0])? }
+        }
+        InstructionFormat::VII => {
+            expect(2)?;
+            Operands::VII { reg1: register(&operands[0])?, reg2: register(&operands[1])? }
+        }
+    };
+
+    Ok(encoded)
+}
+
+// Relative displacements have to be even and fit a signed field of the given
+// number of bits.
+fn branch_displacement(disp: i64, bits: u32) -> Result<i64, String> {
+    let limit = 1i64 << (bits - 1);
+
+    if disp & 1 != 0 {
+        return Err(format!("Branch target is {} bytes away, which is odd", disp));
+    }
+
+    if disp < -limit || disp >= limit {
+        return Err(format!("Branch target is out of range ({} bytes away)", disp));
+    }
+
+    Ok(disp)
+}
+
+fn check_range(value: i64, min: i64, max: i64, what: &str) -> Result<i64, String> {
+    if value < min || value > max {
+        Err(format!("Value {} doesn't fit {}", value, what))
+    } else {
+        Ok(value)
+    }
+}
+
+fn register(operand: &Operand) -> Result<usize, String> {
+    match *operand {
+        Operand::Expr(Expr::Symbol(ref name)) => register_number(name),
+        _ => Err(format!("Expected a register, found {:?}", operand)),
+    }
+}
+
+pub fn register_number(name: &str) -> Result<usize, String> {
+    let name = name.to_lowercase();
+
+    let number = match name.as_str() {
+        "sp" => Some(3),
+        "gp" => Some(4),
+        "tp" => Some(5),
+        "lp" => Some(31),
+        _ if name.starts_with('r') => name[1..].parse::<usize>().ok().filter(|&n| n < 32),
+        _ => None,
+    };
+
+    number.ok_or_else(|| format!("Expected a register, found {}", name))
+}
+
+fn condition(operand: &Operand) -> Result<Condition, String> {
+    let name = match *operand {
+        Operand::Expr(ref expr) => expr.symbol(),
+        _ => None,
+    };
+
+    name.and_then(|name| (0..16).map(Condition::from_bits).find(|condition| condition.to_string() == name))
+        .ok_or_else(|| format!("Expected a condition, found {:?}", operand))
+}
+
+fn system_register(operand: &Operand, labels: &HashMap<String, u32>) -> Result<usize, String> {
+    if let Operand::Expr(ref expr) = *operand {
+        if let Some(name) = expr.symbol() {
+            if let Some(number) = (0..32).find(|&n| SystemRegister(n).to_string() == name) {
+                return Ok(number);
+            }
+        }
+
+        return check_range(expr.eval(labels)?, 0, 31, "system register").map(|n| n as usize);
+    }
+
+    Err(format!("Expected a system register, found {:?}", operand))
+}
+
+fn build_rom(chunks: &[(u32, Vec<u8>)], entry: u32) -> Result<Vec<u8>, AssemblerError> {
+    let error = |message: String| AssemblerError { line: 0, message };
+
+    // Code in the top mirror (vectors and the like) is placed relative to the
+    // end of the ROM, everything else relative to its start.
+    let mut low_extent = 0u64;
+    let mut high_extent = HEADER_AREA_SIZE;
+
+    for &(addr, ref bytes) in chunks.iter() {
+        if addr & 0x07ffffff < ROM_BASE {
+            return Err(error(format!("Address 0x{:08x} isn't in ROM", addr)));
+        }
+
+        if addr >= 0x08000000 {
+            high_extent = high_extent.max(0x100000000 - addr as u64);
+        } else {
+            low_extent = low_extent.max((addr - ROM_BASE) as u64 + bytes.len() as u64);
+        }
+    }
+
+    let size = (low_extent + high_extent).next_power_of_two().max(MIN_ROM_SIZE);
+    if size > MAX_ROM_SIZE {
+        return Err(error(format!("Program needs a {:#x} byte ROM, which is too large", size)));
+    }
+
+    let mut rom = vec![0xff; size as usize];
+    let mut written = vec![false; size as usize];
+
+    let header_offset = (size - HEADER_AREA_SIZE) as usize;
+    rom[header_offset..header_offset + 0x14].copy_from_slice(DEFAULT_TITLE);
+    for byte in rom[header_offset + 0x14..header_offset + 0x19].iter_mut() {
+        *byte = 0x00;
+    }
+    rom[header_offset + 0x19..header_offset + 0x1b].copy_from_slice(DEFAULT_MAKER_CODE);
+    rom[header_offset + 0x1b..header_offset + 0x1f].copy_from_slice(DEFAULT_GAME_CODE);
+    rom[header_offset + 0x1f] = 0x00;
+
+    let rom_mask = (size - 1) as u32;
+
+    for &(addr, ref bytes) in chunks.iter() {
+        for (i, &byte) in bytes.iter().enumerate() {
+            let offset = (addr.wrapping_add(i as u32) & rom_mask) as usize;
+
+            if written[offset] {
+                return Err(error(format!("Code at 0x{:08x} overlaps earlier code", addr.wrapping_add(i as u32))));
+            }
+
+            rom[offset] = byte;
+            written[offset] = true;
+        }
+    }
+
+    let reset_offset = (RESET_VECTOR & rom_mask) as usize;
+    if !written[reset_offset] {
+        let mut labels = HashMap::new();
+        labels.insert("start".to_string(), entry);
+
+        let stub = [
+            "movhi hi(start), r0, r1",
+            "movea lo(start), r1, r1",
+            "jmp [r1]",
+        ];
+
+        let mut offset = reset_offset;
+        for text in stub.iter() {
+            let bytes = assemble_line(text, RESET_VECTOR, &labels).map_err(error)?;
+            rom[offset..offset + bytes.len()].copy_from_slice(&bytes);
+            offset += bytes.len();
+        }
+    }
+
+    Ok(rom)
+}
+
+fn is_identifier_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+fn is_identifier_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+fn is_mnemonic_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'.'
+}
+
+named!(
+    pub line<Line>,
+    complete!(
+        chain!(
+            opt!(space) ~
+            labels: many0!(complete!(label_definition)) ~
+            statement: opt!(complete!(statement)) ~
+            opt!(space) ~
+            opt!(complete!(comment)) ~
+            eof,
+            || Line { labels, statement }
+        )
+    )
+);
+
+named!(
+    label_definition<String>,
+    chain!(
+        opt!(char!('.')) ~
+        name: identifier ~
+        char!(':') ~
+        opt!(space),
+        || name
+    )
+);
+
+named!(
+    comment<()>,
+    chain!(
+        char!(';') ~ call!(nom::rest),
+        || ()
+    )
+);
+
+named!(
+    statement<Statement>,
+    alt_complete!(
+        org | word | halfword | byte | ascii | instruction
+    )
+);
+
+named!(
+    org<Statement>,
+    chain!(
+        tag!(".org") ~ space ~ addr: expr,
+        || Statement::Org(addr)
+    )
+);
+
+named!(
+    word<Statement>,
+    chain!(
+        tag!(".word") ~ space ~ values: expr_list,
+        || Statement::Word(values)
+    )
+);
+
+named!(
+    halfword<Statement>,
+    chain!(
+        tag!(".halfword") ~ space ~ values: expr_list,
+        || Statement::Halfword(values)
+    )
+);
+
+named!(
+    byte<Statement>,
+    chain!(
+        tag!(".byte") ~ space ~ values: expr_list,
+        || Statement::Byte(values)
+    )
+);
+
+named!(
+    ascii<Statement>,
+    chain!(
+        tag!(".ascii") ~ space ~ text: string_literal,
+        || Statement::Ascii(text)
+    )
+);
+
+named!(
+    instruction<Statement>,
+    chain!(
+        mnemonic: map_res!(
+            map_res!(
+                take_while1!(is_mnemonic_char),
+                str::from_utf8
+            ),
+            FromStr::from_str
+        ) ~
+        operands: opt!(complete!(preceded!(space, separated_list!(list_separator, operand)))),
+        || Statement::Instruction(mnemonic, operands.unwrap_or_default())
+    )
+);
+
+named!(
+    operand<Operand>,
+    alt_complete!(
+        chain!(
+            disp: opt!(complete!(expr)) ~
+            opt!(space) ~
+            char!('[') ~
+            opt!(space) ~
+            reg: identifier ~
+            opt!(space) ~
+            char!(']'),
+            || Operand::Indirect(disp, reg)
+        ) |
+        map!(expr, Operand::Expr)
+    )
+);
+
+named!(
+    list_separator<()>,
+    chain!(
+        opt!(space) ~ char!(',') ~ opt!(space),
+        || ()
+    )
+);
+
+named!(
+    expr_list<Vec<Expr> >,
+    separated_nonempty_list!(list_separator, expr)
+);
+
+named!(
+    pub expr<Expr>,
+    chain!(
+        first: term ~
+        rest: many0!(
+            complete!(
+                chain!(
+                    opt!(space) ~
+                    op: alt_complete!(char!('+') | char!('-')) ~
+                    opt!(space) ~
+                    rhs: term,
+                    || (op, rhs)
+                )
+            )
+        ),
+        || rest.into_iter().fold(first, |lhs, (op, rhs)| {
+            if op == '+' {
+                Expr::Add(Box::new(lhs), Box::new(rhs))
+            } else {
+                Expr::Sub(Box::new(lhs), Box::new(rhs))
+            }
+        })
+    )
+);
+
+named!(
+    term<Expr>,
+    alt_complete!(
+        chain!(
+            tag!("hi(") ~ opt!(space) ~ e: expr ~ opt!(space) ~ char!(')'),
+            || Expr::Hi(Box::new(e))
+        ) |
+        chain!(
+            tag!("lo(") ~ opt!(space) ~ e: expr ~ opt!(space) ~ char!(')'),
+            || Expr::Lo(Box::new(e))
+        ) |
+        chain!(
+            char!('-') ~ value: number,
+            || Expr::Number(-value)
+        ) |
+        map!(number, Expr::Number) |
+        map!(preceded!(opt!(char!('.')), identifier), Expr::Symbol)
+    )
+);
+
+named!(
+    number<i64>,
+    alt_complete!(
+        map_res!(
+            map_res!(
+                preceded!(
+                    alt_complete!(
+                        tag!("0x") | tag!("$")
+                    ),
+                    hex_digit
+                ),
+                str::from_utf8
+            ),
+            |s| i64::from_str_radix(s, 16)
+        ) |
+        map_res!(
+            map_res!(
+                digit,
+                str::from_utf8
+            ),
+            FromStr::from_str
+        )
+    )
+);
+
+named!(
+    identifier<String>,
+    map_res!(
+        map_res!(
+            recognize!(
+                preceded!(
+                    take_while1!(is_identifier_start),
+                    take_while!(is_identifier_char)
+                )
+            ),
+            str::from_utf8
+        ),
+        FromStr::from_str
+    )
+);
+
+// Double-quoted text supporting the \n, \t, \0, \\ and \" escapes.
+fn string_literal(input: &[u8]) -> IResult<&[u8], Vec<u8>> {
+    let error = IResult::Error(nom::Err::Position(nom::ErrorKind::Custom(0), input));
+
+    if input.first() != Some(&b'"') {
+        return error;
+    }
+
+    let mut text = Vec::new();
+    let mut chars = input.iter().enumerate().skip(1);
+
+    while let Some((index, &c)) = chars.next() {
+        match c {
+            b'"' => return IResult::Done(&input[index + 1..], text),
+            b'\\' => match chars.next() {
+                Some((_, &b'n')) => text.push(b'\n'),
+                Some((_, &b't')) => text.push(b'\t'),
+                Some((_, &b'0')) => text.push(0),
+                Some((_, &c)) => text.push(c),
+                None => return error,
+            },
+            c => text.push(c),
+        }
+    }
+
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interconnect::*;
+
+    // Decodes the instruction at the start of bytes, placed at the base of
+    // an otherwise blank ROM.
+    fn disassemble(bytes: &[u8]) -> Instruction {
+        let mut rom = vec![0; 1024];
+        rom[..bytes.len()].copy_from_slice(bytes);
+        let interconnect = Interconnect::with_rom_bytes(rom);
+        decode(ROM_BASE, &interconnect).unwrap()
+    }
+
+    fn assemble_at_base(text: &str) -> Vec<u8> {
+        assemble_line(text, ROM_BASE, &HashMap::new()).unwrap_or_else(|e| panic!("{}: {}", text, e))
+    }
+
+    fn encoded_bytes(opcode: Opcode, operands: Operands) -> Vec<u8> {
+        let (first_halfword, second_halfword) = encode(opcode, operands);
+        let mut bytes = vec![first_halfword as u8, (first_halfword >> 8) as u8];
+        if let Some(second_halfword) = second_halfword {
+            bytes.extend_from_slice(&[second_halfword as u8, (second_halfword >> 8) as u8]);
+        }
+        bytes
+    }
+
+    // Operands with every field the opcode uses set to something other than
+    // zero, and every field it ignores left at zero, so that assembling the
+    // listing gives back the same encoding.
+    fn sample_operands(opcode: Opcode) -> Operands {
+        match opcode.instruction_format() {
+            InstructionFormat::I if opcode == Opcode::Jmp => Operands::I { reg1: 7, reg2: 0 },
+            InstructionFormat::I => Operands::I { reg1: 7, reg2: 12 },
+            InstructionFormat::II => match opcode {
+                _ if opcode.takes_no_operands() => Operands::II { imm5: 0, reg2: 0 },
+                Opcode::Trap => Operands::II { imm5: 21, reg2: 0 },
+                Opcode::MovImm | Opcode::AddImm | Opcode::CmpImm => Operands::II { imm5: 0x1b, reg2: 9 },
+                _ => Operands::II { imm5: 5, reg2: 9 },
+            },
+            InstructionFormat::III if opcode == Opcode::Bcond(Condition::F) => Operands::III { disp9: 0 },
+            InstructionFormat::III => Operands::III { disp9: -6 },
+            InstructionFormat::IV => Operands::IV { disp26: 0x12344 },
+            InstructionFormat::V => Operands::V { reg1: 3, reg2: 4, imm16: 0xbeef },
+            InstructionFormat::VI => Operands::VI { reg1: 5, reg2: 6, disp16: -8 },
+            InstructionFormat::VII if opcode == Opcode::Xb || opcode == Opcode::Xh => Operands::VII { reg1: 0, reg2: 11 },
+            InstructionFormat::VII => Operands::VII { reg1: 10, reg2: 11 },
+        }
+    }
+
+    fn assert_round_trips(opcode: Opcode) {
+        let bytes = encoded_bytes(opcode, sample_operands(opcode));
+        let listing = disassemble(&bytes).to_string();
+
+        let assembled = assemble_at_base(&listing);
+        assert_eq!(assembled, bytes, "{}", listing);
+
+        let instruction = disassemble(&assembled);
+        assert_eq!(instruction.opcode, opcode, "{}", listing);
+        assert_eq!(instruction.to_string(), listing);
+    }
+
+    #[test]
+    fn every_opcode_round_trips() {
+        for &opcode in OPCODES.iter() {
+            assert_round_trips(opcode);
+        }
+    }
+
+    #[test]
+    fn every_branch_condition_round_trips() {
+        for bits in 0..16 {
+            assert_round_trips(Opcode::Bcond(Condition::from_bits(bits)));
+        }
+    }
+
+    #[test]
+    fn every_format_is_covered() {
+        let formats = OPCODES.iter().map(|opcode| opcode.instruction_format()).collect::<Vec<_>>();
+        for format in [InstructionFormat::I, InstructionFormat::II, InstructionFormat::IV, InstructionFormat::V,
+                       InstructionFormat::VI, InstructionFormat::VII].iter() {
+            assert!(formats.contains(format), "{:?}", format);
+        }
+        assert_eq!(Opcode::Bcond(Condition::Z).instruction_format(), InstructionFormat::III);
+    }
+
+    #[test]
+    fn branch_aliases_match_their_canonical_names() {
+        let target = ROM_BASE + 0x20;
+        for &(alias, canonical) in [("bl", "bc"), ("be", "bz"), ("bnl", "bnc"), ("bne", "bnz")].iter() {
+            let alias_bytes = assemble_at_base(&format!("{} 0x{:08x}", alias, target));
+            let canonical_bytes = assemble_at_base(&format!("{} 0x{:08x}", canonical, target));
+            assert_eq!(alias_bytes, canonical_bytes, "{}", alias);
+            assert_eq!(disassemble(&alias_bytes).to_string(), format!("{} 0x{:08x}", canonical, target));
+        }
+    }
+
+    #[test]
+    fn stores_accept_the_manuals_operand_order() {
+        for mnemonic in ["st.b", "st.h", "st.w", "out.b", "out.h", "out.w"].iter() {
+            let manual = assemble_at_base(&format!("{} r6, -8[r5]", mnemonic));
+            let listing = assemble_at_base(&format!("{} -8[r5], r6", mnemonic));
+            assert_eq!(manual, listing, "{}", mnemonic);
+        }
+
+        assert!(assemble_line("ld.w r6, -8[r5]", ROM_BASE, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn hi_and_lo_rebuild_the_value() {
+        for &value in [0x00000000u32, 0x07001234, 0x0500fffe, 0x12348000, 0xffff7fff, 0x0000ffff].iter() {
+            let mut labels = HashMap::new();
+            labels.insert("target".to_string(), value);
+
+            let movhi = disassemble(&assemble_line("movhi hi(target), r0, r1", ROM_BASE, &labels).unwrap());
+            let movea = disassemble(&assemble_line("movea lo(target), r1, r1", ROM_BASE, &labels).unwrap());
+
+            let (hi, lo) = match (movhi.operands, movea.operands) {
+                (Operands::V { imm16: hi, .. }, Operands::V { imm16: lo, .. }) => (hi, lo),
+                operands => panic!("{:?}", operands),
+            };
+
+            // movea sign extends its immediate.
+            let rebuilt = ((hi as u32) << 16).wrapping_add(lo as i16 as u32);
+            assert_eq!(rebuilt, value, "0x{:08x}", value);
+        }
+    }
+
+    #[test]
+    fn directives_emit_their_data() {
+        let source = "
+            .org 0x07000010
+            words:
+                .word 0x12345678, words
+                .halfword 0xbeef, -2
+                .byte 1, 0xff, -1
+                .ascii \"VB\\n\"
+            .org 0x07000100
+            start:
+                jr start
+        ";
+        let rom = assemble(source).unwrap();
+
+        assert_eq!(&rom[0x10..0x18], &[0x78, 0x56, 0x34, 0x12, 0x10, 0x00, 0x00, 0x07]);
+        assert_eq!(&rom[0x18..0x1c], &[0xef, 0xbe, 0xfe, 0xff]);
+        assert_eq!(&rom[0x1c..0x1f], &[0x01, 0xff, 0xff]);
+        assert_eq!(&rom[0x1f..0x22], b"VB\n");
+        assert_eq!(disassemble(&rom[0x100..0x104]).to_string(), "jr 0x07000000");
+    }
+
+    #[test]
+    fn org_and_data_directives_are_rejected_or_sized_correctly() {
+        assert!(assemble_line(".org 0x07000000", ROM_BASE, &HashMap::new()).is_err());
+        assert_eq!(assemble_at_base(".word 1, 2").len(), 8);
+        assert_eq!(assemble_at_base(".halfword 1, 2, 3").len(), 6);
+        assert_eq!(assemble_at_base(".byte 1").len(), 1);
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        assert!(assemble_line(".byte 0x100", ROM_BASE, &HashMap::new()).is_err());
+        assert!(assemble_line("mov 16, r1", ROM_BASE, &HashMap::new()).is_err());
+        assert!(assemble_line("trap 32", ROM_BASE, &HashMap::new()).is_err());
+        assert!(assemble_line("bz 0x07000001", ROM_BASE, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn overflowing_expressions_are_rejected() {
+        assert!(assemble_line(".word 0x7fffffffffffffff + 1", ROM_BASE, &HashMap::new()).is_err());
+        assert!(assemble_line(".word 0 - 0x7fffffffffffffff - 2", ROM_BASE, &HashMap::new()).is_err());
+        assert!(assemble_line("movhi hi(0x7fffffffffffffff), r0, r1", ROM_BASE, &HashMap::new()).is_err());
+    }
+}
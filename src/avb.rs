@@ -0,0 +1,201 @@
+use rom::*;
+use interconnect::*;
+use instruction::*;
+use nvc::*;
+use error::*;
+use trace::*;
+use savestate::*;
+use rewind::*;
+use movie::*;
+
+use std::collections::BTreeMap;
+
+// A minute of history at one snapshot per 5 frames.
+const REWIND_CAPACITY: usize = 600;
+const REWIND_INTERVAL_FRAMES: u64 = 5;
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct AVB {
+    pub interconnect: Interconnect,
+    pub cpu: Nvc,
+    pub tracer: Option<Tracer>,
+
+    // Instructions run since reset, which is what rstep counts back in.
+    pub instructions: u64,
+    pub rewind: RewindBuffer,
+
+    // Buttons the user is holding, latched into the game pad at the next
+    // frame, and the frames at which the latched buttons changed, so a
+    // rewind replays the same input.
+    pub pad_input: u16,
+    pad_changes: BTreeMap<u64, u16>,
+
+    pub recorder: Option<MovieRecorder>,
+}
+
+impl AVB {
+    pub fn new(rom: Rom) -> AVB {
+        AVB {
+            interconnect: Interconnect::new(rom),
+            cpu: Nvc::new(),
+            tracer: None,
+            instructions: 0,
+            rewind: RewindBuffer::new(REWIND_CAPACITY, REWIND_INTERVAL_FRAMES),
+            pad_input: 0,
+            pad_changes: BTreeMap::new(),
+            recorder: None,
+        }
+    }
+
+    // Errors leave the instruction unexecuted, except bus faults, which are
+    // reported after it completes.
+    pub fn step(&mut self) -> Result<(), AvbError> {
+        let pc = self.cpu.reg_pc();
+
+        self.snapshot_if_due();
+
+        let frame = self.interconnect.cycle_count() / CYCLES_PER_FRAME;
+
+        let traced = match self.tracer {
+            Some(ref tracer) if tracer.is_traced(pc) => {
+                decode(pc, &self.interconnect).ok().map(|instruction| (instruction, RegisterSnapshot::capture(&self.cpu)))
+            }
+            _ => None,
+        };
+
+        let result = match self.cpu.step(&mut self.interconnect) {
+            Err(e @ AvbError::BusFault { .. }) => Err(e),
+            Err(e) => return Err(e),
+            Ok(()) => Ok(()),
+        };
+
+        if let (Some((instruction, before)), Some(tracer)) = (traced, self.tracer.as_mut()) {
+            if let Err(e) = tracer.trace(&instruction, &before, &self.cpu) {
+                println!("Unable to write trace, tracing stopped: {}", e);
+                self.tracer = None;
+            }
+        }
+
+        self.instructions += 1;
+
+        let new_frame = self.interconnect.cycle_count() / CYCLES_PER_FRAME;
+        if new_frame != frame {
+            self.end_frame(new_frame);
+        }
+
+        result
+    }
+
+    fn end_frame(&mut self, frame: u64) {
+        // Changes recorded past this frame belong to a future that was
+        // rewound away.
+        self.pad_changes.split_off(&frame);
+        if self.pad_input != self.interconnect.game_pad() {
+            self.pad_changes.insert(frame, self.pad_input);
+        }
+        self.interconnect.set_game_pad(self.pad_input);
+
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.frame(&self.cpu, &self.interconnect);
+        }
+    }
+
+    fn snapshot_if_due(&mut self) {
+        if self.rewind.is_due(self.interconnect.cycle_count()) {
+            self.checkpoint();
+        }
+    }
+
+    // Called after the debugger changes registers or memory too, so
+    // replaying forward from the snapshot includes the change.
+    pub fn checkpoint(&mut self) {
+        let state = save_state(&self.cpu, &self.interconnect);
+        self.rewind.push(self.instructions, self.interconnect.cycle_count(), state);
+    }
+
+    pub fn step_back(&mut self, count: u64) -> Result<(), String> {
+        let target = self.instructions.checked_sub(count).ok_or("Can't step back past reset")?;
+        self.rewind_to(|snapshot| snapshot.instructions <= target, |avb| avb.instructions >= target)
+    }
+
+    pub fn rewind_frames(&mut self, frames: u64) -> Result<(), String> {
+        let target = self.interconnect.cycle_count().saturating_sub(frames.saturating_mul(CYCLES_PER_FRAME));
+        self.rewind_to(|snapshot| snapshot.cycles <= target, |avb| avb.interconnect.cycle_count() >= target)
+    }
+
+    // Restores the newest snapshot from before the target and replays
+    // forward to it, untraced and ignoring breakpoints. Later snapshots are
+    // dropped; running on records a new future.
+    fn rewind_to<F, D>(&mut self, before_target: F, reached: D) -> Result<(), String>
+        where F: Fn(&Snapshot) -> bool, D: Fn(&AVB) -> bool
+    {
+        if self.recorder.is_some() {
+            return Err("Can't rewind while recording a movie".into());
+        }
+
+        let index = self.rewind.latest(before_target).ok_or("Not enough rewind history")?;
+        let instructions = self.rewind.snapshots()[index].instructions;
+        let state = self.rewind.truncate(index);
+
+        load_state(&state, &mut self.cpu, &mut self.interconnect).map_err(|e| e.to_string())?;
+        self.instructions = instructions;
+
+        while !reached(self) {
+            self.snapshot_if_due();
+
+            let frame = self.interconnect.cycle_count() / CYCLES_PER_FRAME;
+            match self.cpu.step(&mut self.interconnect) {
+                // Already reported when the instruction first ran.
+                Ok(()) | Err(AvbError::BusFault { .. }) => (),
+                Err(e) => return Err(e.to_string()),
+            }
+            self.instructions += 1;
+
+            let new_frame = self.interconnect.cycle_count() / CYCLES_PER_FRAME;
+            if new_frame != frame {
+                if let Some(&buttons) = self.pad_changes.get(&new_frame) {
+                    self.interconnect.set_game_pad(buttons);
+                }
+            }
+        }
+
+        self.interconnect.take_watch_hit();
+
+        Ok(())
+    }
+
+    pub fn start_trace(&mut self, file_name: &str, range: Option<(u32, u32)>) {
+        self.stop_trace();
+
+        match Tracer::create(file_name, range) {
+            Ok(tracer) => {
+                println!("Tracing to '{}'", file_name);
+                self.tracer = Some(tracer);
+            }
+            Err(e) => println!("Unable to create trace file '{}': {}", file_name, e),
+        }
+    }
+
+    pub fn stop_trace(&mut self) {
+        if let Some(mut tracer) = self.tracer.take() {
+            if let Err(e) = tracer.flush() {
+                println!("Unable to write trace: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewinding_huge_frame_counts_goes_back_to_the_oldest_snapshot() {
+        let mut avb = AVB::new(Rom::from_bytes(vec![0; 1024]).unwrap());
+        assert_eq!(avb.rewind_frames(100000000000000), Err("Not enough rewind history".to_string()));
+
+        avb.checkpoint();
+        assert_eq!(avb.rewind_frames(u64::MAX), Ok(()));
+        assert_eq!(avb.instructions, 0);
+    }
+}
@@ -0,0 +1,35 @@
+extern crate aurora_vb;
+
+use aurora_vb::assembler::*;
+
+use std::env;
+use std::process;
+use std::fs::File;
+use std::io::{Read, Write};
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+
+    if args.len() != 3 {
+        eprintln!("Usage: avb-asm <source file> <rom file>");
+        process::exit(1);
+    }
+
+    let mut source = String::new();
+    if let Err(e) = File::open(&args[1]).and_then(|mut file| file.read_to_string(&mut source)) {
+        eprintln!("Unable to read '{}': {}", args[1], e);
+        process::exit(1);
+    }
+
+    let rom = assemble(&source).unwrap_or_else(|e| {
+        eprintln!("{}: {}", args[1], e);
+        process::exit(1);
+    });
+
+    if let Err(e) = File::create(&args[2]).and_then(|mut file| file.write_all(&rom)) {
+        eprintln!("Unable to write '{}': {}", args[2], e);
+        process::exit(1);
+    }
+
+    println!("Assembled {} bytes to '{}'", rom.len(), args[2]);
+}
@@ -0,0 +1,252 @@
+extern crate aurora_vb;
+
+use aurora_vb::rom::*;
+use aurora_vb::interconnect::*;
+use aurora_vb::instruction::*;
+use aurora_vb::nvc::*;
+use aurora_vb::disassembler::*;
+use aurora_vb::analysis::*;
+
+use std::env;
+use std::process;
+use std::collections::HashMap;
+
+const ROM_BASE: u32 = 0x07000000;
+
+// Shorter runs of these are as likely to be code as padding.
+const MIN_FILL_RUN: u64 = 16;
+
+const DATA_ROW_LEN: usize = 8;
+
+enum Line {
+    Code(Instruction),
+    Data(u32, Vec<u8>),
+    Fill(u32, u64, u8),
+}
+
+fn main() {
+    let mut args = env::args().collect::<Vec<_>>();
+
+    // Without analysis every byte that decodes is listed as an instruction,
+    // which is occasionally useful for code only reached through jump tables.
+    let linear = args.iter().any(|arg| arg == "--linear");
+    args.retain(|arg| arg != "--linear");
+
+    if args.len() < 2 {
+        usage();
+    }
+
+    let rom = Rom::load(&args[1]).unwrap_or_else(|e| {
+        eprintln!("Unable to load ROM file '{}': {}", args[1], e);
+        process::exit(1);
+    });
+
+    let rom_size = rom.size() as u32;
+    let rom_mask = rom_size - 1;
+
+    // The header occupies the 0x20 bytes just below the interrupt vectors.
+    let header_offset = rom_size - 0x220;
+
+    let mut interconnect = Interconnect::new(rom);
+
+    let (start, end) = match args.get(2).map(|s| s.as_str()) {
+        Some("--reset") => {
+            let entry = follow_reset_vector(&mut interconnect);
+            let start = ROM_BASE | (entry & rom_mask);
+            (start, parse_end(args.get(3), start, rom_mask))
+        }
+        Some(s) => {
+            let start = parse_addr(s);
+            (start, parse_end(args.get(3), start, rom_mask))
+        }
+        None => (ROM_BASE, (ROM_BASE + rom_size) as u64),
+    };
+
+    let code_map = if linear { None } else { Some(CodeMap::analyse(&interconnect)) };
+
+    let lines = sweep(&interconnect, code_map.as_ref(), start, end, rom_mask, header_offset);
+
+    let mut labels = HashMap::new();
+    for line in lines.iter() {
+        if let Line::Code(ref instruction) = *line {
+            if let Some((name, target)) = auto_label(instruction) {
+                // A jal anywhere makes the target a subroutine, even if it
+                // was first seen as a plain branch target.
+                let is_sub = name.starts_with("sub");
+                let entry = labels.entry(target).or_insert_with(|| name.clone());
+                if is_sub {
+                    *entry = name;
+                }
+            }
+        }
+    }
+
+    for line in lines.iter() {
+        match *line {
+            Line::Code(ref instruction) => {
+                if let Some(name) = labels.get(&instruction.addr) {
+                    println!(".{}:", name);
+                }
+
+                let target_label = instruction.branch_target().and_then(|target| labels.get(&target));
+                println!("{}", format_instruction(instruction, target_label.map(|s| s.as_str())));
+            }
+            Line::Data(addr, ref bytes) => {
+                println!("{}", format_data(addr, bytes));
+            }
+            Line::Fill(addr, len, value) => {
+                println!("0x{:08x} {:12}  ; data: {:#x} bytes of 0x{:02x}", addr, "", len, value);
+            }
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: avb-disasm [--linear] <rom file> [--reset | <start addr>] [<end addr>]");
+    process::exit(1);
+}
+
+fn parse_addr(s: &str) -> u32 {
+    let digits = s.trim_start_matches("0x").trim_start_matches('$');
+    u32::from_str_radix(digits, 16).unwrap_or_else(|_| {
+        eprintln!("Invalid address: {}", s);
+        usage();
+    })
+}
+
+// Without an explicit end address the listing runs to the end of whichever
+// ROM mirror the start address is in.
+fn parse_end(arg: Option<&String>, start: u32, rom_mask: u32) -> u64 {
+    match arg {
+        Some(s) => parse_addr(s) as u64,
+        None => (start & !rom_mask) as u64 + rom_mask as u64 + 1,
+    }
+}
+
+// The reset vector only has room for a few instructions, which load the
+// entry point into a register and jump to it; run them to find out where.
+fn follow_reset_vector(interconnect: &mut Interconnect) -> u32 {
+    let mut cpu = Nvc::new();
+
+    for _ in 0..8 {
+        if let Err(e) = cpu.step(interconnect) {
+            eprintln!("Unable to follow the reset vector: {}", e);
+            process::exit(1);
+        }
+
+        if cpu.reg_pc() < RESET_VECTOR {
+            return cpu.reg_pc();
+        }
+    }
+
+    eprintln!("Reset vector at 0x{:08x} doesn't jump out of the vector table", RESET_VECTOR);
+    process::exit(1);
+}
+
+fn sweep(interconnect: &Interconnect, code_map: Option<&CodeMap>, start: u32, end: u64, rom_mask: u32, header_offset: u32) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut addr = start as u64;
+
+    while addr < end {
+        let cursor = addr as u32;
+
+        match code_map {
+            Some(code_map) if code_map.is_data(cursor) => {
+                let mut len = 1;
+                while addr + len < end && code_map.is_data(cursor.wrapping_add(len as u32)) {
+                    len += 1;
+                }
+
+                push_data_or_fill(&mut lines, interconnect, cursor, len);
+                addr += len;
+                continue;
+            }
+            Some(_) => (),
+            None => {
+                // The ROM is mirrored across its whole region, so match the
+                // header by its offset into the ROM.
+                if (cursor & 0x07000000) == ROM_BASE && (cursor & rom_mask) == header_offset {
+                    let len = 0x20;
+                    push_data(&mut lines, interconnect, cursor, len);
+                    addr += len;
+                    continue;
+                }
+
+                let fill_len = fill_run_len(interconnect, cursor, end - addr);
+                if fill_len >= MIN_FILL_RUN {
+                    lines.push(Line::Fill(cursor, fill_len, interconnect.peek_byte(cursor)));
+                    addr += fill_len;
+                    continue;
+                }
+            }
+        }
+
+        // Decoding may fetch a second halfword, which mustn't run off the end
+        // of the address space.
+        let instruction = if end - addr >= 4 { decode(cursor, interconnect).ok() } else { None };
+
+        match instruction {
+            Some(ref instruction) if addr + instruction.length as u64 <= end => {
+                addr += instruction.length as u64;
+                lines.push(Line::Code(*instruction));
+            }
+            _ => {
+                let len = (end - addr).min(2);
+                push_data(&mut lines, interconnect, cursor, len);
+                addr += len;
+            }
+        }
+    }
+
+    lines
+}
+
+fn fill_run_len(interconnect: &Interconnect, addr: u32, max_len: u64) -> u64 {
+    let value = interconnect.peek_byte(addr);
+
+    if value != 0x00 && value != 0xff {
+        return 0;
+    }
+
+    let mut len = 0;
+    while len < max_len && interconnect.peek_byte(addr.wrapping_add(len as u32)) == value {
+        len += 1;
+    }
+
+    // Keep runs halfword aligned so decoding resumes on an instruction boundary.
+    len & !1
+}
+
+fn push_data_or_fill(lines: &mut Vec<Line>, interconnect: &Interconnect, addr: u32, len: u64) {
+    let mut offset = 0;
+
+    while offset < len {
+        let cursor = addr.wrapping_add(offset as u32);
+
+        let fill_len = fill_run_len(interconnect, cursor, len - offset);
+        if fill_len >= MIN_FILL_RUN {
+            lines.push(Line::Fill(cursor, fill_len, interconnect.peek_byte(cursor)));
+            offset += fill_len;
+        } else {
+            push_data(lines, interconnect, cursor, 1);
+            offset += 1;
+        }
+    }
+}
+
+// Consecutive data is merged into rows of up to DATA_ROW_LEN bytes.
+fn push_data(lines: &mut Vec<Line>, interconnect: &Interconnect, addr: u32, len: u64) {
+    for i in 0..len as u32 {
+        let addr = addr.wrapping_add(i);
+        let byte = interconnect.peek_byte(addr);
+
+        if let Some(&mut Line::Data(row_addr, ref mut bytes)) = lines.last_mut() {
+            if bytes.len() < DATA_ROW_LEN && row_addr.wrapping_add(bytes.len() as u32) == addr {
+                bytes.push(byte);
+                continue;
+            }
+        }
+
+        lines.push(Line::Data(addr, vec![byte]));
+    }
+}
@@ -0,0 +1,149 @@
+extern crate aurora_vb;
+
+use aurora_vb::trace::*;
+
+use std::env;
+use std::process;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::collections::VecDeque;
+
+const DEFAULT_CONTEXT: usize = 5;
+
+// A trace read a line at a time, keeping the last few lines around to show
+// what led up to a divergence.
+struct TraceReader {
+    file_name: String,
+    lines: Box<dyn Iterator<Item = (usize, String)>>,
+    history: VecDeque<(usize, String)>,
+    context: usize,
+    state: TraceState,
+}
+
+impl TraceReader {
+    fn open(file_name: &str, context: usize) -> TraceReader {
+        let file = File::open(file_name).unwrap_or_else(|e| {
+            eprintln!("Unable to open trace '{}': {}", file_name, e);
+            process::exit(1);
+        });
+
+        let name = file_name.to_string();
+        let lines = BufReader::new(file).lines().enumerate().map(move |(index, line)| {
+            let line = line.unwrap_or_else(|e| {
+                eprintln!("Unable to read trace '{}': {}", name, e);
+                process::exit(1);
+            });
+            (index + 1, line)
+        });
+
+        TraceReader {
+            file_name: file_name.to_string(),
+            lines: Box::new(lines),
+            history: VecDeque::new(),
+            context,
+            state: TraceState::default(),
+        }
+    }
+
+    fn next_entry(&mut self) -> Option<TraceEntry> {
+        while let Some((line_number, line)) = self.lines.next() {
+            let entry = TraceEntry::parse(&line).unwrap_or_else(|e| {
+                eprintln!("{}:{}: {}", self.file_name, line_number, e);
+                process::exit(1);
+            });
+
+            if let Some(entry) = entry {
+                if self.history.len() > self.context {
+                    self.history.pop_front();
+                }
+                self.history.push_back((line_number, line));
+                self.state.apply(&entry);
+
+                return Some(entry);
+            }
+        }
+
+        None
+    }
+
+    fn print_history(&self) {
+        println!("{}:", self.file_name);
+        for &(line_number, ref line) in self.history.iter() {
+            println!("{:>8}: {}", line_number, line);
+        }
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1).collect::<Vec<_>>();
+
+    let context = match args.iter().position(|arg| arg == "--context") {
+        Some(index) if index + 1 < args.len() => {
+            let value = args.remove(index + 1);
+            args.remove(index);
+            value.parse().unwrap_or_else(|_| usage())
+        }
+        Some(_) => usage(),
+        None => DEFAULT_CONTEXT,
+    };
+
+    if args.len() != 2 {
+        usage();
+    }
+
+    let mut ours = TraceReader::open(&args[0], context);
+    let mut reference = TraceReader::open(&args[1], context);
+
+    let mut count = 0;
+
+    loop {
+        let (our_entry, reference_entry) = match (ours.next_entry(), reference.next_entry()) {
+            (Some(our_entry), Some(reference_entry)) => (our_entry, reference_entry),
+            (None, None) => break,
+            // One trace stopping early is as much a divergence as a
+            // register differing.
+            (Some(_), None) => diverge_at_end(count, &reference.file_name, &ours, &reference),
+            (None, Some(_)) => diverge_at_end(count, &ours.file_name, &ours, &reference),
+        };
+
+        let mismatches = ours.state.diff(&reference.state);
+
+        if our_entry.pc != reference_entry.pc || !mismatches.is_empty() {
+            println!("Traces diverge after {} matching instructions\n", count);
+
+            ours.print_history();
+            println!();
+            reference.print_history();
+            println!();
+
+            if our_entry.pc != reference_entry.pc {
+                println!("pc: 0x{:08x} != 0x{:08x}", our_entry.pc, reference_entry.pc);
+            }
+
+            for (register, our_value, reference_value) in mismatches {
+                println!("{}: 0x{:08x} != 0x{:08x}", register, our_value, reference_value);
+            }
+
+            process::exit(1);
+        }
+
+        count += 1;
+    }
+
+    println!("Traces match ({} instructions)", count);
+}
+
+fn diverge_at_end(count: usize, ended: &str, ours: &TraceReader, reference: &TraceReader) -> ! {
+    println!("Traces diverge after {} matching instructions: '{}' ends\n", count, ended);
+
+    ours.print_history();
+    println!();
+    reference.print_history();
+
+    process::exit(1);
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: avb-tracediff [--context <lines>] <aurora trace> <reference trace>");
+    process::exit(1);
+}
@@ -0,0 +1,14 @@
+// The CRC-32 used by zip, PNG and the patch formats (reflected, polynomial
+// 0xedb88320), which is also how ROMs are usually identified.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
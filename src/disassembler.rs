@@ -0,0 +1,79 @@
+use instruction::*;
+
+pub fn format_instruction(instruction: &Instruction, target_label: Option<&str>) -> String {
+    let first_halfword = instruction.first_halfword;
+
+    let raw_bytes = match instruction.second_halfword {
+        Some(second_halfword) => format!(
+            "{:02x}{:02x}{:02x}{:02x}",
+            first_halfword & 0xff, first_halfword >> 8,
+            second_halfword & 0xff, second_halfword >> 8),
+        None => format!("{:02x}{:02x}    ", first_halfword & 0xff, first_halfword >> 8),
+    };
+
+    let mut line = format!("0x{:08x} {}      {}", instruction.addr, raw_bytes, instruction);
+
+    if let Some(label) = target_label {
+        line.push_str(&format!(" ; .{}", label));
+    }
+
+    line
+}
+
+pub fn format_data(addr: u32, bytes: &[u8]) -> String {
+    let values = bytes.iter()
+        .map(|byte| format!("0x{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!("0x{:08x} {:12}  .byte {}", addr, "", values)
+}
+
+// Targets reached with jal are subroutines; everything else branched to is
+// just a location within one. nop is a branch that's never taken, so its
+// "target", the nop itself, gets no label.
+pub fn auto_label(instruction: &Instruction) -> Option<(String, u32)> {
+    if instruction.opcode == Opcode::Bcond(Condition::F) {
+        return None;
+    }
+
+    instruction.branch_target().map(|target| {
+        let prefix = if instruction.opcode == Opcode::Jal { "sub" } else { "loc" };
+        (format!("{}_{:08x}", prefix, target), target)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instruction(opcode: Opcode, operands: Operands) -> Instruction {
+        let (first_halfword, second_halfword) = encode(opcode, operands);
+        Instruction {
+            addr: 0x07000100,
+            opcode,
+            operands,
+            length: if second_halfword.is_some() { 4 } else { 2 },
+            first_halfword,
+            second_halfword,
+        }
+    }
+
+    #[test]
+    fn branch_targets_are_labelled() {
+        let jal = instruction(Opcode::Jal, Operands::IV { disp26: 0x100 });
+        assert_eq!(auto_label(&jal), Some(("sub_07000200".to_string(), 0x07000200)));
+
+        let jr = instruction(Opcode::Jr, Operands::IV { disp26: -0x100 });
+        assert_eq!(auto_label(&jr), Some(("loc_07000000".to_string(), 0x07000000)));
+
+        let bz = instruction(Opcode::Bcond(Condition::Z), Operands::III { disp9: 6 });
+        assert_eq!(auto_label(&bz), Some(("loc_07000106".to_string(), 0x07000106)));
+    }
+
+    #[test]
+    fn nops_and_other_instructions_are_not_labelled() {
+        assert_eq!(auto_label(&instruction(Opcode::Bcond(Condition::F), Operands::III { disp9: 0 })), None);
+        assert_eq!(auto_label(&instruction(Opcode::Jmp, Operands::I { reg1: 31, reg2: 0 })), None);
+    }
+}
@@ -0,0 +1,460 @@
+use elf::*;
+
+use std::io::{self, Error, ErrorKind};
+
+// Standard opcodes.
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_NEGATE_STMT: u8 = 6;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+// Extended opcodes.
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: usize,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Row {
+    addr: u32,
+    location: SourceLocation,
+    end_sequence: bool,
+}
+
+// The address to source line mapping from .debug_line, covering DWARF
+// versions 2 to 4 as emitted by gccvb.
+pub struct LineTable {
+    files: Vec<String>,
+    rows: Vec<Row>,
+}
+
+impl LineTable {
+    pub fn from_elf(elf: &Elf) -> io::Result<Option<LineTable>> {
+        match elf.section(".debug_line") {
+            Some(section) => LineTable::parse(elf.section_bytes(section)?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn parse(debug_line: &[u8]) -> io::Result<LineTable> {
+        let mut table = LineTable {
+            files: Vec::new(),
+            rows: Vec::new(),
+        };
+
+        let mut reader = Reader { bytes: debug_line, pos: 0 };
+        while !reader.is_empty() {
+            table.parse_unit(&mut reader)?;
+        }
+
+        // End of sequence rows sort before a sequence starting at the same
+        // address, so lookups land on the start.
+        table.rows.sort_by_key(|row| (row.addr, !row.end_sequence));
+
+        Ok(table)
+    }
+
+    pub fn file_name(&self, file: usize) -> &str {
+        &self.files[file]
+    }
+
+    // The line addr is part of, if any.
+    pub fn lookup(&self, addr: u32) -> Option<SourceLocation> {
+        let index = self.rows.partition_point(|row| row.addr <= addr);
+        if index == 0 {
+            return None;
+        }
+
+        let row = self.rows[index - 1];
+        if row.end_sequence {
+            None
+        } else {
+            Some(row.location)
+        }
+    }
+
+    fn parse_unit(&mut self, reader: &mut Reader) -> io::Result<()> {
+        let unit_length = reader.word()?;
+        if unit_length >= 0xfffffff0 {
+            return Err(invalid_data("64 bit DWARF isn't supported."));
+        }
+
+        let unit_end = reader.pos.checked_add(unit_length as usize).ok_or_else(truncated)?;
+        if unit_end > reader.bytes.len() {
+            return Err(invalid_data("Line table unit runs past the end of .debug_line."));
+        }
+
+        let version = reader.halfword()?;
+        if !(2..=4).contains(&version) {
+            return Err(invalid_data(&format!("DWARF version {} line tables aren't supported.", version)));
+        }
+
+        let header_length = reader.word()?;
+        let program_start = reader.pos.checked_add(header_length as usize).ok_or_else(truncated)?;
+
+        let min_instruction_length = reader.byte()? as u32;
+        if version >= 4 {
+            // Maximum operations per instruction, only meaningful for VLIW.
+            reader.byte()?;
+        }
+        let default_is_stmt = reader.byte()? != 0;
+        let line_base = reader.byte()? as i8 as i64;
+        let line_range = reader.byte()?;
+        let opcode_base = reader.byte()?;
+
+        if line_range == 0 {
+            return Err(invalid_data("Line table has a line range of 0."));
+        }
+
+        let mut standard_opcode_lengths = Vec::new();
+        for _ in 1..opcode_base {
+            standard_opcode_lengths.push(reader.byte()?);
+        }
+
+        let mut directories = Vec::new();
+        loop {
+            let directory = reader.string()?;
+            if directory.is_empty() {
+                break;
+            }
+            directories.push(directory);
+        }
+
+        // Files are numbered from 1 within the unit; map them to indices in
+        // the table's shared list.
+        let mut files = Vec::new();
+        loop {
+            let name = reader.string()?;
+            if name.is_empty() {
+                break;
+            }
+            let directory = reader.uleb128()? as usize;
+            reader.uleb128()?;
+            reader.uleb128()?;
+
+            files.push(self.add_file(&directories, directory, name));
+        }
+
+        reader.pos = program_start;
+
+        let mut addr = 0u32;
+        let mut file = 1usize;
+        let mut line = 1i64;
+        let mut is_stmt = default_is_stmt;
+
+        while reader.pos < unit_end {
+            let opcode = reader.byte()?;
+
+            if opcode >= opcode_base {
+                let adjusted = opcode - opcode_base;
+                addr = addr.wrapping_add((adjusted / line_range) as u32 * min_instruction_length);
+                line += line_base + (adjusted % line_range) as i64;
+                self.emit(&files, addr, file, line, is_stmt, false);
+                continue;
+            }
+
+            match opcode {
+                0 => {
+                    let len = reader.uleb128()? as usize;
+                    let end = reader.pos.checked_add(len).ok_or_else(truncated)?;
+                    if len == 0 {
+                        continue;
+                    }
+
+                    match reader.byte()? {
+                        DW_LNE_END_SEQUENCE => {
+                            self.emit(&files, addr, file, line, true, true);
+                            addr = 0;
+                            file = 1;
+                            line = 1;
+                            is_stmt = default_is_stmt;
+                        }
+                        DW_LNE_SET_ADDRESS => addr = reader.word()?,
+                        DW_LNE_DEFINE_FILE => {
+                            let name = reader.string()?;
+                            let directory = reader.uleb128()? as usize;
+                            files.push(self.add_file(&directories, directory, name));
+                        }
+                        _ => (),
+                    }
+
+                    reader.pos = end;
+                }
+                DW_LNS_COPY => self.emit(&files, addr, file, line, is_stmt, false),
+                DW_LNS_ADVANCE_PC => {
+                    let delta = reader.uleb128()? as u32;
+                    addr = addr.wrapping_add(delta.wrapping_mul(min_instruction_length));
+                }
+                DW_LNS_ADVANCE_LINE => line += reader.sleb128()?,
+                DW_LNS_SET_FILE => file = reader.uleb128()? as usize,
+                DW_LNS_NEGATE_STMT => is_stmt = !is_stmt,
+                DW_LNS_CONST_ADD_PC => {
+                    let adjusted = 255 - opcode_base;
+                    addr = addr.wrapping_add((adjusted / line_range) as u32 * min_instruction_length);
+                }
+                DW_LNS_FIXED_ADVANCE_PC => addr = addr.wrapping_add(reader.halfword()? as u32),
+                // Anything else, including column and prologue markers we
+                // have no use for, is skipped by its operand count.
+                _ => {
+                    for _ in 0..standard_opcode_lengths[opcode as usize - 1] {
+                        reader.uleb128()?;
+                    }
+                }
+            }
+        }
+
+        reader.pos = unit_end;
+
+        Ok(())
+    }
+
+    fn add_file(&mut self, directories: &[String], directory: usize, name: String) -> usize {
+        let path = match directory {
+            0 => name,
+            _ if name.starts_with('/') => name,
+            n => match directories.get(n - 1) {
+                Some(directory) => format!("{}/{}", directory, name),
+                None => name,
+            },
+        };
+
+        match self.files.iter().position(|file| *file == path) {
+            Some(index) => index,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        }
+    }
+
+    // Rows that aren't statement boundaries (the middle of an expression
+    // split across instructions) would only add noise.
+    fn emit(&mut self, files: &[usize], addr: u32, file: usize, line: i64, is_stmt: bool, end_sequence: bool) {
+        if !is_stmt && !end_sequence {
+            return;
+        }
+
+        if let Some(&file) = file.checked_sub(1).and_then(|index| files.get(index)) {
+            let location = SourceLocation { file, line: line.max(0) as u32 };
+            self.rows.push(Row { addr, location, end_sequence });
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        let byte = *self.bytes.get(self.pos).ok_or_else(truncated)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn halfword(&mut self) -> io::Result<u16> {
+        let low = self.byte()? as u16;
+        let high = self.byte()? as u16;
+        Ok(low | (high << 8))
+    }
+
+    fn word(&mut self) -> io::Result<u32> {
+        let low = self.halfword()? as u32;
+        let high = self.halfword()? as u32;
+        Ok(low | (high << 16))
+    }
+
+    fn uleb128(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.byte()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sleb128(&mut self) -> io::Result<i64> {
+        let mut value = 0i64;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.byte()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let rest = self.bytes.get(self.pos..).ok_or_else(truncated)?;
+        let len = rest.iter().position(|&c| c == 0).ok_or_else(truncated)?;
+
+        self.pos += len + 1;
+        Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+    }
+}
+
+fn truncated() -> Error {
+    invalid_data("Truncated .debug_line section.")
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPCODE_BASE: u8 = 13;
+    const LINE_BASE: i8 = -5;
+    const LINE_RANGE: u8 = 14;
+
+    // Wraps a line program in a unit header with gcc's usual parameters,
+    // 2 byte instructions and a single file, src/main.c.
+    fn unit(version: u16, program: &[u8]) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.push(2);
+        if version >= 4 {
+            header.push(1);
+        }
+        header.extend_from_slice(&[1, LINE_BASE as u8, LINE_RANGE, OPCODE_BASE]);
+        header.extend_from_slice(&[0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+        header.extend_from_slice(b"src\0\0");
+        header.extend_from_slice(b"main.c\0\x01\0\0\0");
+
+        let mut rest = Vec::new();
+        rest.extend_from_slice(&[version as u8, (version >> 8) as u8]);
+        rest.extend_from_slice(&word(header.len() as u32));
+        rest.extend_from_slice(&header);
+        rest.extend_from_slice(program);
+
+        let mut unit = word(rest.len() as u32).to_vec();
+        unit.extend_from_slice(&rest);
+        unit
+    }
+
+    fn word(value: u32) -> [u8; 4] {
+        [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+    }
+
+    fn set_address(addr: u32) -> Vec<u8> {
+        let mut op = vec![0, 5, DW_LNE_SET_ADDRESS];
+        op.extend_from_slice(&word(addr));
+        op
+    }
+
+    fn special(addr_advance: u8, line_advance: i8) -> u8 {
+        (line_advance - LINE_BASE) as u8 + LINE_RANGE * addr_advance + OPCODE_BASE
+    }
+
+    fn line(table: &LineTable, addr: u32) -> Option<(&str, u32)> {
+        table.lookup(addr).map(|location| (table.file_name(location.file), location.line))
+    }
+
+    fn program() -> Vec<u8> {
+        let mut program = set_address(0x07000000);
+        // 0x07000000: line 2.
+        program.push(special(0, 1));
+        // 0x07000004: line 5.
+        program.extend_from_slice(&[DW_LNS_ADVANCE_PC, 2, DW_LNS_ADVANCE_LINE, 3, DW_LNS_COPY]);
+        // 0x07000006: line 6, not a statement so there's no row.
+        program.extend_from_slice(&[DW_LNS_NEGATE_STMT, special(1, 1)]);
+        // 0x07000008: line 6 again, a statement.
+        program.extend_from_slice(&[DW_LNS_NEGATE_STMT, special(1, 0)]);
+        // 0x0700002a: util.c line 9.
+        program.extend_from_slice(&[0, 11, DW_LNE_DEFINE_FILE]);
+        program.extend_from_slice(b"util.c\0\0\0\0");
+        program.extend_from_slice(&[DW_LNS_SET_FILE, 2, DW_LNS_CONST_ADD_PC, DW_LNS_ADVANCE_LINE, 3, DW_LNS_COPY]);
+        // The sequence ends at 0x0700003a.
+        program.extend_from_slice(&[DW_LNS_FIXED_ADVANCE_PC, 0x10, 0x00, 0, 1, DW_LNE_END_SEQUENCE]);
+        program
+    }
+
+    #[test]
+    fn line_program_maps_addresses_to_lines() {
+        for version in 2..5 {
+            let table = LineTable::parse(&unit(version, &program())).unwrap();
+
+            assert_eq!(line(&table, 0x06fffffe), None);
+            assert_eq!(line(&table, 0x07000000), Some(("src/main.c", 2)));
+            assert_eq!(line(&table, 0x07000002), Some(("src/main.c", 2)));
+            assert_eq!(line(&table, 0x07000004), Some(("src/main.c", 5)));
+            assert_eq!(line(&table, 0x07000006), Some(("src/main.c", 5)));
+            assert_eq!(line(&table, 0x07000008), Some(("src/main.c", 6)));
+            assert_eq!(line(&table, 0x07000028), Some(("src/main.c", 6)));
+            assert_eq!(line(&table, 0x0700002a), Some(("util.c", 9)));
+            assert_eq!(line(&table, 0x07000038), Some(("util.c", 9)));
+            assert_eq!(line(&table, 0x0700003a), None);
+        }
+    }
+
+    #[test]
+    fn units_share_the_file_list() {
+        let mut debug_line = unit(2, &program());
+        let mut second = set_address(0x07000100);
+        second.extend_from_slice(&[DW_LNS_COPY, DW_LNS_ADVANCE_PC, 1, 0, 1, DW_LNE_END_SEQUENCE]);
+        debug_line.extend_from_slice(&unit(3, &second));
+
+        let table = LineTable::parse(&debug_line).unwrap();
+        assert_eq!(line(&table, 0x07000100), Some(("src/main.c", 1)));
+        assert_eq!(table.lookup(0x07000100).unwrap().file, table.lookup(0x07000000).unwrap().file);
+        assert_eq!(line(&table, 0x07000102), None);
+    }
+
+    #[test]
+    fn huge_pc_advances_wrap() {
+        let mut program = set_address(0x07000000);
+        // 0x80000001 instructions of 2 bytes wraps around to 2 bytes on.
+        program.extend_from_slice(&[DW_LNS_ADVANCE_PC, 0x81, 0x80, 0x80, 0x80, 0x08, DW_LNS_COPY]);
+
+        let table = LineTable::parse(&unit(2, &program)).unwrap();
+        assert_eq!(line(&table, 0x07000000), None);
+        assert_eq!(line(&table, 0x07000002), Some(("src/main.c", 1)));
+    }
+
+    #[test]
+    fn bad_units_are_rejected() {
+        assert!(LineTable::parse(&unit(5, &program())).is_err());
+
+        let debug_line = unit(2, &program());
+        assert!(LineTable::parse(&debug_line[..debug_line.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn huge_extended_opcode_lengths_are_rejected() {
+        let mut program = set_address(0x07000000);
+        program.extend_from_slice(&[0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, DW_LNE_END_SEQUENCE]);
+
+        assert!(LineTable::parse(&unit(2, &program)).is_err());
+    }
+}
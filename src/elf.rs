@@ -0,0 +1,385 @@
+use std::path::Path;
+use std::fs::File;
+use std::io::{self, Read, Error, ErrorKind};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+
+const PT_LOAD: u32 = 1;
+
+const SHT_SYMTAB: u32 = 2;
+const SHN_UNDEF: u16 = 0;
+
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+const ROM_BASE: u32 = 0x07000000;
+const ROM_SPACE_SIZE: u64 = 0x01000000;
+const MIN_ROM_SIZE: u64 = 0x400;
+
+// The header and vectors at the top of ROM.
+const HEADER_AREA_SIZE: u64 = 0x220;
+
+const PROGRAM_HEADER_SIZE: usize = 32;
+const SECTION_HEADER_SIZE: usize = 40;
+const SYMBOL_SIZE: usize = 16;
+
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub addr: u32,
+    pub offset: u32,
+    pub size: u32,
+    pub link: u32,
+}
+
+// A PT_LOAD segment. The file bytes live at paddr (where the ROM holds
+// them) and are used at vaddr, which differs for initialised data that the
+// startup code copies into RAM.
+pub struct Segment {
+    pub offset: u32,
+    pub vaddr: u32,
+    pub paddr: u32,
+    pub file_size: u32,
+    pub mem_size: u32,
+}
+
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,
+    pub size: u32,
+}
+
+// Just enough of a 32 bit little endian ELF file, as produced by gccvb, to
+// get at its sections and symbols.
+pub struct Elf {
+    bytes: Box<[u8]>,
+    entry: u32,
+    segments: Vec<Segment>,
+    sections: Vec<Section>,
+}
+
+impl Elf {
+    pub fn load<P: AsRef<Path>>(file_name: P) -> io::Result<Elf> {
+        let mut bytes = Vec::new();
+        File::open(file_name)?.read_to_end(&mut bytes)?;
+
+        Elf::parse(bytes)
+    }
+
+    pub fn parse(bytes: Vec<u8>) -> io::Result<Elf> {
+        if !is_elf(&bytes) {
+            return Err(invalid_data("Not an ELF file."));
+        }
+
+        if bytes[4] != ELFCLASS32 || bytes[5] != ELFDATA2LSB {
+            return Err(invalid_data("Only 32 bit little endian ELF files are supported."));
+        }
+
+        let mut elf = Elf {
+            bytes: bytes.into_boxed_slice(),
+            entry: 0,
+            segments: Vec::new(),
+            sections: Vec::new(),
+        };
+
+        elf.entry = elf.read_word(0x18)?;
+
+        let program_headers_offset = elf.read_word(0x1c)? as usize;
+        let program_header_count = elf.read_halfword(0x2c)? as usize;
+
+        for index in 0..program_header_count {
+            let offset = program_headers_offset + index * PROGRAM_HEADER_SIZE;
+
+            if elf.read_word(offset)? != PT_LOAD {
+                continue;
+            }
+
+            let segment = Segment {
+                offset: elf.read_word(offset + 0x04)?,
+                vaddr: elf.read_word(offset + 0x08)?,
+                paddr: elf.read_word(offset + 0x0c)?,
+                file_size: elf.read_word(offset + 0x10)?,
+                mem_size: elf.read_word(offset + 0x14)?,
+            };
+            elf.segment_bytes(&segment)?;
+
+            elf.segments.push(segment);
+        }
+
+        let section_headers_offset = elf.read_word(0x20)? as usize;
+        let section_header_count = elf.read_halfword(0x30)? as usize;
+        let names_index = elf.read_halfword(0x32)? as usize;
+
+        let mut sections = Vec::new();
+        let mut name_offsets = Vec::new();
+
+        for index in 0..section_header_count {
+            let offset = section_headers_offset + index * SECTION_HEADER_SIZE;
+
+            name_offsets.push(elf.read_word(offset)?);
+            sections.push(Section {
+                name: String::new(),
+                kind: elf.read_word(offset + 0x04)?,
+                addr: elf.read_word(offset + 0x0c)?,
+                offset: elf.read_word(offset + 0x10)?,
+                size: elf.read_word(offset + 0x14)?,
+                link: elf.read_word(offset + 0x18)?,
+            });
+        }
+
+        if let Some(names) = sections.get(names_index) {
+            let names = elf.section_bytes(names)?;
+
+            for (section, &name_offset) in sections.iter_mut().zip(name_offsets.iter()) {
+                section.name = read_string(names, name_offset as usize)?;
+            }
+        }
+
+        elf.sections = sections;
+
+        Ok(elf)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn segment_bytes(&self, segment: &Segment) -> io::Result<&[u8]> {
+        let start = segment.offset as usize;
+        let end = start + segment.file_size as usize;
+
+        self.bytes.get(start..end).ok_or_else(|| invalid_data("Segment extends past the end of the file."))
+    }
+
+    // Lays the segments stored in ROM out as a .vb image. The linker places
+    // code from 0x07000000 up and the header and vectors just below the top
+    // of the 16 Mb ROM space (or its mirror at 0xfffffxxx); the image is
+    // padded between the two to a power of two, so the header ends up at its
+    // end as on a real cartridge.
+    pub fn rom_image(&self) -> io::Result<Vec<u8>> {
+        let mut low_extent = 0u64;
+        let mut high_extent = HEADER_AREA_SIZE;
+
+        for segment in self.rom_segments() {
+            let start = (segment.paddr & 0x00ffffff) as u64;
+            let end = start + segment.file_size as u64;
+
+            if end > ROM_SPACE_SIZE {
+                return Err(invalid_data("Segment runs past the end of ROM."));
+            }
+
+            if start >= ROM_SPACE_SIZE / 2 {
+                high_extent = high_extent.max(ROM_SPACE_SIZE - start);
+            } else {
+                low_extent = low_extent.max(end);
+            }
+        }
+
+        let size = (low_extent + high_extent).next_power_of_two().max(MIN_ROM_SIZE);
+        if size > ROM_SPACE_SIZE {
+            return Err(invalid_data("Segments don't fit in a 16 Mb ROM."));
+        }
+
+        let rom_mask = (size - 1) as u32;
+        let mut rom = vec![0xff; size as usize];
+
+        for segment in self.rom_segments() {
+            for (i, &byte) in self.segment_bytes(segment)?.iter().enumerate() {
+                rom[(segment.paddr.wrapping_add(i as u32) & rom_mask) as usize] = byte;
+            }
+        }
+
+        Ok(rom)
+    }
+
+    // Segments the program expects to already be in RAM, rather than
+    // copied there from ROM by its startup code, with .bss zero filled.
+    pub fn ram_segments(&self) -> io::Result<Vec<(u32, Vec<u8>)>> {
+        let mut segments = Vec::new();
+
+        for segment in self.segments.iter().filter(|segment| !is_rom_addr(segment.paddr)) {
+            let mut bytes = self.segment_bytes(segment)?.to_vec();
+            bytes.resize(segment.mem_size.max(segment.file_size) as usize, 0);
+
+            segments.push((segment.vaddr, bytes));
+        }
+
+        Ok(segments)
+    }
+
+    fn rom_segments(&self) -> impl Iterator<Item = &Segment> {
+        self.segments.iter().filter(|segment| is_rom_addr(segment.paddr) && segment.file_size > 0)
+    }
+
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    pub fn section_bytes(&self, section: &Section) -> io::Result<&[u8]> {
+        let start = section.offset as usize;
+        let end = start + section.size as usize;
+
+        self.bytes.get(start..end).ok_or_else(|| invalid_data("Section extends past the end of the file."))
+    }
+
+    // Named functions and data objects, skipping section and file symbols
+    // and compiler-local labels that couldn't be typed as debugger labels.
+    pub fn symbols(&self) -> io::Result<Vec<Symbol>> {
+        let mut symbols = Vec::new();
+
+        for symtab in self.sections.iter().filter(|section| section.kind == SHT_SYMTAB) {
+            let names = match self.sections.get(symtab.link as usize) {
+                Some(strtab) => self.section_bytes(strtab)?,
+                None => return Err(invalid_data("Symbol table has no string table.")),
+            };
+            let entries = self.section_bytes(symtab)?;
+
+            for entry in entries.chunks(SYMBOL_SIZE).filter(|entry| entry.len() == SYMBOL_SIZE) {
+                let name_offset = le_word(&entry[0..4]) as usize;
+                let value = le_word(&entry[4..8]);
+                let size = le_word(&entry[8..12]);
+                let kind = entry[12] & 0x0f;
+                let section_index = (entry[14] as u16) | ((entry[15] as u16) << 8);
+
+                if section_index == SHN_UNDEF || !matches!(kind, STT_NOTYPE | STT_OBJECT | STT_FUNC) {
+                    continue;
+                }
+
+                let name = read_string(names, name_offset)?;
+                if name.is_empty() || !name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_') {
+                    continue;
+                }
+
+                symbols.push(Symbol { name, value, size });
+            }
+        }
+
+        Ok(symbols)
+    }
+
+    fn read_halfword(&self, offset: usize) -> io::Result<u16> {
+        self.bytes.get(offset..offset + 2)
+            .map(|bytes| (bytes[0] as u16) | ((bytes[1] as u16) << 8))
+            .ok_or_else(|| invalid_data("Truncated ELF file."))
+    }
+
+    fn read_word(&self, offset: usize) -> io::Result<u32> {
+        self.bytes.get(offset..offset + 4)
+            .map(le_word)
+            .ok_or_else(|| invalid_data("Truncated ELF file."))
+    }
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(ELF_MAGIC)
+}
+
+fn is_rom_addr(addr: u32) -> bool {
+    addr & 0x07ffffff >= ROM_BASE
+}
+
+fn le_word(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) | ((bytes[1] as u32) << 8) | ((bytes[2] as u32) << 16) | ((bytes[3] as u32) << 24)
+}
+
+fn read_string(table: &[u8], offset: usize) -> io::Result<String> {
+    let bytes = table.get(offset..).ok_or_else(|| invalid_data("String offset out of range."))?;
+    let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+
+    Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE_HEADER_SIZE: usize = 0x34;
+
+    // Builds an ELF file with a PT_LOAD segment per (vaddr, paddr, bytes,
+    // mem_size) and no sections.
+    fn elf(segments: &[(u32, u32, &[u8], u32)]) -> Elf {
+        let mut bytes = vec![0; FILE_HEADER_SIZE];
+        bytes[..4].copy_from_slice(ELF_MAGIC);
+        bytes[4] = ELFCLASS32;
+        bytes[5] = ELFDATA2LSB;
+        bytes[0x18..0x1c].copy_from_slice(&0xfffffff0u32.to_le_bytes());
+        bytes[0x1c..0x20].copy_from_slice(&(FILE_HEADER_SIZE as u32).to_le_bytes());
+        bytes[0x2c..0x2e].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+        let mut offset = FILE_HEADER_SIZE + segments.len() * PROGRAM_HEADER_SIZE;
+        for &(vaddr, paddr, data, mem_size) in segments {
+            for word in &[PT_LOAD, offset as u32, vaddr, paddr, data.len() as u32, mem_size, 0, 0] {
+                bytes.extend_from_slice(&word.to_le_bytes());
+            }
+            offset += data.len();
+        }
+        for &(_, _, data, _) in segments {
+            bytes.extend_from_slice(data);
+        }
+
+        Elf::parse(bytes).unwrap()
+    }
+
+    #[test]
+    fn rom_image_places_code_low_and_vectors_high() {
+        let code = [0x01, 0x02, 0x03, 0x04];
+        let vectors = [0xaa; 0x10];
+        let elf = elf(&[
+            (0x07000000, 0x07000000, &code, 4),
+            (0xfffffff0, 0xfffffff0, &vectors, 0x10),
+        ]);
+
+        let rom = elf.rom_image().unwrap();
+        assert_eq!(rom.len(), 0x400);
+        assert_eq!(&rom[..4], &code);
+        assert!(rom[4..0x3f0].iter().all(|&byte| byte == 0xff));
+        assert_eq!(&rom[0x3f0..], &vectors);
+    }
+
+    #[test]
+    fn rom_image_grows_to_fit_both_ends() {
+        let code = [0x55; 0x300];
+        let header = [0xaa; 0x20];
+        let elf = elf(&[
+            (0x07000000, 0x07000000, &code, 0x300),
+            (0x07fffde0, 0x07fffde0, &header, 0x20),
+        ]);
+
+        let rom = elf.rom_image().unwrap();
+        assert_eq!(rom.len(), 0x800);
+        assert_eq!(&rom[..0x300], &code[..]);
+        assert_eq!(&rom[0x5e0..0x600], &header);
+        assert!(rom[0x600..].iter().all(|&byte| byte == 0xff));
+    }
+
+    #[test]
+    fn ram_segments_are_kept_out_of_rom() {
+        let data = [0x12, 0x34];
+        let elf = elf(&[
+            (0x07000000, 0x07000000, &[0x01, 0x02], 2),
+            (0x05000000, 0x05000000, &data, 4),
+        ]);
+
+        assert_eq!(elf.rom_image().unwrap().len(), 0x400);
+        assert_eq!(elf.ram_segments().unwrap(), vec![(0x05000000, vec![0x12, 0x34, 0, 0])]);
+    }
+}
@@ -0,0 +1,58 @@
+use rom::*;
+use instruction::*;
+
+use std::fmt;
+use std::error;
+use std::io;
+
+#[derive(Debug)]
+pub enum AvbError {
+    Io(io::Error),
+    Rom(RomError),
+    // An access to an address nothing is mapped at, by the instruction at pc.
+    BusFault { pc: u32, addr: u32 },
+    // An exception raised while the NP flag is set, with its exception code.
+    FatalException { pc: u32, code: u32 },
+    Decode(DecodeError),
+    Unimplemented(Instruction),
+    Command(String),
+    Usage(String),
+}
+
+impl fmt::Display for AvbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AvbError::Io(ref e) => write!(f, "{}", e),
+            AvbError::Rom(RomError::Patch(ref e)) => write!(f, "Unable to apply patch: {}", e),
+            AvbError::Rom(ref e) => write!(f, "Unable to load ROM: {}", e),
+            AvbError::BusFault { pc, addr } => write!(f, "Bus fault at 0x{:08x}: nothing is mapped at 0x{:08x}", pc, addr),
+            AvbError::FatalException { pc, code } => {
+                write!(f, "Fatal exception at 0x{:08x}: exception 0x{:04x} raised with PSW.NP set", pc, code)
+            }
+            AvbError::Decode(ref e) => write!(f, "{}", e),
+            AvbError::Unimplemented(ref instruction) => write!(f, "Unimplemented instruction at 0x{:08x}: {}", instruction.addr, instruction),
+            AvbError::Command(ref message) => write!(f, "{}", message),
+            AvbError::Usage(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl error::Error for AvbError {}
+
+impl From<io::Error> for AvbError {
+    fn from(e: io::Error) -> AvbError {
+        AvbError::Io(e)
+    }
+}
+
+impl From<RomError> for AvbError {
+    fn from(e: RomError) -> AvbError {
+        AvbError::Rom(e)
+    }
+}
+
+impl From<DecodeError> for AvbError {
+    fn from(e: DecodeError) -> AvbError {
+        AvbError::Decode(e)
+    }
+}
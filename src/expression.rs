@@ -0,0 +1,433 @@
+use instruction::*;
+use interconnect::*;
+use nvc::*;
+use assembler::register_number;
+
+use nom::{IResult, eof, space, hex_digit};
+
+use std::fmt;
+use std::str::{self, FromStr};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
+    Byte,
+    Halfword,
+    Word,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    LogicalNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Xor,
+    Or,
+    LogicalAnd,
+    LogicalOr,
+}
+
+impl BinaryOp {
+    // Same ordering as C; higher binds tighter.
+    fn precedence(self) -> u32 {
+        match self {
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 10,
+            BinaryOp::Add | BinaryOp::Sub => 9,
+            BinaryOp::Shl | BinaryOp::Shr => 8,
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 7,
+            BinaryOp::Eq | BinaryOp::Ne => 6,
+            BinaryOp::And => 5,
+            BinaryOp::Xor => 4,
+            BinaryOp::Or => 3,
+            BinaryOp::LogicalAnd => 2,
+            BinaryOp::LogicalOr => 1,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::And => "&",
+            BinaryOp::Xor => "^",
+            BinaryOp::Or => "|",
+            BinaryOp::LogicalAnd => "&&",
+            BinaryOp::LogicalOr => "||",
+        }
+    }
+}
+
+// Debugger expressions. Values are 32 bit and wrap; comparisons are unsigned
+// and, like the logical operators, give 0 or 1.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(u32),
+    Label(String),
+    Gpr(usize),
+    Pc,
+    SystemRegister(usize),
+    PswFlag(PswFlag),
+    Memory(Box<Expression>, Size),
+    Unary(UnaryOp, Box<Expression>),
+    Binary(BinaryOp, Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    pub fn eval(&self, cpu: &Nvc, interconnect: &Interconnect, labels: &HashMap<String, u32>) -> Result<u32, String> {
+        let eval = |expr: &Expression| expr.eval(cpu, interconnect, labels);
+
+        match *self {
+            Expression::Number(value) => Ok(value),
+            Expression::Label(ref name) => labels.get(name)
+                .cloned()
+                .ok_or_else(|| format!("Unknown label: .{}", name)),
+            Expression::Gpr(index) => Ok(cpu.reg_gpr(index)),
+            Expression::Pc => Ok(cpu.reg_pc()),
+            Expression::SystemRegister(index) => Ok(cpu.reg_system(index)),
+            Expression::PswFlag(flag) => Ok(cpu.psw_flag(flag)),
+            // The debugger looking at memory mustn't trip watchpoints.
+            Expression::Memory(ref addr, size) => {
+                let addr = eval(addr)?;
+                Ok(match size {
+                    Size::Byte => interconnect.peek_byte(addr) as u32,
+                    Size::Halfword => interconnect.peek_halfword(addr) as u32,
+                    Size::Word => interconnect.peek_word(addr),
+                })
+            }
+            Expression::Unary(op, ref operand) => {
+                let value = eval(operand)?;
+                Ok(match op {
+                    UnaryOp::Neg => value.wrapping_neg(),
+                    UnaryOp::Not => !value,
+                    UnaryOp::LogicalNot => (value == 0) as u32,
+                })
+            }
+            // Short circuit, so `r5 != 0 && [r5].w == 1` never reads through a null pointer.
+            Expression::Binary(BinaryOp::LogicalAnd, ref lhs, ref rhs) => {
+                Ok((eval(lhs)? != 0 && eval(rhs)? != 0) as u32)
+            }
+            Expression::Binary(BinaryOp::LogicalOr, ref lhs, ref rhs) => {
+                Ok((eval(lhs)? != 0 || eval(rhs)? != 0) as u32)
+            }
+            Expression::Binary(op, ref lhs, ref rhs) => {
+                let lhs = eval(lhs)?;
+                let rhs = eval(rhs)?;
+
+                Ok(match op {
+                    BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                    BinaryOp::Div => lhs.checked_div(rhs).ok_or("Division by zero")?,
+                    BinaryOp::Rem => lhs.checked_rem(rhs).ok_or("Division by zero")?,
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Shl => lhs.checked_shl(rhs).unwrap_or(0),
+                    BinaryOp::Shr => lhs.checked_shr(rhs).unwrap_or(0),
+                    BinaryOp::Lt => (lhs < rhs) as u32,
+                    BinaryOp::Le => (lhs <= rhs) as u32,
+                    BinaryOp::Gt => (lhs > rhs) as u32,
+                    BinaryOp::Ge => (lhs >= rhs) as u32,
+                    BinaryOp::Eq => (lhs == rhs) as u32,
+                    BinaryOp::Ne => (lhs != rhs) as u32,
+                    BinaryOp::And => lhs & rhs,
+                    BinaryOp::Xor => lhs ^ rhs,
+                    BinaryOp::Or => lhs | rhs,
+                    BinaryOp::LogicalAnd | BinaryOp::LogicalOr => unreachable!(),
+                })
+            }
+        }
+    }
+}
+
+impl FromStr for Expression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match complete!(s.as_bytes(), terminated!(expression, eof)) {
+            IResult::Done(_, e) => Ok(e),
+            _ => Err(format!("Unable to parse expression: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expression::Number(value) => write!(f, "0x{:x}", value),
+            Expression::Label(ref name) => write!(f, ".{}", name),
+            Expression::Gpr(index) => write!(f, "r{}", index),
+            Expression::Pc => write!(f, "pc"),
+            Expression::SystemRegister(index) => write!(f, "{}", SystemRegister(index)),
+            Expression::PswFlag(flag) => write!(f, "psw.{}", flag.name()),
+            Expression::Memory(ref addr, size) => {
+                let suffix = match size {
+                    Size::Byte => "b",
+                    Size::Halfword => "h",
+                    Size::Word => "w",
+                };
+                write!(f, "[{}].{}", addr, suffix)
+            }
+            Expression::Unary(op, ref operand) => {
+                let symbol = match op {
+                    UnaryOp::Neg => "-",
+                    UnaryOp::Not => "~",
+                    UnaryOp::LogicalNot => "!",
+                };
+                write!(f, "{}", symbol)?;
+                write_operand(f, operand)
+            }
+            Expression::Binary(op, ref lhs, ref rhs) => {
+                write_operand(f, lhs)?;
+                write!(f, " {} ", op.symbol())?;
+                write_operand(f, rhs)
+            }
+        }
+    }
+}
+
+fn write_operand(f: &mut fmt::Formatter, expr: &Expression) -> fmt::Result {
+    match *expr {
+        Expression::Binary(..) => write!(f, "({})", expr),
+        _ => write!(f, "{}", expr),
+    }
+}
+
+fn is_identifier_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+// Registers, pc, psw flags (psw.z, psw.cy, ...) and system registers by name.
+fn named_value(name: &str, field: Option<&str>) -> Result<Expression, ()> {
+    match (name, field) {
+        ("pc", None) => Ok(Expression::Pc),
+        ("psw", Some(field)) => PswFlag::from_name(field).map(Expression::PswFlag).ok_or(()),
+        (_, Some(_)) => Err(()),
+        _ => register_number(name).map(Expression::Gpr)
+            .or_else(|_| (0..32).find(|&n| SystemRegister(n).to_string() == name).map(Expression::SystemRegister).ok_or(())),
+    }
+}
+
+named!(
+    pub expression<Expression>,
+    call!(binary, 1)
+);
+
+// Precedence climbing: parses operators binding at least as tightly as
+// min_precedence, leaving looser ones to the caller.
+fn binary(input: &[u8], min_precedence: u32) -> IResult<&[u8], Expression> {
+    let (mut input, mut lhs) = match unary(input) {
+        IResult::Done(rest, lhs) => (rest, lhs),
+        IResult::Error(e) => return IResult::Error(e),
+        IResult::Incomplete(n) => return IResult::Incomplete(n),
+    };
+
+    loop {
+        let (rest, op) = match binary_operator(input) {
+            IResult::Done(rest, op) if op.precedence() >= min_precedence => (rest, op),
+            _ => break,
+        };
+
+        let (rest, rhs) = match binary(rest, op.precedence() + 1) {
+            IResult::Done(rest, rhs) => (rest, rhs),
+            _ => break,
+        };
+
+        input = rest;
+        lhs = Expression::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+
+    IResult::Done(input, lhs)
+}
+
+named!(
+    binary_operator<BinaryOp>,
+    chain!(
+        opt!(space) ~
+        op: alt_complete!(
+            map!(tag!("||"), |_| BinaryOp::LogicalOr) |
+            map!(tag!("&&"), |_| BinaryOp::LogicalAnd) |
+            map!(tag!("<<"), |_| BinaryOp::Shl) |
+            map!(tag!(">>"), |_| BinaryOp::Shr) |
+            map!(tag!("<="), |_| BinaryOp::Le) |
+            map!(tag!(">="), |_| BinaryOp::Ge) |
+            map!(tag!("=="), |_| BinaryOp::Eq) |
+            map!(tag!("!="), |_| BinaryOp::Ne) |
+            map!(tag!("<"), |_| BinaryOp::Lt) |
+            map!(tag!(">"), |_| BinaryOp::Gt) |
+            map!(tag!("|"), |_| BinaryOp::Or) |
+            map!(tag!("&"), |_| BinaryOp::And) |
+            map!(tag!("^"), |_| BinaryOp::Xor) |
+            map!(tag!("+"), |_| BinaryOp::Add) |
+            map!(tag!("-"), |_| BinaryOp::Sub) |
+            map!(tag!("*"), |_| BinaryOp::Mul) |
+            map!(tag!("/"), |_| BinaryOp::Div) |
+            map!(tag!("%"), |_| BinaryOp::Rem)
+        ) ~
+        opt!(space),
+        || op
+    )
+);
+
+named!(
+    unary<Expression>,
+    alt_complete!(
+        chain!(
+            op: alt_complete!(
+                map!(char!('-'), |_| UnaryOp::Neg) |
+                map!(char!('~'), |_| UnaryOp::Not) |
+                map!(char!('!'), |_| UnaryOp::LogicalNot)
+            ) ~
+            opt!(space) ~
+            operand: unary,
+            || Expression::Unary(op, Box::new(operand))
+        ) |
+        primary
+    )
+);
+
+named!(
+    primary<Expression>,
+    alt_complete!(
+        chain!(
+            char!('(') ~ opt!(space) ~ e: expression ~ opt!(space) ~ char!(')'),
+            || e
+        ) |
+        memory |
+        map!(preceded!(char!('.'), identifier), Expression::Label) |
+        map_res!(
+            chain!(
+                name: identifier ~
+                field: opt!(complete!(preceded!(char!('.'), identifier))),
+                || (name, field)
+            ),
+            |(name, field): (String, Option<String>)| named_value(&name, field.as_deref())
+        ) |
+        map!(number, Expression::Number)
+    )
+);
+
+// [addr] reads a word; [addr].b and [addr].h read a byte or halfword.
+named!(
+    memory<Expression>,
+    chain!(
+        char!('[') ~ opt!(space) ~ addr: expression ~ opt!(space) ~ char!(']') ~
+        size: opt!(complete!(
+            alt_complete!(
+                map!(tag!(".b"), |_| Size::Byte) |
+                map!(tag!(".h"), |_| Size::Halfword) |
+                map!(tag!(".w"), |_| Size::Word)
+            )
+        )),
+        || Expression::Memory(Box::new(addr), size.unwrap_or(Size::Word))
+    )
+);
+
+// Numbers are hex, with or without a prefix, like everywhere else in the
+// debugger.
+named!(
+    number<u32>,
+    map_res!(
+        map_res!(
+            preceded!(
+                opt!(
+                    alt_complete!(
+                        tag!("0x") | tag!("$")
+                    )
+                ),
+                hex_digit
+            ),
+            str::from_utf8
+        ),
+        |s| u32::from_str_radix(s, 16)
+    )
+);
+
+named!(
+    identifier<String>,
+    map_res!(
+        map_res!(
+            take_while1!(is_identifier_char),
+            str::from_utf8
+        ),
+        FromStr::from_str
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> Result<u32, String> {
+        let interconnect = Interconnect::with_blank_rom();
+        let expression = text.parse::<Expression>()?;
+        expression.eval(&Nvc::new(), &interconnect, &HashMap::new())
+    }
+
+    #[test]
+    fn operators_bind_like_c() {
+        assert_eq!(eval("2 + 3 * 4"), Ok(14));
+        assert_eq!(eval("(2 + 3) * 4"), Ok(20));
+        assert_eq!(eval("1 << 2 + 1"), Ok(8));
+        assert_eq!(eval("6 & 3 == 3"), Ok(0));
+        assert_eq!(eval("1 | 2 ^ 3 & 2"), Ok(1));
+        assert_eq!(eval("1 + 1 < 3 && 0 || 1"), Ok(1));
+        assert_eq!(eval("-1 + 2"), Ok(1));
+        assert_eq!(eval("!0 + 1"), Ok(2));
+    }
+
+    #[test]
+    fn operators_of_equal_precedence_group_left_to_right() {
+        assert_eq!(eval("a - 3 - 2"), Ok(5));
+        assert_eq!(eval("18 / 3 / 2"), Ok(4));
+        assert_eq!(eval("10 - 4 + 1"), Ok(0xd));
+    }
+
+    #[test]
+    fn printing_keeps_the_grouping() {
+        let expression = "1 + 2 * 3 - 4".parse::<Expression>().unwrap();
+        assert_eq!(expression.to_string(), "(0x1 + (0x2 * 0x3)) - 0x4");
+        assert_eq!(expression.to_string().parse::<Expression>(), Ok(expression));
+    }
+
+    #[test]
+    fn logical_operators_short_circuit() {
+        assert_eq!(eval("0 && 1 / 0"), Ok(0));
+        assert_eq!(eval("1 || .missing"), Ok(1));
+        assert!(eval("1 && 1 / 0").is_err());
+        assert!(eval("0 || .missing").is_err());
+    }
+
+    #[test]
+    fn logical_operators_give_0_or_1() {
+        assert_eq!(eval("5 && 7"), Ok(1));
+        assert_eq!(eval("0 || 7"), Ok(1));
+        assert_eq!(eval("!5"), Ok(0));
+    }
+}
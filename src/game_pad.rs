@@ -0,0 +1,28 @@
+// Button bits as they appear in the keypad registers, SDHR in the high
+// byte and SDLR in the low. Bit 1 always reads as set on a real pad.
+pub const PAD_SIGNATURE: u16 = 1 << 1;
+
+const BUTTONS: [(&str, u16); 14] = [
+    ("a", 1 << 2),
+    ("b", 1 << 3),
+    ("r", 1 << 4),
+    ("l", 1 << 5),
+    ("ru", 1 << 6),
+    ("rr", 1 << 7),
+    ("lr", 1 << 8),
+    ("ll", 1 << 9),
+    ("ld", 1 << 10),
+    ("lu", 1 << 11),
+    ("start", 1 << 12),
+    ("select", 1 << 13),
+    ("rl", 1 << 14),
+    ("rd", 1 << 15),
+];
+
+pub fn button_mask(name: &str) -> Option<u16> {
+    BUTTONS.iter().find(|&&(button, _)| button == name).map(|&(_, mask)| mask)
+}
+
+pub fn button_names(buttons: u16) -> Vec<&'static str> {
+    BUTTONS.iter().filter(|&&(_, mask)| buttons & mask != 0).map(|&(name, _)| name).collect()
+}
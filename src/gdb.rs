@@ -0,0 +1,440 @@
+use interconnect::*;
+use nvc::*;
+use avb::*;
+use watchpoint::*;
+use error::*;
+
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Read, Write, Error, ErrorKind};
+use std::net::TcpStream;
+use std::str;
+
+// Registers in the order g/G and p/P number them: r0-r31, then the 32
+// system registers in ldsr/stsr order, then pc.
+const NUM_GPRS: usize = 32;
+const NUM_SYSTEM_REGISTERS: usize = 32;
+const PC_REGISTER: usize = NUM_GPRS + NUM_SYSTEM_REGISTERS;
+const NUM_REGISTERS: usize = PC_REGISTER + 1;
+
+// How many instructions a continue runs between checks for a Ctrl-C from
+// the debugger.
+const INTERRUPT_POLL_STEPS: usize = 4096;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+const INTERRUPT: u8 = 0x03;
+
+// The largest packet we accept, as told to the debugger by qSupported. A
+// memory read's reply has two hex digits a byte, so it can't ask for more
+// than half that.
+const PACKET_SIZE: usize = 0x1000;
+const MAX_READ_LEN: u32 = (PACKET_SIZE / 2) as u32;
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+// A GDB remote serial protocol stub for a single connected debugger. It
+// runs the CPU itself while resumed and answers requests while stopped.
+// Breakpoints, software or hardware, are kept as a set of addresses rather
+// than patched into memory.
+pub struct GdbStub {
+    stream: TcpStream,
+    input: VecDeque<u8>,
+    breakpoints: HashSet<u32>,
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> GdbStub {
+        GdbStub {
+            stream,
+            input: VecDeque::new(),
+            breakpoints: HashSet::new(),
+        }
+    }
+
+    // Serves requests until the debugger detaches, kills the session or
+    // disconnects.
+    pub fn serve(&mut self, avb: &mut AVB) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet() {
+                Ok(packet) => packet,
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+
+            let (command, args) = match packet.split_first() {
+                Some((&command, args)) => (command, str::from_utf8(args).unwrap_or("")),
+                None => {
+                    self.write_packet("")?;
+                    continue;
+                }
+            };
+
+            let reply = match command {
+                b'?' => stop_reply(SIGTRAP),
+                b'g' => (0..NUM_REGISTERS).map(|index| hex_word(read_register(&avb.cpu, index))).collect(),
+                b'G' => write_registers(&mut avb.cpu, args),
+                b'p' => match usize::from_str_radix(args, 16) {
+                    Ok(index) if index < NUM_REGISTERS => hex_word(read_register(&avb.cpu, index)),
+                    _ => error_reply(),
+                },
+                b'P' => write_register(&mut avb.cpu, args),
+                b'm' => read_memory(&avb.interconnect, args),
+                b'M' => write_memory(&mut avb.interconnect, args),
+                b'c' | b's' => {
+                    if !args.is_empty() {
+                        match u32::from_str_radix(args, 16) {
+                            Ok(addr) => avb.cpu.set_reg_pc(addr),
+                            Err(_) => {
+                                self.write_packet(&error_reply())?;
+                                continue;
+                            }
+                        }
+                    }
+
+                    let resume = if command == b'c' { Resume::Continue } else { Resume::Step };
+                    self.resume(avb, resume)?
+                }
+                b'Z' | b'z' => self.set_breakpoint(&mut avb.interconnect, command == b'Z', args),
+                b'H' => "OK".into(),
+                b'q' if args == "Attached" => "1".into(),
+                b'q' if args.starts_with("Supported") => format!("PacketSize={:x}", PACKET_SIZE),
+                b'D' => {
+                    self.write_packet("OK")?;
+                    return Ok(());
+                }
+                b'k' => return Ok(()),
+                // Anything else, including vCont and the binary X write,
+                // gets the empty reply so the debugger falls back to the
+                // basic packets.
+                _ => String::new(),
+            };
+
+            self.write_packet(&reply)?;
+        }
+    }
+
+    // Steps the same way the built in debugger does, so tracing, rewind
+    // history and the game pad keep running under the remote one.
+    fn resume(&mut self, avb: &mut AVB, resume: Resume) -> io::Result<String> {
+        avb.interconnect.take_watch_hit();
+
+        let mut steps = 0usize;
+
+        loop {
+            match avb.step() {
+                Ok(()) => (),
+                Err(AvbError::BusFault { .. }) => return Ok(stop_reply(SIGSEGV)),
+                Err(_) => return Ok(stop_reply(SIGILL)),
+            }
+            steps += 1;
+
+            if let Some(hit) = avb.interconnect.take_watch_hit() {
+                let name = match avb.interconnect.watchpoints()[hit.index].kind {
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Write => "watch",
+                    WatchKind::Access => "awatch",
+                };
+                return Ok(format!("T{:02x}{}:{:08x};", SIGTRAP, name, hit.addr));
+            }
+
+            if let Resume::Step = resume {
+                return Ok(stop_reply(SIGTRAP));
+            }
+
+            if self.breakpoints.contains(&avb.cpu.reg_pc()) {
+                return Ok(stop_reply(SIGTRAP));
+            }
+
+            if steps.is_multiple_of(INTERRUPT_POLL_STEPS) && self.poll_interrupt()? {
+                return Ok(stop_reply(SIGINT));
+            }
+        }
+    }
+
+    // Types 0 and 1 are software and hardware breakpoints, 2 to 4 write,
+    // read and access watchpoints.
+    fn set_breakpoint(&mut self, interconnect: &mut Interconnect, insert: bool, args: &str) -> String {
+        let fields = args.split(',').collect::<Vec<_>>();
+        if fields.len() < 3 {
+            return error_reply();
+        }
+
+        let addr = u32::from_str_radix(fields[1], 16);
+        let len = u32::from_str_radix(fields[2], 16);
+        let (addr, len) = match (addr, len) {
+            (Ok(addr), Ok(len)) => (addr, len.max(1)),
+            _ => return error_reply(),
+        };
+
+        let kind = match fields[0] {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                return "OK".into();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+
+        let watchpoint = Watchpoint {
+            start: addr,
+            end: addr.wrapping_add(len - 1),
+            kind,
+            value: None,
+        };
+
+        if insert {
+            interconnect.add_watchpoint(watchpoint);
+        } else {
+            let index = interconnect.watchpoints().iter().position(|existing| {
+                existing.start == watchpoint.start && existing.end == watchpoint.end && existing.kind == kind
+            });
+
+            if let Some(index) = index {
+                interconnect.remove_watchpoint(index);
+            }
+        }
+
+        "OK".into()
+    }
+
+    // Packets are $data#checksum, each acknowledged with + (or - to ask for
+    // it again). Anything outside a packet, such as a stray Ctrl-C while
+    // already stopped, is skipped.
+    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            while self.read_byte()? != b'$' {}
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+
+            let checksum_digits = [self.read_byte()?, self.read_byte()?];
+            let checksum = str::from_utf8(&checksum_digits).ok().and_then(|digits| u8::from_str_radix(digits, 16).ok());
+
+            if checksum == Some(checksum_of(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(data);
+            }
+
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+
+            match self.read_byte()? {
+                b'-' => continue,
+                b'+' => return Ok(()),
+                // The debugger may skip acknowledging and send its next
+                // request straight away.
+                byte => {
+                    self.input.push_front(byte);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        if let Some(byte) = self.input.pop_front() {
+            return Ok(byte);
+        }
+
+        let mut buffer = [0; 1024];
+        let len = self.stream.read(&mut buffer)?;
+        if len == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Debugger disconnected."));
+        }
+
+        self.input.extend(&buffer[1..len]);
+        Ok(buffer[0])
+    }
+
+    // Checks, without blocking, whether the debugger has sent a Ctrl-C.
+    // Anything else that arrived is kept for the next packet.
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        let mut buffer = [0; 1024];
+
+        self.stream.set_nonblocking(true)?;
+        let result = self.stream.read(&mut buffer);
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Err(Error::new(ErrorKind::UnexpectedEof, "Debugger disconnected.")),
+            Ok(len) => {
+                let interrupted = buffer[..len].contains(&INTERRUPT);
+                self.input.extend(buffer[..len].iter().filter(|&&byte| byte != INTERRUPT));
+                Ok(interrupted)
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn read_register(cpu: &Nvc, index: usize) -> u32 {
+    match index {
+        PC_REGISTER => cpu.reg_pc(),
+        index if index < NUM_GPRS => cpu.reg_gpr(index),
+        index => cpu.reg_system(index - NUM_GPRS),
+    }
+}
+
+fn set_register(cpu: &mut Nvc, index: usize, value: u32) {
+    match index {
+        PC_REGISTER => cpu.set_reg_pc(value),
+        index if index < NUM_GPRS => cpu.set_reg_gpr(index, value),
+        index => cpu.set_reg_system(index - NUM_GPRS, value),
+    }
+}
+
+fn write_registers(cpu: &mut Nvc, args: &str) -> String {
+    let values = match parse_hex_bytes(args) {
+        Some(ref bytes) if bytes.len() == NUM_REGISTERS * 4 => {
+            bytes.chunks(4).map(le_word).collect::<Vec<_>>()
+        }
+        _ => return error_reply(),
+    };
+
+    for (index, value) in values.into_iter().enumerate() {
+        set_register(cpu, index, value);
+    }
+
+    "OK".into()
+}
+
+fn write_register(cpu: &mut Nvc, args: &str) -> String {
+    let mut fields = args.splitn(2, '=');
+    let index = fields.next().and_then(|index| usize::from_str_radix(index, 16).ok());
+    let value = fields.next().and_then(parse_hex_bytes);
+
+    match (index, value) {
+        (Some(index), Some(ref value)) if index < NUM_REGISTERS && value.len() == 4 => {
+            set_register(cpu, index, le_word(value));
+            "OK".into()
+        }
+        _ => error_reply(),
+    }
+}
+
+fn read_memory(interconnect: &Interconnect, args: &str) -> String {
+    let (addr, len) = match parse_addr_len(args) {
+        Some((addr, len)) if len <= MAX_READ_LEN => (addr, len),
+        _ => return error_reply(),
+    };
+
+    let addrs = (0..len).map(|offset| addr.wrapping_add(offset));
+    if !addrs.clone().all(|addr| interconnect.is_mapped(addr)) {
+        return error_reply();
+    }
+
+    addrs.map(|addr| format!("{:02x}", interconnect.peek_byte(addr))).collect()
+}
+
+fn write_memory(interconnect: &mut Interconnect, args: &str) -> String {
+    let mut fields = args.splitn(2, ':');
+    let range = fields.next().and_then(parse_addr_len);
+    let bytes = fields.next().and_then(parse_hex_bytes);
+
+    let (addr, bytes) = match (range, bytes) {
+        (Some((addr, len)), Some(bytes)) if bytes.len() == len as usize => (addr, bytes),
+        _ => return error_reply(),
+    };
+
+    if !(0..bytes.len() as u32).all(|offset| interconnect.is_mapped(addr.wrapping_add(offset))) {
+        return error_reply();
+    }
+
+    for (offset, &byte) in bytes.iter().enumerate() {
+        interconnect.patch_byte(addr.wrapping_add(offset as u32), byte);
+    }
+
+    "OK".into()
+}
+
+fn parse_addr_len(args: &str) -> Option<(u32, u32)> {
+    let mut fields = args.splitn(2, ',');
+    let addr = fields.next().and_then(|addr| u32::from_str_radix(addr, 16).ok())?;
+    let len = fields.next().and_then(|len| u32::from_str_radix(len, 16).ok())?;
+
+    Some((addr, len))
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len()).step_by(2)
+        .map(|index| hex.get(index..index + 2).and_then(|digits| u8::from_str_radix(digits, 16).ok()))
+        .collect()
+}
+
+// Registers go over the wire in target byte order.
+fn hex_word(value: u32) -> String {
+    value.to_le_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn le_word(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) | ((bytes[1] as u32) << 8) | ((bytes[2] as u32) << 16) | ((bytes[3] as u32) << 24)
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn error_reply() -> String {
+    "E01".into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WRAM: u32 = 0x05000000;
+
+    #[test]
+    fn memory_reads_are_hex() {
+        let mut interconnect = Interconnect::with_blank_rom();
+        interconnect.patch_byte(WRAM, 0x12);
+        interconnect.patch_byte(WRAM + 1, 0xab);
+
+        assert_eq!(read_memory(&interconnect, "5000000,2"), "12ab");
+        assert_eq!(read_memory(&interconnect, "1000000,1"), "E01");
+    }
+
+    #[test]
+    fn memory_reads_fit_in_a_packet() {
+        let interconnect = Interconnect::with_blank_rom();
+
+        let reply = read_memory(&interconnect, &format!("{:x},{:x}", WRAM, MAX_READ_LEN));
+        assert_eq!(reply.len(), PACKET_SIZE);
+
+        assert_eq!(read_memory(&interconnect, &format!("{:x},{:x}", WRAM, MAX_READ_LEN + 1)), "E01");
+        assert_eq!(read_memory(&interconnect, "5000000,ffffffff"), "E01");
+    }
+}
@@ -1,86 +1,766 @@
+use interconnect::*;
+
 use std::fmt;
+use std::error;
 
-#[derive(PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Opcode {
-    Movhi,
-    Movea,
+    MovReg,
+    Add,
+    Sub,
+    Cmp,
+    Shl,
+    Shr,
+    Jmp,
+    Sar,
+    Mul,
+    Div,
+    Mulu,
+    Divu,
+    Or,
+    And,
+    Xor,
+    Not,
+
     MovImm,
+    AddImm,
+    Setf,
+    CmpImm,
+    ShlImm,
+    ShrImm,
+    Cli,
+    SarImm,
+    Trap,
+    Reti,
+    Halt,
+    Ldsr,
+    Stsr,
+    Sei,
+
+    Sch0bsu,
+    Sch0bsd,
+    Sch1bsu,
+    Sch1bsd,
+    Orbsu,
+    Andbsu,
+    Xorbsu,
+    Movbsu,
+    Ornbsu,
+    Andnbsu,
+    Xornbsu,
+    Notbsu,
+
+    Bcond(Condition),
+
+    Movea,
+    Addi,
+    Jr,
+    Jal,
+    Ori,
+    Andi,
+    Xori,
+    Movhi,
+
+    Ldb,
+    Ldh,
+    Ldw,
     Stb,
-    Jmp,
-    Sub,
+    Sth,
+    Stw,
+    Inb,
+    Inh,
+    Caxi,
+    Inw,
+    Outb,
+    Outh,
     Outw,
+
+    Cmpf,
+    Cvtws,
+    Cvtsw,
+    Addf,
+    Subf,
+    Mulf,
+    Divf,
+    Xb,
+    Xh,
+    Rev,
+    Trnc,
+    Mpyhw,
 }
 
 impl Opcode {
-    pub fn from_halfword(halfword: u16) -> Opcode {
-        let opcode_bits = halfword >> 10;
-        match opcode_bits {
-            0b101111 => Opcode::Movhi,
-            0b101000 => Opcode::Movea,
+    fn from_halfwords(first_halfword: u16, second_halfword: u16) -> Option<Opcode> {
+        let opcode_bits = first_halfword >> 10;
+        let opcode = match opcode_bits {
+            0b000000 => Opcode::MovReg,
+            0b000001 => Opcode::Add,
+            0b000010 => Opcode::Sub,
+            0b000011 => Opcode::Cmp,
+            0b000100 => Opcode::Shl,
+            0b000101 => Opcode::Shr,
+            0b000110 => Opcode::Jmp,
+            0b000111 => Opcode::Sar,
+            0b001000 => Opcode::Mul,
+            0b001001 => Opcode::Div,
+            0b001010 => Opcode::Mulu,
+            0b001011 => Opcode::Divu,
+            0b001100 => Opcode::Or,
+            0b001101 => Opcode::And,
+            0b001110 => Opcode::Xor,
+            0b001111 => Opcode::Not,
             0b010000 => Opcode::MovImm,
+            0b010001 => Opcode::AddImm,
+            0b010010 => Opcode::Setf,
+            0b010011 => Opcode::CmpImm,
+            0b010100 => Opcode::ShlImm,
+            0b010101 => Opcode::ShrImm,
+            0b010110 => Opcode::Cli,
+            0b010111 => Opcode::SarImm,
+            0b011000 => Opcode::Trap,
+            0b011001 => Opcode::Reti,
+            0b011010 => Opcode::Halt,
+            0b011100 => Opcode::Ldsr,
+            0b011101 => Opcode::Stsr,
+            0b011110 => Opcode::Sei,
+            0b011111 => match first_halfword & 0x1f {
+                0b00000 => Opcode::Sch0bsu,
+                0b00001 => Opcode::Sch0bsd,
+                0b00010 => Opcode::Sch1bsu,
+                0b00011 => Opcode::Sch1bsd,
+                0b01000 => Opcode::Orbsu,
+                0b01001 => Opcode::Andbsu,
+                0b01010 => Opcode::Xorbsu,
+                0b01011 => Opcode::Movbsu,
+                0b01100 => Opcode::Ornbsu,
+                0b01101 => Opcode::Andnbsu,
+                0b01110 => Opcode::Xornbsu,
+                0b01111 => Opcode::Notbsu,
+                _ => return None,
+            },
+            0b100000..=0b100111 => Opcode::Bcond(Condition::from_bits(first_halfword >> 9)),
+            0b101000 => Opcode::Movea,
+            0b101001 => Opcode::Addi,
+            0b101010 => Opcode::Jr,
+            0b101011 => Opcode::Jal,
+            0b101100 => Opcode::Ori,
+            0b101101 => Opcode::Andi,
+            0b101110 => Opcode::Xori,
+            0b101111 => Opcode::Movhi,
+            0b110000 => Opcode::Ldb,
+            0b110001 => Opcode::Ldh,
+            0b110011 => Opcode::Ldw,
             0b110100 => Opcode::Stb,
-            0b000110 => Opcode::Jmp,
-            0b000010 => Opcode::Sub,
+            0b110101 => Opcode::Sth,
+            0b110111 => Opcode::Stw,
+            0b111000 => Opcode::Inb,
+            0b111001 => Opcode::Inh,
+            0b111010 => Opcode::Caxi,
+            0b111011 => Opcode::Inw,
+            0b111100 => Opcode::Outb,
+            0b111101 => Opcode::Outh,
+            0b111110 => match second_halfword >> 10 {
+                0b000000 => Opcode::Cmpf,
+                0b000010 => Opcode::Cvtws,
+                0b000011 => Opcode::Cvtsw,
+                0b000100 => Opcode::Addf,
+                0b000101 => Opcode::Subf,
+                0b000110 => Opcode::Mulf,
+                0b000111 => Opcode::Divf,
+                0b001000 => Opcode::Xb,
+                0b001001 => Opcode::Xh,
+                0b001010 => Opcode::Rev,
+                0b001011 => Opcode::Trnc,
+                0b001100 => Opcode::Mpyhw,
+                _ => return None,
+            },
             0b111111 => Opcode::Outw,
-            _ => panic!("Unrecognized opcode bits: {:06b}", opcode_bits)
+            _ => return None,
+        };
+
+        Some(opcode)
+    }
+
+    // The inverse of from_halfwords: the six opcode bits of the first halfword.
+    fn opcode_bits(&self) -> u16 {
+        match *self {
+            Opcode::MovReg => 0b000000,
+            Opcode::Add => 0b000001,
+            Opcode::Sub => 0b000010,
+            Opcode::Cmp => 0b000011,
+            Opcode::Shl => 0b000100,
+            Opcode::Shr => 0b000101,
+            Opcode::Jmp => 0b000110,
+            Opcode::Sar => 0b000111,
+            Opcode::Mul => 0b001000,
+            Opcode::Div => 0b001001,
+            Opcode::Mulu => 0b001010,
+            Opcode::Divu => 0b001011,
+            Opcode::Or => 0b001100,
+            Opcode::And => 0b001101,
+            Opcode::Xor => 0b001110,
+            Opcode::Not => 0b001111,
+            Opcode::MovImm => 0b010000,
+            Opcode::AddImm => 0b010001,
+            Opcode::Setf => 0b010010,
+            Opcode::CmpImm => 0b010011,
+            Opcode::ShlImm => 0b010100,
+            Opcode::ShrImm => 0b010101,
+            Opcode::Cli => 0b010110,
+            Opcode::SarImm => 0b010111,
+            Opcode::Trap => 0b011000,
+            Opcode::Reti => 0b011001,
+            Opcode::Halt => 0b011010,
+            Opcode::Ldsr => 0b011100,
+            Opcode::Stsr => 0b011101,
+            Opcode::Sei => 0b011110,
+            Opcode::Sch0bsu | Opcode::Sch0bsd | Opcode::Sch1bsu | Opcode::Sch1bsd |
+            Opcode::Orbsu | Opcode::Andbsu | Opcode::Xorbsu | Opcode::Movbsu |
+            Opcode::Ornbsu | Opcode::Andnbsu | Opcode::Xornbsu | Opcode::Notbsu => 0b011111,
+            Opcode::Bcond(condition) => 0b100000 | (condition.bits() >> 1),
+            Opcode::Movea => 0b101000,
+            Opcode::Addi => 0b101001,
+            Opcode::Jr => 0b101010,
+            Opcode::Jal => 0b101011,
+            Opcode::Ori => 0b101100,
+            Opcode::Andi => 0b101101,
+            Opcode::Xori => 0b101110,
+            Opcode::Movhi => 0b101111,
+            Opcode::Ldb => 0b110000,
+            Opcode::Ldh => 0b110001,
+            Opcode::Ldw => 0b110011,
+            Opcode::Stb => 0b110100,
+            Opcode::Sth => 0b110101,
+            Opcode::Stw => 0b110111,
+            Opcode::Inb => 0b111000,
+            Opcode::Inh => 0b111001,
+            Opcode::Caxi => 0b111010,
+            Opcode::Inw => 0b111011,
+            Opcode::Outb => 0b111100,
+            Opcode::Outh => 0b111101,
+            Opcode::Cmpf | Opcode::Cvtws | Opcode::Cvtsw | Opcode::Addf |
+            Opcode::Subf | Opcode::Mulf | Opcode::Divf | Opcode::Xb |
+            Opcode::Xh | Opcode::Rev | Opcode::Trnc | Opcode::Mpyhw => 0b111110,
+            Opcode::Outw => 0b111111,
         }
     }
 
+    // Bit string and format VII instructions share an opcode and are told
+    // apart by a sub-opcode.
+    fn sub_opcode_bits(&self) -> Option<u16> {
+        let bits = match *self {
+            Opcode::Sch0bsu => 0b00000,
+            Opcode::Sch0bsd => 0b00001,
+            Opcode::Sch1bsu => 0b00010,
+            Opcode::Sch1bsd => 0b00011,
+            Opcode::Orbsu => 0b01000,
+            Opcode::Andbsu => 0b01001,
+            Opcode::Xorbsu => 0b01010,
+            Opcode::Movbsu => 0b01011,
+            Opcode::Ornbsu => 0b01100,
+            Opcode::Andnbsu => 0b01101,
+            Opcode::Xornbsu => 0b01110,
+            Opcode::Notbsu => 0b01111,
+            Opcode::Cmpf => 0b000000,
+            Opcode::Cvtws => 0b000010,
+            Opcode::Cvtsw => 0b000011,
+            Opcode::Addf => 0b000100,
+            Opcode::Subf => 0b000101,
+            Opcode::Mulf => 0b000110,
+            Opcode::Divf => 0b000111,
+            Opcode::Xb => 0b001000,
+            Opcode::Xh => 0b001001,
+            Opcode::Rev => 0b001010,
+            Opcode::Trnc => 0b001011,
+            Opcode::Mpyhw => 0b001100,
+            _ => return None,
+        };
+
+        Some(bits)
+    }
+
     pub fn instruction_format(&self) -> InstructionFormat {
-        match self {          
-            &Opcode::Movhi => InstructionFormat::V,
-            &Opcode::Movea => InstructionFormat::V,
-            &Opcode::Jmp => InstructionFormat::I,
-            &Opcode::Sub => InstructionFormat::I,
-            &Opcode::MovImm => InstructionFormat::II,
-            &Opcode::Outw => InstructionFormat::VI,
-            &Opcode::Stb => InstructionFormat::VI,
+        match *self {
+            Opcode::MovReg | Opcode::Add | Opcode::Sub | Opcode::Cmp |
+            Opcode::Shl | Opcode::Shr | Opcode::Jmp | Opcode::Sar |
+            Opcode::Mul | Opcode::Div | Opcode::Mulu | Opcode::Divu |
+            Opcode::Or | Opcode::And | Opcode::Xor | Opcode::Not => InstructionFormat::I,
+
+            Opcode::MovImm | Opcode::AddImm | Opcode::Setf | Opcode::CmpImm |
+            Opcode::ShlImm | Opcode::ShrImm | Opcode::Cli | Opcode::SarImm |
+            Opcode::Trap | Opcode::Reti | Opcode::Halt | Opcode::Ldsr |
+            Opcode::Stsr | Opcode::Sei |
+            Opcode::Sch0bsu | Opcode::Sch0bsd | Opcode::Sch1bsu | Opcode::Sch1bsd |
+            Opcode::Orbsu | Opcode::Andbsu | Opcode::Xorbsu | Opcode::Movbsu |
+            Opcode::Ornbsu | Opcode::Andnbsu | Opcode::Xornbsu | Opcode::Notbsu => InstructionFormat::II,
+
+            Opcode::Bcond(_) => InstructionFormat::III,
+
+            Opcode::Jr | Opcode::Jal => InstructionFormat::IV,
+
+            Opcode::Movea | Opcode::Addi | Opcode::Ori | Opcode::Andi |
+            Opcode::Xori | Opcode::Movhi => InstructionFormat::V,
+
+            Opcode::Ldb | Opcode::Ldh | Opcode::Ldw | Opcode::Stb |
+            Opcode::Sth | Opcode::Stw | Opcode::Inb | Opcode::Inh |
+            Opcode::Caxi | Opcode::Inw | Opcode::Outb | Opcode::Outh |
+            Opcode::Outw => InstructionFormat::VI,
+
+            Opcode::Cmpf | Opcode::Cvtws | Opcode::Cvtsw | Opcode::Addf |
+            Opcode::Subf | Opcode::Mulf | Opcode::Divf | Opcode::Xb |
+            Opcode::Xh | Opcode::Rev | Opcode::Trnc | Opcode::Mpyhw => InstructionFormat::VII,
         }
     }
 
+    // Approximate timings; memory and bit string instructions also depend on
+    // wait states and operand length, which aren't modelled yet.
     pub fn num_cycles(&self) -> usize {
-        match self {
-            &Opcode::Jmp => 3,
-            &Opcode::MovImm => 1,
-            &Opcode::Sub => 1,
-            &Opcode::Movea => 1,
-            &Opcode::Movhi => 1,
-            &Opcode::Stb => 1,
-            &Opcode::Outw => 1,
+        match *self {
+            Opcode::Jmp | Opcode::Jr | Opcode::Jal => 3,
+            Opcode::Bcond(_) => 3,
+            Opcode::Mul | Opcode::Mulu => 13,
+            Opcode::Div => 38,
+            Opcode::Divu => 36,
+            Opcode::Setf | Opcode::Cli | Opcode::Sei => 1,
+            Opcode::Trap => 15,
+            Opcode::Reti => 10,
+            Opcode::Ldb | Opcode::Ldh | Opcode::Ldw => 5,
+            Opcode::Inb | Opcode::Inh | Opcode::Inw => 5,
+            Opcode::Caxi => 22,
+            Opcode::Sch0bsu | Opcode::Sch0bsd | Opcode::Sch1bsu | Opcode::Sch1bsd => 51,
+            Opcode::Orbsu | Opcode::Andbsu | Opcode::Xorbsu | Opcode::Movbsu |
+            Opcode::Ornbsu | Opcode::Andnbsu | Opcode::Xornbsu | Opcode::Notbsu => 49,
+            Opcode::Cmpf => 10,
+            Opcode::Cvtws => 16,
+            Opcode::Cvtsw => 14,
+            Opcode::Addf | Opcode::Subf => 28,
+            Opcode::Mulf => 30,
+            Opcode::Divf => 44,
+            Opcode::Trnc => 14,
+            Opcode::Rev => 22,
+            Opcode::Mpyhw => 9,
+            _ => 1,
         }
     }
 }
 
 impl fmt::Display for Opcode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mnemonic = match self {
-            &Opcode::Sub => "sub",
-            &Opcode::Movhi => "movhi",
-            &Opcode::Movea => "movea",
-            &Opcode::MovImm => "mov",
-            &Opcode::Jmp => "jmp",
-            &Opcode::Outw => "out.w",
-            &Opcode::Stb => "st.b",
+        let mnemonic = match *self {
+            Opcode::MovReg => "mov",
+            Opcode::Add => "add",
+            Opcode::Sub => "sub",
+            Opcode::Cmp => "cmp",
+            Opcode::Shl => "shl",
+            Opcode::Shr => "shr",
+            Opcode::Jmp => "jmp",
+            Opcode::Sar => "sar",
+            Opcode::Mul => "mul",
+            Opcode::Div => "div",
+            Opcode::Mulu => "mulu",
+            Opcode::Divu => "divu",
+            Opcode::Or => "or",
+            Opcode::And => "and",
+            Opcode::Xor => "xor",
+            Opcode::Not => "not",
+            Opcode::MovImm => "mov",
+            Opcode::AddImm => "add",
+            Opcode::Setf => "setf",
+            Opcode::CmpImm => "cmp",
+            Opcode::ShlImm => "shl",
+            Opcode::ShrImm => "shr",
+            Opcode::Cli => "cli",
+            Opcode::SarImm => "sar",
+            Opcode::Trap => "trap",
+            Opcode::Reti => "reti",
+            Opcode::Halt => "halt",
+            Opcode::Ldsr => "ldsr",
+            Opcode::Stsr => "stsr",
+            Opcode::Sei => "sei",
+            Opcode::Sch0bsu => "sch0bsu",
+            Opcode::Sch0bsd => "sch0bsd",
+            Opcode::Sch1bsu => "sch1bsu",
+            Opcode::Sch1bsd => "sch1bsd",
+            Opcode::Orbsu => "orbsu",
+            Opcode::Andbsu => "andbsu",
+            Opcode::Xorbsu => "xorbsu",
+            Opcode::Movbsu => "movbsu",
+            Opcode::Ornbsu => "ornbsu",
+            Opcode::Andnbsu => "andnbsu",
+            Opcode::Xornbsu => "xornbsu",
+            Opcode::Notbsu => "notbsu",
+            Opcode::Bcond(condition) => condition.branch_mnemonic(),
+            Opcode::Movea => "movea",
+            Opcode::Addi => "addi",
+            Opcode::Jr => "jr",
+            Opcode::Jal => "jal",
+            Opcode::Ori => "ori",
+            Opcode::Andi => "andi",
+            Opcode::Xori => "xori",
+            Opcode::Movhi => "movhi",
+            Opcode::Ldb => "ld.b",
+            Opcode::Ldh => "ld.h",
+            Opcode::Ldw => "ld.w",
+            Opcode::Stb => "st.b",
+            Opcode::Sth => "st.h",
+            Opcode::Stw => "st.w",
+            Opcode::Inb => "in.b",
+            Opcode::Inh => "in.h",
+            Opcode::Caxi => "caxi",
+            Opcode::Inw => "in.w",
+            Opcode::Outb => "out.b",
+            Opcode::Outh => "out.h",
+            Opcode::Outw => "out.w",
+            Opcode::Cmpf => "cmpf.s",
+            Opcode::Cvtws => "cvt.ws",
+            Opcode::Cvtsw => "cvt.sw",
+            Opcode::Addf => "addf.s",
+            Opcode::Subf => "subf.s",
+            Opcode::Mulf => "mulf.s",
+            Opcode::Divf => "divf.s",
+            Opcode::Xb => "xb",
+            Opcode::Xh => "xh",
+            Opcode::Rev => "rev",
+            Opcode::Trnc => "trnc.sw",
+            Opcode::Mpyhw => "mpyhw",
         };
         write!(f, "{}", mnemonic)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    V,
+    C,
+    Z,
+    Nh,
+    N,
+    T,
+    Lt,
+    Le,
+    Nv,
+    Nc,
+    Nz,
+    H,
+    P,
+    F,
+    Ge,
+    Gt,
+}
+
+impl Condition {
+    pub fn bits(&self) -> u16 {
+        *self as u16
+    }
+
+    pub fn from_bits(bits: u16) -> Condition {
+        match bits & 0x0f {
+            0x0 => Condition::V,
+            0x1 => Condition::C,
+            0x2 => Condition::Z,
+            0x3 => Condition::Nh,
+            0x4 => Condition::N,
+            0x5 => Condition::T,
+            0x6 => Condition::Lt,
+            0x7 => Condition::Le,
+            0x8 => Condition::Nv,
+            0x9 => Condition::Nc,
+            0xa => Condition::Nz,
+            0xb => Condition::H,
+            0xc => Condition::P,
+            0xd => Condition::F,
+            0xe => Condition::Ge,
+            _ => Condition::Gt,
+        }
+    }
+
+    pub fn branch_mnemonic(&self) -> &'static str {
+        match *self {
+            Condition::V => "bv",
+            Condition::C => "bc",
+            Condition::Z => "bz",
+            Condition::Nh => "bnh",
+            Condition::N => "bn",
+            Condition::T => "br",
+            Condition::Lt => "blt",
+            Condition::Le => "ble",
+            Condition::Nv => "bnv",
+            Condition::Nc => "bnc",
+            Condition::Nz => "bnz",
+            Condition::H => "bh",
+            Condition::P => "bp",
+            Condition::F => "nop",
+            Condition::Ge => "bge",
+            Condition::Gt => "bgt",
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Condition::V => "v",
+            Condition::C => "c",
+            Condition::Z => "z",
+            Condition::Nh => "nh",
+            Condition::N => "n",
+            Condition::T => "t",
+            Condition::Lt => "lt",
+            Condition::Le => "le",
+            Condition::Nv => "nv",
+            Condition::Nc => "nc",
+            Condition::Nz => "nz",
+            Condition::H => "h",
+            Condition::P => "p",
+            Condition::F => "f",
+            Condition::Ge => "ge",
+            Condition::Gt => "gt",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Named after the formats in the V810 architecture manual.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InstructionFormat {
     I,
     II,
+    III,
+    IV,
     V,
-    VI
+    VI,
+    VII,
 }
 
 impl InstructionFormat {
     pub fn has_second_halfword(&self) -> bool {
-        match self {
-            &InstructionFormat::I => false,
-            &InstructionFormat::II => false,
-            &InstructionFormat::V => true,
-            &InstructionFormat::VI => true,
+        match *self {
+            InstructionFormat::I => false,
+            InstructionFormat::II => false,
+            InstructionFormat::III => false,
+            InstructionFormat::IV => true,
+            InstructionFormat::V => true,
+            InstructionFormat::VI => true,
+            InstructionFormat::VII => true,
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operands {
+    I { reg1: usize, reg2: usize },
+    II { imm5: usize, reg2: usize },
+    III { disp9: i32 },
+    IV { disp26: i32 },
+    V { reg1: usize, reg2: usize, imm16: u16 },
+    VI { reg1: usize, reg2: usize, disp16: i16 },
+    VII { reg1: usize, reg2: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub addr: u32,
+    pub opcode: Opcode,
+    pub operands: Operands,
+    pub length: u32,
+    pub first_halfword: u16,
+    pub second_halfword: Option<u16>,
+}
+
+impl Instruction {
+    pub fn next_addr(&self) -> u32 {
+        self.addr.wrapping_add(self.length)
+    }
+
+    // Destination of a relative branch or jump; register jumps aren't known
+    // until run time.
+    pub fn branch_target(&self) -> Option<u32> {
+        match self.operands {
+            Operands::III { disp9 } => Some(self.addr.wrapping_add(disp9 as u32)),
+            Operands::IV { disp26 } => Some(self.addr.wrapping_add(disp26 as u32)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let opcode = self.opcode;
+
+        match self.operands {
+            Operands::I { reg1, .. } if opcode == Opcode::Jmp => write!(f, "jmp [r{}]", reg1),
+            Operands::I { reg1, reg2 } => write!(f, "{} r{}, r{}", opcode, reg1, reg2),
+
+            Operands::II { .. } if opcode.takes_no_operands() => write!(f, "{}", opcode),
+            Operands::II { imm5, .. } if opcode == Opcode::Trap => write!(f, "trap {}", imm5),
+            Operands::II { imm5, reg2 } if opcode == Opcode::Setf => {
+                write!(f, "setf {}, r{}", Condition::from_bits(imm5 as u16), reg2)
+            }
+            Operands::II { imm5, reg2 } if opcode == Opcode::Ldsr => {
+                write!(f, "ldsr r{}, {}", reg2, SystemRegister(imm5))
+            }
+            Operands::II { imm5, reg2 } if opcode == Opcode::Stsr => {
+                write!(f, "stsr {}, r{}", SystemRegister(imm5), reg2)
+            }
+            Operands::II { imm5, reg2 } if opcode.has_signed_imm5() => {
+                write!(f, "{} {}, r{}", opcode, sign_extend_imm5(imm5) as i32, reg2)
+            }
+            Operands::II { imm5, reg2 } => write!(f, "{} {}, r{}", opcode, imm5, reg2),
+
+            Operands::III { .. } if opcode == Opcode::Bcond(Condition::F) => write!(f, "nop"),
+            Operands::III { .. } | Operands::IV { .. } => {
+                write!(f, "{} 0x{:08x}", opcode, self.branch_target().unwrap())
+            }
+
+            Operands::V { reg1, reg2, imm16 } => write!(f, "{} {:#x}, r{}, r{}", opcode, imm16, reg1, reg2),
+
+            Operands::VI { reg1, reg2, disp16 } => write!(f, "{} {}[r{}], r{}", opcode, disp16, reg1, reg2),
+
+            Operands::VII { reg2, .. } if opcode == Opcode::Xb || opcode == Opcode::Xh => {
+                write!(f, "{} r{}", opcode, reg2)
+            }
+            Operands::VII { reg1, reg2 } => write!(f, "{} r{}, r{}", opcode, reg1, reg2),
+        }
+    }
+}
+
+impl Opcode {
+    pub fn takes_no_operands(&self) -> bool {
+        matches!(
+            *self,
+            Opcode::Cli | Opcode::Sei | Opcode::Reti | Opcode::Halt |
+            Opcode::Sch0bsu | Opcode::Sch0bsd | Opcode::Sch1bsu | Opcode::Sch1bsd |
+            Opcode::Orbsu | Opcode::Andbsu | Opcode::Xorbsu | Opcode::Movbsu |
+            Opcode::Ornbsu | Opcode::Andnbsu | Opcode::Xornbsu | Opcode::Notbsu
+        )
+    }
+
+    fn has_signed_imm5(&self) -> bool {
+        matches!(*self, Opcode::MovImm | Opcode::AddImm | Opcode::CmpImm)
+    }
+}
+
+pub struct SystemRegister(pub usize);
+
+impl fmt::Display for SystemRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            0 => write!(f, "eipc"),
+            1 => write!(f, "eipsw"),
+            2 => write!(f, "fepc"),
+            3 => write!(f, "fepsw"),
+            4 => write!(f, "ecr"),
+            5 => write!(f, "psw"),
+            6 => write!(f, "pir"),
+            7 => write!(f, "tkcw"),
+            24 => write!(f, "chcw"),
+            25 => write!(f, "adtre"),
+            n => write!(f, "sr{}", n),
         }
     }
 }
+
+#[derive(Debug)]
+pub struct DecodeError {
+    pub addr: u32,
+    pub halfword: u16,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unrecognized instruction at 0x{:08x}: 0x{:04x}", self.addr, self.halfword)
+    }
+}
+
+impl error::Error for DecodeError {}
+
+pub fn decode(addr: u32, interconnect: &Interconnect) -> Result<Instruction, DecodeError> {
+    // Fetches peek so they never trip data watchpoints.
+    let first_halfword = interconnect.peek_halfword(addr);
+
+    // Format VII keeps its sub-opcode in the second halfword, so it has to be
+    // fetched before the opcode can be resolved.
+    let second_halfword = if first_halfword >> 10 >= 0b101000 {
+        Some(interconnect.peek_halfword(addr.wrapping_add(2)))
+    } else {
+        None
+    };
+
+    let opcode = match Opcode::from_halfwords(first_halfword, second_halfword.unwrap_or(0)) {
+        Some(opcode) => opcode,
+        None => return Err(DecodeError { addr, halfword: first_halfword }),
+    };
+
+    let reg1 = (first_halfword & 0x1f) as usize;
+    let reg2 = ((first_halfword >> 5) & 0x1f) as usize;
+    let imm16 = second_halfword.unwrap_or(0);
+
+    let operands = match opcode.instruction_format() {
+        InstructionFormat::I => Operands::I { reg1, reg2 },
+        InstructionFormat::II => Operands::II { imm5: reg1, reg2 },
+        InstructionFormat::III => {
+            let disp9 = ((((first_halfword & 0x1ff) << 7) as i16) >> 7) as i32;
+            Operands::III { disp9: disp9 & !1 }
+        }
+        InstructionFormat::IV => {
+            let disp26 = (((first_halfword as u32 & 0x3ff) << 16) | imm16 as u32) << 6;
+            Operands::IV { disp26: ((disp26 as i32) >> 6) & !1 }
+        }
+        InstructionFormat::V => Operands::V { reg1, reg2, imm16 },
+        InstructionFormat::VI => Operands::VI { reg1, reg2, disp16: imm16 as i16 },
+        InstructionFormat::VII => Operands::VII { reg1, reg2 },
+    };
+
+    let second_halfword = if opcode.instruction_format().has_second_halfword() {
+        second_halfword
+    } else {
+        None
+    };
+
+    Ok(Instruction {
+        addr,
+        opcode,
+        operands,
+        length: if second_halfword.is_some() { 4 } else { 2 },
+        first_halfword,
+        second_halfword,
+    })
+}
+
+// Builds the halfwords for an instruction; operands are expected to already
+// fit their fields.
+pub fn encode(opcode: Opcode, operands: Operands) -> (u16, Option<u16>) {
+    let opcode_bits = opcode.opcode_bits() << 10;
+
+    match operands {
+        Operands::I { reg1, reg2 } => (opcode_bits | ((reg2 as u16) << 5) | reg1 as u16, None),
+        Operands::II { imm5, reg2 } => {
+            let imm5 = opcode.sub_opcode_bits().unwrap_or(imm5 as u16 & 0x1f);
+            (opcode_bits | ((reg2 as u16) << 5) | imm5, None)
+        }
+        Operands::III { disp9 } => {
+            let condition_bits = match opcode {
+                Opcode::Bcond(condition) => condition.bits(),
+                _ => 0,
+            };
+            (0b100 << 13 | condition_bits << 9 | (disp9 as u16 & 0x1ff), None)
+        }
+        Operands::IV { disp26 } => {
+            let disp26 = disp26 as u32;
+            (opcode_bits | ((disp26 >> 16) as u16 & 0x3ff), Some(disp26 as u16))
+        }
+        Operands::V { reg1, reg2, imm16 } => (opcode_bits | ((reg2 as u16) << 5) | reg1 as u16, Some(imm16)),
+        Operands::VI { reg1, reg2, disp16 } => (opcode_bits | ((reg2 as u16) << 5) | reg1 as u16, Some(disp16 as u16)),
+        Operands::VII { reg1, reg2 } => {
+            let sub_opcode_bits = opcode.sub_opcode_bits().unwrap_or(0);
+            (opcode_bits | ((reg2 as u16) << 5) | reg1 as u16, Some(sub_opcode_bits << 10))
+        }
+    }
+}
+
+pub fn sign_extend_imm5(imm5: usize) -> u32 {
+    let imm5 = imm5 | (if imm5 & 0x10 == 0 { 0x00 } else { 0xe0 });
+    (imm5 as i8) as u32
+}
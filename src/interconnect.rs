@@ -1,56 +1,335 @@
 use rom::*;
+use watchpoint::*;
+use game_pad::*;
+
+use std::cell::Cell;
+
+const WRAM_SIZE: usize = 65536; // 64 Kb.
+
+const SDLR: u32 = 0x02000010;
+const SDHR: u32 = 0x02000014;
+const SCR: u32 = 0x02000028;
 
 pub struct Interconnect {
-    rom: Rom
+    rom: Rom,
+
+    // Copy of the ROM made the first time the debugger patches it, so the
+    // loaded image itself is left untouched.
+    rom_overlay: Option<Box<[u8]>>,
+
+    wram: Box<[u8]>,
+
+    watchpoints: Vec<Watchpoint>,
+
+    // Reads only borrow the interconnect, so the first watchpoint they trip
+    // is recorded through a Cell until the debugger collects it.
+    watch_hit: Cell<Option<WatchHit>>,
+
+    // Likewise the first access to an unmapped address; it reads as zero or
+    // is dropped, and the CPU turns it into an error after the instruction.
+    bus_fault: Cell<Option<u32>>,
+
+    cycles: u64,
+
+    // Buttons held as of the last frame; the keypad registers read these
+    // whenever the game asks.
+    game_pad: u16,
 }
 
 impl Interconnect {
     pub fn new(rom: Rom) -> Interconnect {
-        Interconnect { rom: rom }
+        Interconnect {
+            rom,
+            rom_overlay: None,
+            wram: vec![0; WRAM_SIZE].into_boxed_slice(),
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            bus_fault: Cell::new(None),
+            cycles: 0,
+            game_pad: 0,
+        }
+    }
+
+    pub fn rom(&self) -> &Rom {
+        &self.rom
+    }
+
+    pub fn wram(&self) -> &[u8] {
+        &self.wram
+    }
+
+    pub fn set_wram(&mut self, bytes: &[u8]) {
+        self.wram.copy_from_slice(bytes);
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.watchpoints.push(watchpoint);
+        self.watchpoints.len() - 1
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.watchpoints.len() {
+            Some(self.watchpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    pub fn take_bus_fault(&self) -> Option<u32> {
+        self.bus_fault.take()
     }
 
     pub fn read_byte(&self, addr: u32) -> u8 {
+        let value = self.peek_byte(addr);
+        self.check_watchpoints(AccessKind::Read, addr, 1, value as u32, value as u32);
+        value
+    }
+
+    pub fn read_halfword(&self, addr: u32) -> u16 {
+        let addr = addr & 0x07fffffe;
+        let value = self.peek_halfword(addr);
+        self.check_watchpoints(AccessKind::Read, addr, 2, value as u32, value as u32);
+        value
+    }
+
+    pub fn read_word(&self, addr: u32) -> u32 {
+        let addr = addr & 0x07fffffc;
+        let value = self.peek_word(addr);
+        self.check_watchpoints(AccessKind::Read, addr, 4, value, value);
+        value
+    }
+
+    // Reads without tripping watchpoints, for instruction fetches and for
+    // the debugger looking at memory.
+    pub fn peek_byte(&self, addr: u32) -> u8 {
         let addr = addr & 0x07ffffff;
-        
+
         if addr >= 0x07000000 {
-            let rom_bytes = self.rom.bytes();
+            let rom_bytes = match self.rom_overlay {
+                Some(ref overlay) => overlay,
+                None => self.rom.bytes(),
+            };
             let rom_size = self.rom.size();
             let rom_mask = (rom_size - 1) as u32;
             let addr = addr & rom_mask;
 
             rom_bytes[addr as usize]
+        } else if (0x05000000..0x06000000).contains(&addr) {
+            self.wram[(addr as usize) & (WRAM_SIZE - 1)]
+        } else if addr == SDLR {
+            (self.game_pad | PAD_SIGNATURE) as u8
+        } else if addr == SDHR {
+            (self.game_pad >> 8) as u8
         } else {
-            panic!("Unrecognized addr: {:#08x}", addr);
+            self.record_bus_fault(addr);
+            0
         }
     }
 
-    pub fn read_halfword(&self, addr: u32) -> u16 {
-        let addr = addr & 0x07fffffe;        
-        let low_byte = self.read_byte(addr);
-        let high_byte = self.read_byte(addr + 1);
+    pub fn peek_halfword(&self, addr: u32) -> u16 {
+        let addr = addr & 0x07fffffe;
+        let low_byte = self.peek_byte(addr);
+        let high_byte = self.peek_byte(addr + 1);
         ((high_byte as u16) << 8) | (low_byte as u16)
     }
 
+    pub fn peek_word(&self, addr: u32) -> u32 {
+        let addr = addr & 0x07fffffc;
+        let low_halfword = self.peek_halfword(addr);
+        let high_halfword = self.peek_halfword(addr + 2);
+        ((high_halfword as u32) << 16) | (low_halfword as u32)
+    }
+
     pub fn write_byte(&mut self, addr: u32, value: u8) {
+        if !self.watchpoints.is_empty() {
+            let old_value = self.peek_old_value(|interconnect| interconnect.peek_byte(addr));
+            self.check_watchpoints(AccessKind::Write, addr, 1, old_value as u32, value as u32);
+        }
+
+        self.store_byte(addr, value);
+    }
+
+    pub fn write_halfword(&mut self, addr: u32, value: u16) {
+        let addr = addr & 0xfffffffe;
+
+        if !self.watchpoints.is_empty() {
+            let old_value = self.peek_old_value(|interconnect| interconnect.peek_halfword(addr));
+            self.check_watchpoints(AccessKind::Write, addr, 2, old_value as u32, value as u32);
+        }
+
+        self.store_byte(addr, value as u8);
+        self.store_byte(addr + 1, (value >> 8) as u8);
+    }
+
+    pub fn write_word(&mut self, addr: u32, value: u32) {
+        let addr = addr & 0xfffffffc;
+
+        if !self.watchpoints.is_empty() {
+            let old_value = self.peek_old_value(|interconnect| interconnect.peek_word(addr));
+            self.check_watchpoints(AccessKind::Write, addr, 4, old_value, value);
+        }
+
+        self.store_byte(addr, value as u8);
+        self.store_byte(addr + 1, (value >> 8) as u8);
+        self.store_byte(addr + 2, (value >> 16) as u8);
+        self.store_byte(addr + 3, (value >> 24) as u8);
+    }
+
+    // Write-only registers don't read back, so looking up the value a write
+    // replaces for the watchpoints mustn't count as a fault.
+    fn peek_old_value<T, F: FnOnce(&Interconnect) -> T>(&self, peek: F) -> T {
+        let bus_fault = self.bus_fault.get();
+        let value = peek(self);
+        self.bus_fault.set(bus_fault);
+        value
+    }
+
+    // Whether the debugger can peek and patch addr without hitting an
+    // unimplemented region.
+    pub fn is_mapped(&self, addr: u32) -> bool {
+        let addr = addr & 0x07ffffff;
+        addr >= 0x07000000 || (0x05000000..0x06000000).contains(&addr)
+    }
+
+    // Debugger writes: unlike write_byte, ROM can be patched too, and
+    // watchpoints aren't tripped.
+    pub fn patch_byte(&mut self, addr: u32, value: u8) {
+        let masked_addr = addr & 0x07ffffff;
+
+        if masked_addr >= 0x07000000 {
+            let rom_mask = (self.rom.size() - 1) as u32;
+            let rom = &self.rom;
+            let overlay = self.rom_overlay.get_or_insert_with(|| rom.bytes().to_vec().into_boxed_slice());
+
+            overlay[(masked_addr & rom_mask) as usize] = value;
+        } else {
+            self.store_byte(addr, value);
+        }
+    }
+
+    pub fn patch_halfword(&mut self, addr: u32, value: u16) {
+        let addr = addr & 0xfffffffe;
+        self.patch_byte(addr, value as u8);
+        self.patch_byte(addr + 1, (value >> 8) as u8);
+    }
+
+    pub fn patch_word(&mut self, addr: u32, value: u32) {
+        let addr = addr & 0xfffffffc;
+        self.patch_halfword(addr, value as u16);
+        self.patch_halfword(addr + 2, (value >> 16) as u16);
+    }
+
+    pub fn cycles(&mut self, cycles: usize) {
+        self.cycles += cycles as u64;
+    }
+
+    pub fn game_pad(&self) -> u16 {
+        self.game_pad
+    }
+
+    pub fn set_game_pad(&mut self, buttons: u16) {
+        self.game_pad = buttons;
+    }
+
+    // CPU cycles run since reset.
+    pub fn cycle_count(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn set_cycle_count(&mut self, cycles: u64) {
+        self.cycles = cycles;
+    }
+
+    fn store_byte(&mut self, addr: u32, value: u8) {
         let addr = addr & 0x07ffffff;
 
         if addr == 0x02000024 {
             println!("Wait Control Register: ({:#08x}) written: 0x{:02x}", addr, value);
             println!("Cartridge ROM Waits: {}", if value & 0x01 == 0 { 2 } else { 1 });
             println!(" Cartridge Expansion Waits: {}", if value & 0x02 == 0 { 2 } else { 1 });
+        } else if (0x05000000..0x06000000).contains(&addr) {
+            self.wram[(addr as usize) & (WRAM_SIZE - 1)] = value;
+        } else if addr == SCR {
+            // Starting a keypad read; the buttons are already latched.
         } else {
-            panic!("Unrecognized addr: {:#08x}", addr);
+            self.record_bus_fault(addr);
         }
     }
 
-    pub fn write_word(&mut self, addr: u32, value: u32) {
-        let addr = addr & 0xfffffffc;
-        self.write_byte(addr, value as u8);
-        self.write_byte(addr + 1, (value >> 8) as u8);
-        self.write_byte(addr + 2, (value >> 16) as u8);
-        self.write_byte(addr + 3, (value >> 24) as u8);
+    fn record_bus_fault(&self, addr: u32) {
+        if self.bus_fault.get().is_none() {
+            self.bus_fault.set(Some(addr));
+        }
     }
 
-    pub fn cycles(&mut self, cycles: usize) {
+    fn check_watchpoints(&self, access: AccessKind, addr: u32, size: u32, old_value: u32, new_value: u32) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+
+        // Keep the first hit of an instruction; later ones add nothing.
+        let first_hit = self.watch_hit.get();
+        if first_hit.is_some() {
+            return;
+        }
+
+        let index = self.watchpoints.iter().position(|watchpoint| watchpoint.matches(access, addr, size, new_value));
+
+        if let Some(index) = index {
+            self.watch_hit.set(Some(WatchHit { index, access, addr, size, old_value, new_value }));
+        }
+    }
+}
+
+// Test fixtures shared by every module's tests, built around a blank 1 Kb
+// ROM or a given image.
+#[cfg(test)]
+impl Interconnect {
+    pub fn with_blank_rom() -> Interconnect {
+        Interconnect::with_rom_bytes(vec![0; 1024])
+    }
+
+    pub fn with_rom_bytes(bytes: Vec<u8>) -> Interconnect {
+        Interconnect::new(Rom::from_bytes(bytes).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WCR: u32 = 0x02000024;
+
+    #[test]
+    fn watched_writes_to_write_only_registers_dont_fault() {
+        let mut interconnect = Interconnect::with_blank_rom();
+        interconnect.add_watchpoint(Watchpoint { start: 0x05000000, end: 0x05000000, kind: WatchKind::Write, value: None });
+
+        interconnect.write_byte(WCR, 0x01);
+        interconnect.write_byte(SCR, 0x84);
+
+        assert_eq!(interconnect.take_bus_fault(), None);
+    }
+
+    #[test]
+    fn watched_writes_to_unmapped_addresses_still_fault() {
+        let mut interconnect = Interconnect::with_blank_rom();
+        interconnect.add_watchpoint(Watchpoint { start: 0x05000000, end: 0x05000000, kind: WatchKind::Write, value: None });
+
+        interconnect.write_word(0x01000000, 0x12345678);
+
+        assert_eq!(interconnect.take_bus_fault(), Some(0x01000000));
     }
 }
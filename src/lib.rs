@@ -0,0 +1,31 @@
+extern crate encoding;
+extern crate flate2;
+extern crate zip;
+
+#[macro_use]
+extern crate nom;
+
+pub mod error;
+pub mod rom;
+pub mod interconnect;
+pub mod instruction;
+pub mod nvc;
+pub mod disassembler;
+pub mod analysis;
+pub mod assembler;
+pub mod watchpoint;
+pub mod expression;
+pub mod trace;
+pub mod elf;
+pub mod symbols;
+pub mod dwarf;
+pub mod gdb;
+pub mod crc32;
+pub mod savestate;
+pub mod rewind;
+pub mod game_pad;
+pub mod movie;
+pub mod avb;
+pub mod patch;
+pub mod archive;
+pub mod rom_database;
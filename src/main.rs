@@ -1,26 +1,42 @@
-extern crate encoding;
+extern crate aurora_vb;
+extern crate ctrlc;
 
 #[macro_use]
 extern crate nom;
 
-mod rom;
-mod interconnect;
-mod instruction;
-mod nvc;
-
 use nom::{IResult, eof, space, digit, hex_digit, alphanumeric};
 
-use rom::*;
-use interconnect::*;
-use instruction::*;
-use nvc::*;
+use aurora_vb::error::*;
+use aurora_vb::rom::*;
+use aurora_vb::rom_database::*;
+use aurora_vb::instruction::*;
+use aurora_vb::nvc::*;
+use aurora_vb::disassembler::*;
+use aurora_vb::analysis::*;
+use aurora_vb::assembler::*;
+use aurora_vb::watchpoint::*;
+use aurora_vb::expression::*;
+use aurora_vb::symbols::*;
+use aurora_vb::elf::*;
+use aurora_vb::dwarf::*;
+use aurora_vb::gdb::*;
+use aurora_vb::savestate::*;
+use aurora_vb::rewind::*;
+use aurora_vb::game_pad::*;
+use aurora_vb::movie::*;
+use aurora_vb::avb::*;
 
 use std::env;
-use std::io::{stdin, stdout, Write};
-use std::borrow::Cow;
+use std::io::{self, stdin, stdout, Write, BufRead, BufReader};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::net::TcpListener;
 use std::str::{self, FromStr};
+use std::convert::TryFrom;
+use std::process;
 use std::collections::HashMap;
-
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 #[derive(Debug, Clone)]
 pub enum Command {
@@ -31,41 +47,251 @@ pub enum Command {
     Disassemble(usize),
     Label,
     AddLabel(String, u32),
+    Assemble(Option<u32>),
+    Break(Location, Option<Expression>),
+    Delete(Option<usize>),
+    Breakpoints,
+    Continue(Option<usize>),
+    Next,
+    Finish,
+    Until(Location),
+    Backtrace,
+    List(Option<Location>),
+    Watch(Watchpoint),
+    Unwatch(Option<usize>),
+    Watchpoints,
+    Print(Expression),
+    Set(Register, Expression),
+    Poke(Size, u32, Expression),
+    Fill(u32, u32, u8),
+    TraceOn(String, Option<(u32, u32)>),
+    TraceOff,
+    SaveLabels(String),
+    LoadLabels(String),
+    Rewind(u64),
+    StepBack(u64),
+    Pad(Vec<String>),
+    RecordOn(String),
+    RecordOff,
+    SaveState(String),
+    LoadState(String),
     Exit,
     Repeat,
 }
 
 impl FromStr for Command {
-    type Err = Cow<'static, str>;
+    type Err = AvbError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match command(s.as_bytes()) {
             IResult::Done(_, c) => Ok(c),
-            err => Err(format!("Unable to parse command: {:?}", err).into()),
+            err => Err(AvbError::Command(format!("Unable to parse command: {:?}", err))),
         }
     }
 }
 
-struct AVB {
-    pub interconnect: Interconnect,
-    pub cpu: Nvc,
+#[derive(Debug, Clone)]
+pub enum Location {
+    Addr(u32),
+    Label(String),
 }
 
-impl AVB {
-    pub fn new(rom: Rom) -> AVB {
-        AVB {
-            interconnect: Interconnect::new(rom),
-            cpu: Nvc::new()
+impl Location {
+    fn resolve(&self, labels: &HashMap<String, u32>) -> Result<u32, String> {
+        match *self {
+            Location::Addr(addr) => Ok(addr),
+            Location::Label(ref name) => labels.get(name)
+                .cloned()
+                .ok_or_else(|| format!("Unknown label: .{}", name)),
         }
     }
+}
+
+#[derive(Debug, Clone)]
+pub enum Register {
+    Gpr(usize),
+    Pc,
+    Psw,
+    PswFlag(PswFlag),
+}
 
-    pub fn step(&mut self) {
-        self.cpu.step(&mut self.interconnect);
+impl Register {
+    fn from_name(name: &str, field: Option<&str>) -> Result<Register, String> {
+        match (name, field) {
+            ("pc", None) => Ok(Register::Pc),
+            ("psw", None) => Ok(Register::Psw),
+            ("psw", Some(field)) => PswFlag::from_name(field)
+                .map(Register::PswFlag)
+                .ok_or_else(|| format!("Unknown psw flag: {}", field)),
+            (_, None) => register_number(name).map(Register::Gpr),
+            (_, Some(field)) => Err(format!("Unknown register: {}.{}", name, field)),
+        }
+    }
+}
+
+struct Breakpoint {
+    addr: u32,
+    label: Option<String>,
+    condition: Option<Expression>,
+}
+
+#[derive(Clone, Copy)]
+enum RunUntil {
+    Stopped,
+    Addr(u32),
+    // Stops once the shadow call stack is shallower than the given number
+    // of frames below the current one; 0 returns from the current
+    // subroutine. Traps taken on the way are frames too, so their reti
+    // doesn't end the run early.
+    Return(usize),
+}
+
+enum StopReason {
+    Breakpoint(usize),
+    BadCondition(usize, String),
+    Watchpoint(WatchHit, u32),
+    StepCount,
+    Reached,
+    Interrupted,
+    Fault(AvbError),
+}
+
+// Source lines for addresses, from the ELF's line table, with the files read
+// in as they're needed. Relative paths are tried from the working directory,
+// then from the directory the ROM was loaded from.
+struct SourceView {
+    line_table: Option<LineTable>,
+    base_dir: PathBuf,
+    files: HashMap<usize, Option<Vec<String>>>,
+    last_location: Option<SourceLocation>,
+}
+
+impl SourceView {
+    fn new(line_table: Option<LineTable>, rom_file_name: &str) -> SourceView {
+        SourceView {
+            line_table,
+            base_dir: Path::new(rom_file_name).parent().map(Path::to_path_buf).unwrap_or_default(),
+            files: HashMap::new(),
+            last_location: None,
+        }
+    }
+
+    fn lookup(&self, addr: u32) -> Option<SourceLocation> {
+        self.line_table.as_ref().and_then(|line_table| line_table.lookup(addr))
+    }
+
+    fn file_name(&self, file: usize) -> &str {
+        self.line_table.as_ref().map_or("", |line_table| line_table.file_name(file))
+    }
+
+    fn lines(&mut self, file: usize) -> Option<&[String]> {
+        if !self.files.contains_key(&file) {
+            let path = Path::new(self.file_name(file));
+            let lines = File::open(path)
+                .or_else(|_| File::open(self.base_dir.join(path)))
+                .and_then(|file| BufReader::new(file).lines().collect::<Result<Vec<_>, _>>())
+                .ok();
+
+            self.files.insert(file, lines);
+        }
+
+        self.files[&file].as_deref()
+    }
+
+    // Prints the source line addr belongs to, but only when it differs from
+    // the last one shown so a run of instructions gets a single heading.
+    fn print_location(&mut self, addr: u32) {
+        let location = self.lookup(addr);
+
+        if let Some(location) = location {
+            if self.last_location != Some(location) {
+                let text = self.lines(location.file)
+                    .and_then(|lines| lines.get((location.line as usize).wrapping_sub(1)))
+                    .map(|text| text.trim().to_string())
+                    .unwrap_or_default();
+
+                println!("{}:{}: {}", self.file_name(location.file), location.line, text);
+            }
+        }
+
+        self.last_location = location;
+    }
+
+    fn list(&mut self, addr: u32, pc: u32) {
+        const CONTEXT: u32 = 5;
+
+        let location = match self.lookup(addr) {
+            Some(location) => location,
+            None => {
+                println!("No source line for 0x{:08x}", addr);
+                return;
+            }
+        };
+
+        let pc_line = self.lookup(pc)
+            .filter(|pc_location| pc_location.file == location.file)
+            .map(|pc_location| pc_location.line);
+
+        let file_name = self.file_name(location.file).to_string();
+        let lines = match self.lines(location.file) {
+            Some(lines) => lines,
+            None => {
+                println!("{}:{}: source file not found", file_name, location.line);
+                return;
+            }
+        };
+
+        println!("{}:", file_name);
+
+        let first = location.line.saturating_sub(CONTEXT).max(1);
+        let last = (location.line + CONTEXT).min(lines.len() as u32);
+
+        for line in first..=last {
+            let marker = if Some(line) == pc_line { "=>" } else { "  " };
+            println!("{}{:>5}  {}", marker, line, lines[line as usize - 1]);
+        }
     }
 }
 
 fn main() {
-    let rom_file_name = env::args().nth(1).unwrap();
+    if let Err(e) = start() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn start() -> Result<(), AvbError> {
+    let mut args = env::args().skip(1).collect::<Vec<_>>();
+
+    // --trace <file> logs every instruction from reset on.
+    let trace_file_name = take_option(&mut args, "--trace");
+
+    // --symbols <file> may be given more than once; later files win.
+    let mut symbol_file_names = Vec::new();
+    while let Some(file_name) = take_option(&mut args, "--symbols") {
+        symbol_file_names.push(file_name);
+    }
+
+    // --gdb <port> hands the emulator to a remote debugger instead of the
+    // built in one.
+    let gdb_port = match take_option(&mut args, "--gdb") {
+        Some(port) => Some(port.parse::<u16>().map_err(|_| AvbError::Usage(format!("Invalid --gdb port: {}", port)))?),
+        None => None,
+    };
+
+    // --play <movie> replays a recorded movie without the debugger and
+    // exits with its result.
+    let movie_file_name = take_option(&mut args, "--play");
+
+    // --entry <name> picks the file to load from a zip archive.
+    let entry_name = take_option(&mut args, "--entry");
+
+    // --patch <file> applies an IPS or BPS patch to the ROM in memory.
+    let patch_file_name = take_option(&mut args, "--patch");
+
+    let rom_file_name = args.first().cloned().ok_or_else(|| {
+        AvbError::Usage("Usage: aurora_vb [--trace <file>] [--symbols <file>] [--gdb <port>] [--play <movie>] [--patch <file>] [--entry <name>] <rom file>".into())
+    })?;
 
     println!("\n--------------------");
     println!("\nAurora VB Emulator");
@@ -73,31 +299,106 @@ fn main() {
 
     println!("\nLoading ROM file '{}'", rom_file_name);
 
-    let rom = Rom::load(rom_file_name).unwrap();
+    let mut rom = Rom::load_entry(&rom_file_name, entry_name.as_deref())?;
+
+    if let Some(ref file_name) = patch_file_name {
+        println!("\nApplying patch file '{}'", file_name);
+        rom = rom.patch(file_name)?;
+        println!("\nPatched ROM CRC-32: {:08x}", rom.crc32());
+    }
+
+    // ELF files also carry symbols, and possibly data that goes straight
+    // into RAM.
+    let elf = Elf::load(&rom_file_name).ok();
+
+    let header = rom.header();
 
     println!("\nHeader info:");
 
-    println!("\nGame: {}", rom.name().unwrap());
-    println!("\nMaker code: {}", rom.maker_code().unwrap());
-    println!("\nGame code: {}", rom.game_code().unwrap());
-    println!("\nGame version: 1.{:#02}\n", rom.game_version());
+    println!("\nGame: {}", header.title);
+    println!("\nMaker code: {}", header.maker_code());
+    println!("\nGame code: {}", header.game_code());
+    println!("\nGame version: 1.{:#02}\n", header.version);
+
+    if let Err(e) = header.check() {
+        println!("Warning: {}\n", e);
+    }
+
+    match identify(rom.crc32()) {
+        Some(known) => {
+            println!("Known ROM (CRC-32 {:08x}):", known.crc32);
+            println!("\nTitle: {} ({})", known.title, known.region);
+            println!("\nSRAM: {}", if known.sram_size == 0 { "none".into() } else { format!("{} bytes", known.sram_size) });
+            for quirk in known.quirks.iter() {
+                println!("\nQuirk: {}", quirk);
+            }
+            println!();
+        }
+        None => println!("Unknown ROM (CRC-32 {:08x})\n", rom.crc32()),
+    }
 
     let mut avb = AVB::new(rom);
 
+    if let Some(ref file_name) = trace_file_name {
+        avb.start_trace(file_name, None);
+    }
+
     let mut labels = HashMap::new();
 
+    if let Some(ref elf) = elf {
+        load_elf(&mut avb, &mut labels, elf);
+    }
+
+    let mut code_map = CodeMap::analyse(&avb.interconnect);
+
+    let line_table = elf.as_ref().and_then(|elf| {
+        LineTable::from_elf(elf).unwrap_or_else(|e| {
+            println!("Unable to read line info from '{}': {}", rom_file_name, e);
+            None
+        })
+    });
+    let mut source = SourceView::new(line_table, &rom_file_name);
+
+    for file_name in symbol_file_names.iter() {
+        load_label_file(&mut labels, file_name);
+    }
+
+    if let Some(ref file_name) = movie_file_name {
+        let success = play_movie(&mut avb, file_name);
+        avb.stop_trace();
+        process::exit(if success { 0 } else { 1 });
+    }
+
+    if let Some(port) = gdb_port {
+        let result = serve_gdb(&mut avb, port);
+        avb.stop_trace();
+        return result;
+    }
+
+    let mut breakpoints = Vec::new();
+
+    // Ctrl-C stops a running `continue` rather than exiting.
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let interrupted = interrupted.clone();
+        ctrlc::set_handler(move || interrupted.store(true, Ordering::SeqCst))
+            .map_err(|e| AvbError::Usage(format!("Unable to install Ctrl-C handler: {}", e)))?;
+    }
+
     let mut cursor = 0xfffffff0;
 
+    let mut recording_file_name = None;
+
     let mut last_command = None;
 
     loop {
         print!("Aurora VB: ");
 
-        stdout().flush().unwrap();
+        stdout().flush()?;
 
-        let command = match (read_stdin().parse(), last_command.clone()) {
+        let command = match (read_stdin()?.parse(), last_command.clone()) {
             (Ok(Command::Repeat), Some(c)) => Ok(c),
-            (Ok(Command::Repeat), None) => Err("No last command".into()),
+            (Ok(Command::Repeat), None) => Err(AvbError::Command("No last command".into())),
             (Ok(c), _) => Ok(c),
             (Err(e), _) => Err(e),
         };
@@ -114,9 +415,18 @@ fn main() {
                 println!("psw: 0x{:08x}", avb.cpu.reg_psw());
             }
             Ok(Command::Step) => {
-                avb.step();
+                let pc = avb.cpu.reg_pc();
+                avb.interconnect.take_watch_hit();
+                if let Err(e) = avb.step() {
+                    println!("{}", e);
+                }
+
+                if let Some(hit) = avb.interconnect.take_watch_hit() {
+                    print_watch_hit(&avb, &hit, pc);
+                }
+
                 cursor = avb.cpu.reg_pc();
-                disassemble_instruction(&mut avb, &mut labels, &mut cursor);
+                disassemble_instruction(&mut avb, &mut labels, &mut source, &mut cursor);
                 cursor = avb.cpu.reg_pc();
             }
             Ok(Command::Goto(addr)) => {
@@ -136,7 +446,7 @@ fn main() {
                     print!("0x{:08x} ", cursor);
 
                     for x in 0..NUM_COLS {
-                        let byte = avb.interconnect.read_byte(cursor);
+                        let byte = avb.interconnect.peek_byte(cursor);
                         cursor = cursor.wrapping_add(1);
 
                         print!("{:02x} ", byte);
@@ -151,7 +461,11 @@ fn main() {
             }
             Ok(Command::Disassemble(count)) => {
                 for _ in 0..count {
-                    disassemble_instruction(&mut avb, &mut labels, &mut cursor);
+                    if code_map.is_data(cursor) {
+                        disassemble_data(&mut avb, &labels, &code_map, &mut cursor);
+                    } else {
+                        disassemble_instruction(&mut avb, &mut labels, &mut source, &mut cursor);
+                    }
                 }
             }
             Ok(Command::Label) => {
@@ -162,85 +476,570 @@ fn main() {
             Ok(Command::AddLabel(ref name, addr)) => {
                 labels.insert(name.clone(), addr);
             }
+            Ok(Command::Assemble(addr)) => {
+                if let Some(addr) = addr {
+                    cursor = addr;
+                }
+
+                assemble_interactive(&mut avb, &labels, &mut cursor)?;
+            }
+            Ok(Command::Break(ref location, ref condition)) => {
+                match location.resolve(&labels) {
+                    Ok(addr) => {
+                        let label = match *location {
+                            Location::Label(ref name) => Some(name.clone()),
+                            Location::Addr(_) => None,
+                        };
+                        let condition = condition.clone();
+
+                        breakpoints.push(Breakpoint { addr, label, condition });
+                        println!("Breakpoint {} at 0x{:08x}", breakpoints.len() - 1, addr);
+                    }
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Ok(Command::Delete(index)) => {
+                match index {
+                    Some(index) if index < breakpoints.len() => {
+                        breakpoints.remove(index);
+                    }
+                    Some(index) => println!("No breakpoint {}", index),
+                    None => breakpoints.clear(),
+                }
+            }
+            Ok(Command::Breakpoints) => {
+                for (index, breakpoint) in breakpoints.iter().enumerate() {
+                    print!("{}: 0x{:08x}", index, breakpoint.addr);
+
+                    if let Some(ref name) = breakpoint.label {
+                        print!(" (.{})", name);
+                    }
+
+                    if let Some(ref condition) = breakpoint.condition {
+                        print!(" if {}", condition);
+                    }
+
+                    println!();
+                }
+            }
+            Ok(Command::Continue(max_steps)) => {
+                let reason = run(&mut avb, &breakpoints, &labels, max_steps, RunUntil::Stopped, &interrupted);
+                report_stop(&mut avb, &mut labels, &mut source, reason, &mut cursor);
+            }
+            Ok(Command::Next) => {
+                let pc = avb.cpu.reg_pc();
+                let is_call = decode(pc, &avb.interconnect).is_ok_and(|instruction| instruction.opcode == Opcode::Jal);
+
+                let reason = if is_call {
+                    run(&mut avb, &breakpoints, &labels, None, RunUntil::Return(1), &interrupted)
+                } else {
+                    run(&mut avb, &breakpoints, &labels, Some(1), RunUntil::Stopped, &interrupted)
+                };
+                report_stop(&mut avb, &mut labels, &mut source, reason, &mut cursor);
+            }
+            Ok(Command::Finish) => {
+                let reason = run(&mut avb, &breakpoints, &labels, None, RunUntil::Return(0), &interrupted);
+                report_stop(&mut avb, &mut labels, &mut source, reason, &mut cursor);
+            }
+            Ok(Command::Until(ref location)) => {
+                match location.resolve(&labels) {
+                    Ok(addr) => {
+                        let reason = run(&mut avb, &breakpoints, &labels, None, RunUntil::Addr(addr), &interrupted);
+                        report_stop(&mut avb, &mut labels, &mut source, reason, &mut cursor);
+                    }
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Ok(Command::Backtrace) => print_backtrace(&avb, &labels),
+            Ok(Command::List(ref location)) => {
+                let pc = avb.cpu.reg_pc();
+                match location.as_ref().map_or(Ok(pc), |location| location.resolve(&labels)) {
+                    Ok(addr) => source.list(addr, pc),
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Ok(Command::Watch(ref watchpoint)) => {
+                let index = avb.interconnect.add_watchpoint(watchpoint.clone());
+                println!("Watchpoint {}: {}", index, watchpoint);
+            }
+            Ok(Command::Unwatch(index)) => {
+                match index {
+                    Some(index) => {
+                        if avb.interconnect.remove_watchpoint(index).is_none() {
+                            println!("No watchpoint {}", index);
+                        }
+                    }
+                    None => avb.interconnect.clear_watchpoints(),
+                }
+            }
+            Ok(Command::Watchpoints) => {
+                for (index, watchpoint) in avb.interconnect.watchpoints().iter().enumerate() {
+                    println!("{}: {}", index, watchpoint);
+                }
+            }
+            Ok(Command::Print(ref expression)) => {
+                match expression.eval(&avb.cpu, &avb.interconnect, &labels) {
+                    Ok(value) => println!("0x{:08x} ({})", value, value as i32),
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Ok(Command::Set(ref register, ref value)) => {
+                match value.eval(&avb.cpu, &avb.interconnect, &labels) {
+                    Ok(value) => {
+                        match *register {
+                            Register::Gpr(index) => avb.cpu.set_reg_gpr(index, value),
+                            Register::Pc => {
+                                avb.cpu.set_reg_pc(value);
+                                cursor = avb.cpu.reg_pc();
+                            }
+                            Register::Psw => avb.cpu.set_reg_psw(value),
+                            Register::PswFlag(flag) => avb.cpu.set_psw_flag(flag, value),
+                        }
+                    }
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Ok(Command::Poke(size, addr, ref value)) => {
+                let len = match size {
+                    Size::Byte => 1,
+                    Size::Halfword => 2,
+                    Size::Word => 4,
+                };
+
+                match value.eval(&avb.cpu, &avb.interconnect, &labels) {
+                    Ok(_) if !is_mapped_range(&avb, addr, len) => println!("Address 0x{:08x} isn't mapped", addr),
+                    Ok(value) => {
+                        match size {
+                            Size::Byte => avb.interconnect.patch_byte(addr, value as u8),
+                            Size::Halfword => avb.interconnect.patch_halfword(addr, value as u16),
+                            Size::Word => avb.interconnect.patch_word(addr, value),
+                        }
+                    }
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Ok(Command::Fill(addr, len, value)) => {
+                if is_mapped_range(&avb, addr, len) {
+                    for offset in 0..len {
+                        avb.interconnect.patch_byte(addr.wrapping_add(offset), value);
+                    }
+                } else {
+                    println!("Range 0x{:08x}-0x{:08x} isn't mapped", addr, addr.wrapping_add(len).wrapping_sub(1));
+                }
+            }
+            Ok(Command::TraceOn(ref file_name, range)) => avb.start_trace(file_name, range),
+            Ok(Command::TraceOff) => avb.stop_trace(),
+            Ok(Command::SaveLabels(ref file_name)) => {
+                match save_symbols(file_name, &labels) {
+                    Ok(()) => println!("Saved {} labels to '{}'", labels.len(), file_name),
+                    Err(e) => println!("Unable to save labels to '{}': {}", file_name, e),
+                }
+            }
+            Ok(Command::LoadLabels(ref file_name)) => load_label_file(&mut labels, file_name),
+            Ok(Command::Rewind(frames)) => {
+                match avb.rewind_frames(frames) {
+                    Ok(()) => report_rewind(&mut avb, &mut labels, &mut source, &mut cursor),
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Ok(Command::StepBack(count)) => {
+                match avb.step_back(count) {
+                    Ok(()) => report_rewind(&mut avb, &mut labels, &mut source, &mut cursor),
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Ok(Command::Pad(ref names)) => {
+                if names.is_empty() {
+                    println!("Holding: {}", pad_description(avb.pad_input));
+                    println!("Latched: {}", pad_description(avb.interconnect.game_pad()));
+                } else {
+                    match parse_buttons(names) {
+                        Ok(buttons) => avb.pad_input = buttons,
+                        Err(e) => println!("{}", e),
+                    }
+                }
+            }
+            Ok(Command::RecordOn(ref file_name)) => {
+                if avb.recorder.is_some() {
+                    println!("Already recording");
+                } else {
+                    avb.recorder = Some(MovieRecorder::start(&avb.cpu, &avb.interconnect));
+                    recording_file_name = Some(file_name.clone());
+                    println!("Recording to '{}'", file_name);
+                }
+            }
+            Ok(Command::RecordOff) => stop_recording(&mut avb, &mut recording_file_name),
+            Ok(Command::SaveState(ref slot)) => {
+                let file_name = state_file_name(&rom_file_name, slot);
+                match save_state_file(&file_name, &avb.cpu, &avb.interconnect) {
+                    Ok(()) => println!("Saved state to '{}'", file_name),
+                    Err(e) => println!("Unable to save state to '{}': {}", file_name, e),
+                }
+            }
+            Ok(Command::LoadState(_)) if avb.recorder.is_some() => println!("Can't load a state while recording a movie"),
+            Ok(Command::LoadState(ref slot)) => {
+                let file_name = state_file_name(&rom_file_name, slot);
+                match load_state_file(&file_name, &mut avb.cpu, &mut avb.interconnect) {
+                    Ok(()) => {
+                        println!("Loaded state from '{}'", file_name);
+
+                        // History from before the load belongs to another
+                        // timeline.
+                        avb.rewind.clear();
+                        avb.checkpoint();
+
+                        cursor = avb.cpu.reg_pc();
+                        disassemble_instruction(&mut avb, &mut labels, &mut source, &mut cursor);
+                        cursor = avb.cpu.reg_pc();
+                    }
+                    Err(e) => println!("Unable to load state from '{}': {}", file_name, e),
+                }
+            }
             Ok(Command::Exit) => break,
             Ok(Command::Repeat) => unreachable!(),
             Err(ref e) => println!("{}", e),
         }
 
+        if matches!(command, Ok(Command::Assemble(_)) | Ok(Command::Set(..)) | Ok(Command::Poke(..)) | Ok(Command::Fill(..))) {
+            avb.checkpoint();
+
+            if avb.recorder.is_some() {
+                println!("Edits aren't recorded; the movie won't replay past this point");
+            }
+        }
+
+        // Edits can turn data into code and back, so the code/data split
+        // has to be worked out again.
+        if matches!(command, Ok(Command::Assemble(_)) | Ok(Command::Poke(..)) | Ok(Command::Fill(..))) {
+            code_map = CodeMap::analyse(&avb.interconnect);
+        }
+
         if let Ok(c) = command {
             last_command = Some(c);
         }
     }
+
+    stop_recording(&mut avb, &mut recording_file_name);
+    avb.stop_trace();
+
+    Ok(())
 }
 
-fn disassemble_instruction(avb: &mut AVB, labels: &mut HashMap<String, u32>, cursor: &mut u32) {
-    print_labels(labels, *cursor);
+fn load_elf(avb: &mut AVB, labels: &mut HashMap<String, u32>, elf: &Elf) {
+    match elf.ram_segments() {
+        Ok(segments) => {
+            for (addr, bytes) in segments {
+                if !is_mapped_range(avb, addr, bytes.len() as u32) {
+                    println!("Segment at 0x{:08x} isn't in mapped memory, skipping it", addr);
+                    continue;
+                }
 
-    print!("0x{:08x} ", cursor);
+                for (i, &byte) in bytes.iter().enumerate() {
+                    avb.interconnect.patch_byte(addr.wrapping_add(i as u32), byte);
+                }
+            }
+        }
+        Err(e) => println!("Unable to load RAM segments: {}", e),
+    }
+
+    match elf.symbols() {
+        Ok(symbols) => {
+            println!("Loaded {} labels from ELF symbols", symbols.len());
+            labels.extend(symbols.into_iter().map(|symbol| (symbol.name, symbol.value)));
+        }
+        Err(e) => println!("Unable to load ELF symbols: {}", e),
+    }
+}
 
-    let first_halfword = avb.interconnect.read_halfword(*cursor);
-    *cursor = cursor.wrapping_add(2); // jump through distination & source registers.
+// Failing to listen or to talk to the debugger is an error, so --gdb exits
+// non-zero when it couldn't serve a session.
+fn serve_gdb(avb: &mut AVB, port: u16) -> Result<(), AvbError> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .map_err(|e| io::Error::new(e.kind(), format!("Unable to listen on port {}: {}", port, e)))?;
 
-    print!("{:02x}{:02x}", first_halfword & 0xff, first_halfword >> 8);
+    println!("Waiting for GDB to connect on 127.0.0.1:{}", port);
 
-    let opcode = Opcode::from_halfword(first_halfword);
+    listener.accept().and_then(|(stream, addr)| {
+        println!("GDB connected from {}", addr);
+        GdbStub::new(stream).serve(avb)
+    }).map_err(|e| io::Error::new(e.kind(), format!("GDB connection failed: {}", e)))?;
 
-    let instruction_format = opcode.instruction_format();
+    println!("GDB disconnected");
 
-    let second_halfword = if instruction_format.has_second_halfword() {
-        let second_halfword = avb.interconnect.read_halfword(*cursor);
-        print!("{:02x}{:02x}", second_halfword & 0xff, second_halfword >> 8);
+    Ok(())
+}
 
-        *cursor = cursor.wrapping_add(2);
-        second_halfword
-    } else {
-        print!("      ");
-        0
+fn play_movie(avb: &mut AVB, file_name: &str) -> bool {
+    let movie = match Movie::load(file_name) {
+        Ok(movie) => movie,
+        Err(e) => {
+            println!("Unable to load movie '{}': {}", file_name, e);
+            return false;
+        }
     };
 
-    print!("      ");
+    println!("Playing '{}' ({} frames, {} checkpoints)", file_name, movie.inputs.len(), movie.checkpoints.len());
+
+    match movie.play(avb) {
+        Ok(verified) => {
+            println!("Movie finished, {} checkpoints matched", verified);
+            true
+        }
+        Err(e) => {
+            println!("{}", e);
+            false
+        }
+    }
+}
+
+fn stop_recording(avb: &mut AVB, file_name: &mut Option<String>) {
+    if let (Some(recorder), Some(file_name)) = (avb.recorder.take(), file_name.take()) {
+        let frame_count = recorder.frame_count();
+        match recorder.finish().save(&file_name) {
+            Ok(()) => println!("Saved {} frames to '{}'", frame_count, file_name),
+            Err(e) => println!("Unable to save movie to '{}': {}", file_name, e),
+        }
+    }
+}
+
+fn parse_buttons(names: &[String]) -> Result<u16, String> {
+    names.iter().filter(|name| *name != "none").try_fold(0, |buttons, name| {
+        button_mask(name).map(|mask| buttons | mask).ok_or_else(|| format!("Unknown button: {}", name))
+    })
+}
+
+fn pad_description(buttons: u16) -> String {
+    let names = button_names(buttons);
+    if names.is_empty() {
+        "none".into()
+    } else {
+        names.join(" ")
+    }
+}
+
+// A bare number names a slot kept next to the ROM (game.vb slot 1 is
+// game.ss1); anything else is used as the file name.
+fn state_file_name(rom_file_name: &str, slot: &str) -> String {
+    if slot.bytes().all(|c| c.is_ascii_digit()) {
+        Path::new(rom_file_name).with_extension(format!("ss{}", slot)).to_string_lossy().into_owned()
+    } else {
+        slot.to_string()
+    }
+}
+
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    match args.iter().position(|arg| arg == name) {
+        Some(index) if index + 1 < args.len() => {
+            let value = args.remove(index + 1);
+            args.remove(index);
+            Some(value)
+        }
+        _ => None,
+    }
+}
+
+// Merges the symbols into the label table, replacing labels with the same
+// name.
+fn load_label_file(labels: &mut HashMap<String, u32>, file_name: &str) {
+    match load_symbols(file_name) {
+        Ok(symbols) => {
+            println!("Loaded {} labels from '{}'", symbols.len(), file_name);
+            labels.extend(symbols);
+        }
+        Err(e) => println!("Unable to load labels from '{}': {}", file_name, e),
+    }
+}
+
+// Steps until a watchpoint is tripped, the pc lands on a breakpoint whose
+// condition holds, max_steps instructions have run, the until condition is
+// met or Ctrl-C is pressed. At least one instruction always runs, so
+// continuing from a breakpoint moves past it.
+fn run(avb: &mut AVB, breakpoints: &[Breakpoint], labels: &HashMap<String, u32>, max_steps: Option<usize>, until: RunUntil, interrupted: &AtomicBool) -> StopReason {
+    let mut steps = 0;
+    let start_depth = avb.cpu.call_stack().len();
 
-    match instruction_format {
-        InstructionFormat::I => {
-            let reg1 = (first_halfword & 0x1f) as usize;
-            let reg2 = ((first_halfword >> 5) & 0x1f) as usize;
+    interrupted.store(false, Ordering::SeqCst);
+    avb.interconnect.take_watch_hit();
 
-            if opcode == Opcode::Jmp {
-                println!("jmp [r{}]", reg1);
-            } else {
-                println!("{} r{}, r{}", opcode, reg1, reg2)    
+    loop {
+        let pc = avb.cpu.reg_pc();
+
+        if let Err(e) = avb.step() {
+            return StopReason::Fault(e);
+        }
+        steps += 1;
+
+        if let Some(hit) = avb.interconnect.take_watch_hit() {
+            return StopReason::Watchpoint(hit, pc);
+        }
+
+        let pc = avb.cpu.reg_pc();
+        for (index, breakpoint) in breakpoints.iter().enumerate().filter(|&(_, breakpoint)| breakpoint.addr == pc) {
+            let condition = breakpoint.condition.as_ref()
+                .map_or(Ok(1), |condition| condition.eval(&avb.cpu, &avb.interconnect, labels));
+
+            match condition {
+                Ok(0) => (),
+                Ok(_) => return StopReason::Breakpoint(index),
+                Err(e) => return StopReason::BadCondition(index, e),
             }
+        }
 
-            let imm16 = second_halfword;
+        match until {
+            RunUntil::Addr(addr) if pc == addr => return StopReason::Reached,
+            RunUntil::Return(frames) if avb.cpu.call_stack().len() < start_depth + frames => return StopReason::Reached,
+            _ => (),
+        }
+
+        if max_steps == Some(steps) {
+            return StopReason::StepCount;
+        }
 
-            println!("{}, {:#x}, r{}, r{}", opcode, imm16, reg1, reg2)
+        if interrupted.load(Ordering::SeqCst) {
+            return StopReason::Interrupted;
         }
+    }
+}
 
-        InstructionFormat::II => {
-            let imm5 = (first_halfword & 0x1f) as usize;
-            let reg2 = ((first_halfword >> 5) & 0x1f) as usize;
+fn report_stop(avb: &mut AVB, labels: &mut HashMap<String, u32>, source: &mut SourceView, reason: StopReason, cursor: &mut u32) {
+    match reason {
+        StopReason::Breakpoint(index) => println!("Breakpoint {} hit", index),
+        StopReason::BadCondition(index, e) => println!("Breakpoint {} condition failed: {}", index, e),
+        StopReason::Watchpoint(hit, pc) => print_watch_hit(avb, &hit, pc),
+        StopReason::StepCount | StopReason::Reached => (),
+        StopReason::Interrupted => println!("Interrupted"),
+        StopReason::Fault(e) => println!("{}", e),
+    }
 
-            println!("{} {}, r{}", opcode, imm5, reg2)
+    *cursor = avb.cpu.reg_pc();
+    disassemble_instruction(avb, labels, source, cursor);
+    *cursor = avb.cpu.reg_pc();
+}
+
+fn report_rewind(avb: &mut AVB, labels: &mut HashMap<String, u32>, source: &mut SourceView, cursor: &mut u32) {
+    let cycles = avb.interconnect.cycle_count();
+    println!("Rewound to instruction {} (frame {}, cycle {})", avb.instructions, cycles / CYCLES_PER_FRAME, cycles);
+
+    *cursor = avb.cpu.reg_pc();
+    disassemble_instruction(avb, labels, source, cursor);
+    *cursor = avb.cpu.reg_pc();
+}
+
+// Innermost frame first: the pc and the function it's in, then each call
+// site in turn, ending with the outermost one which has no known caller.
+fn print_backtrace(avb: &AVB, labels: &HashMap<String, u32>) {
+    let call_stack = avb.cpu.call_stack();
+    let mut pc = avb.cpu.reg_pc();
+
+    for (depth, frame) in call_stack.iter().rev().enumerate() {
+        let name = match frame.kind {
+            FrameKind::Call => labels.iter()
+                .find(|&(_, &addr)| addr == frame.target)
+                .map(|(name, _)| format!(".{}", name))
+                .unwrap_or_else(|| format!("sub_{:08x}", frame.target)),
+            FrameKind::Trap(vector) => format!("trap {} handler", vector),
+        };
+
+        println!("#{:<3} 0x{:08x} in {} (returns to 0x{:08x})", depth, pc, name, frame.return_addr);
+
+        pc = frame.call_site;
+    }
+
+    println!("#{:<3} 0x{:08x}", call_stack.len(), pc);
+}
+
+fn is_mapped_range(avb: &AVB, addr: u32, len: u32) -> bool {
+    (0..len).all(|offset| avb.interconnect.is_mapped(addr.wrapping_add(offset)))
+}
+
+fn print_watch_hit(avb: &AVB, hit: &WatchHit, pc: u32) {
+    let watchpoint = &avb.interconnect.watchpoints()[hit.index];
+    let size_suffix = match hit.size {
+        1 => "b",
+        2 => "h",
+        _ => "w",
+    };
+    let width = hit.size as usize * 2;
+
+    println!("Watchpoint {} ({}) hit by instruction at 0x{:08x}", hit.index, watchpoint, pc);
+
+    match hit.access {
+        AccessKind::Read => {
+            println!("read.{} 0x{:08x}: 0x{:0width$x}", size_suffix, hit.addr, hit.new_value, width = width);
+        }
+        AccessKind::Write => {
+            println!("write.{} 0x{:08x}: 0x{:0width$x} -> 0x{:0width$x}",
+                size_suffix, hit.addr, hit.old_value, hit.new_value, width = width);
         }
+    }
+}
 
-        InstructionFormat::V => {
-            let reg1 = (first_halfword & 0x1f) as usize;
-            let reg2 = ((first_halfword >> 5) & 0x1f) as usize;
+fn disassemble_instruction(avb: &mut AVB, labels: &mut HashMap<String, u32>, source: &mut SourceView, cursor: &mut u32) {
+    source.print_location(*cursor);
+    print_labels(labels, *cursor);
 
-            let imm16 = second_halfword;
+    match decode(*cursor, &avb.interconnect) {
+        Ok(instruction) => {
+            let target_label = instruction.branch_target()
+                .and_then(|target| labels.iter().find(|x| *x.1 == target))
+                .map(|x| x.0.as_str());
 
-            println!("{} {:#x}, r{}, r{}", opcode, imm16, reg1, reg2)
+            println!("{}", format_instruction(&instruction, target_label));
+
+            *cursor = instruction.next_addr();
+        }
+        Err(e) => {
+            let halfword = avb.interconnect.peek_halfword(*cursor);
+            println!("0x{:08x} {:02x}{:02x}          {}", cursor, halfword & 0xff, halfword >> 8, e);
+
+            *cursor = cursor.wrapping_add(2);
         }
+    }
+}
+
+// Bytes the analyser never reached from a vector are shown as data rather
+// than decoded into nonsense instructions.
+fn disassemble_data(avb: &mut AVB, labels: &HashMap<String, u32>, code_map: &CodeMap, cursor: &mut u32) {
+    const MAX_BYTES: usize = 8;
 
-        InstructionFormat::VI => {
-            let reg1 = (first_halfword & 0x1f) as usize;
-            let reg2 = ((first_halfword >> 5) & 0x1f) as usize;
+    print_labels(labels, *cursor);
+
+    let addr = *cursor;
+    let mut bytes = Vec::new();
+
+    while bytes.len() < MAX_BYTES && code_map.is_data(*cursor) {
+        bytes.push(avb.interconnect.peek_byte(*cursor));
+        *cursor = cursor.wrapping_add(1);
+    }
+
+    println!("{}", format_data(addr, &bytes));
+}
+
+// Reads assembly a line at a time, writing each instruction at the cursor
+// until an empty line is entered.
+fn assemble_interactive(avb: &mut AVB, labels: &HashMap<String, u32>, cursor: &mut u32) -> Result<(), AvbError> {
+    loop {
+        print!("0x{:08x}: ", cursor);
 
-            let disp16 = second_halfword as i16;
+        stdout().flush()?;
 
-            println!("{} {}[r{}], r{}", opcode, disp16, reg1, reg2)
+        let line = read_stdin()?;
+        if line.is_empty() {
+            break;
+        }
+
+        match assemble_line(&line, *cursor, labels) {
+            Ok(bytes) => {
+                for byte in bytes {
+                    avb.interconnect.patch_byte(*cursor, byte);
+                    *cursor = cursor.wrapping_add(1);
+                }
+            }
+            Err(e) => println!("{}", e),
         }
     }
+
+    Ok(())
 }
 
 fn print_labels(labels: &HashMap<String, u32>, addr: u32) {
@@ -249,10 +1048,15 @@ fn print_labels(labels: &HashMap<String, u32>, addr: u32) {
     }
 }
 
-fn read_stdin() -> String {
+// Underscores too, so symbols from C like _start can be named.
+fn is_label_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+fn read_stdin() -> Result<String, AvbError> {
     let mut input = String::new();
-    stdin().read_line(&mut input).unwrap();
-    input.trim().into()
+    stdin().read_line(&mut input)?;
+    Ok(input.trim().into())
 }
 
 named!(
@@ -260,8 +1064,10 @@ named!(
     complete!(
         terminated!(
             alt_complete!(
-                goto | show_mem | disassemble | exit | repeat |
-                label | add_label | show_regs | step
+                watchpoints | watch | unwatch | rewind | step_back | pad | record_on | record_off | save_labels | load_labels | savestate | loadstate | set | poke | fill | goto | show_mem | delete | disassemble |
+                exit | add_label | assemble | list | label | show_regs | step | breakpoints |
+                set_break | continue_ | next | finish | until | backtrace | print | trace_on |
+                trace_off | repeat
             ),
             eof
         )
@@ -308,13 +1114,321 @@ named!(
     )
 );
 
+named!(
+    assemble<Command>,
+    chain!(
+        alt_complete!(
+            tag!("assemble") | tag!("a")
+        ) ~ addr: opt!(preceded!(space, hex_u32_parser)),
+        || Command::Assemble(addr)
+    )
+);
+
+named!(
+    set_break<Command>,
+    chain!(
+        alt_complete!(
+            tag!("break") | tag!("b")
+        ) ~ space ~ location: location ~
+        condition: opt!(complete!(
+            chain!(
+                space ~ tag!("if") ~ space ~ condition: expression,
+                || condition
+            )
+        )),
+        || Command::Break(location, condition)
+    )
+);
+
+named!(
+    print<Command>,
+    chain!(
+        alt_complete!(
+            tag!("print") | tag!("p")
+        ) ~ space ~ expression: expression,
+        || Command::Print(expression)
+    )
+);
+
+named!(
+    delete<Command>,
+    chain!(
+        tag!("delete") ~ index: opt!(preceded!(space, usize_parser)),
+        || Command::Delete(index)
+    )
+);
+
+named!(
+    breakpoints<Command>,
+    map!(
+        alt_complete!(
+            tag!("breakpoints") | tag!("bp")
+        ),
+        |_| Command::Breakpoints
+    )
+);
+
+named!(
+    continue_<Command>,
+    chain!(
+        alt_complete!(
+            tag!("continue") | tag!("c")
+        ) ~ count: opt!(preceded!(space, usize_parser)),
+        || Command::Continue(count)
+    )
+);
+
+named!(
+    next<Command>,
+    map!(
+        alt_complete!(
+            tag!("next") | tag!("n")
+        ),
+        |_| Command::Next
+    )
+);
+
+named!(
+    finish<Command>,
+    map!(
+        tag!("finish"),
+        |_| Command::Finish
+    )
+);
+
+named!(
+    until<Command>,
+    chain!(
+        alt_complete!(
+            tag!("until") | tag!("u")
+        ) ~ space ~ location: location,
+        || Command::Until(location)
+    )
+);
+
+named!(
+    backtrace<Command>,
+    map!(
+        alt_complete!(
+            tag!("backtrace") | tag!("bt")
+        ),
+        |_| Command::Backtrace
+    )
+);
+
+named!(
+    list<Command>,
+    chain!(
+        tag!("list") ~ location: opt!(complete!(preceded!(space, location))),
+        || Command::List(location)
+    )
+);
+
+named!(
+    watch<Command>,
+    chain!(
+        kind: alt_complete!(
+            map!(tag!("watch"), |_| WatchKind::Write) |
+            map!(tag!("rwatch"), |_| WatchKind::Read) |
+            map!(tag!("awatch"), |_| WatchKind::Access)
+        ) ~
+        space ~
+        start: hex_u32_parser ~
+        end: opt!(complete!(preceded!(space, hex_u32_parser))) ~
+        value: opt!(complete!(
+            chain!(
+                opt!(space) ~ tag!("==") ~ opt!(space) ~ value: hex_u32_parser,
+                || value
+            )
+        )),
+        || Command::Watch(Watchpoint { start, end: end.unwrap_or(start), kind, value })
+    )
+);
+
+named!(
+    unwatch<Command>,
+    chain!(
+        tag!("unwatch") ~ index: opt!(preceded!(space, usize_parser)),
+        || Command::Unwatch(index)
+    )
+);
+
+named!(
+    watchpoints<Command>,
+    map!(
+        alt_complete!(
+            tag!("watchpoints") | tag!("wp")
+        ),
+        |_| Command::Watchpoints
+    )
+);
+
+named!(
+    set<Command>,
+    chain!(
+        tag!("set") ~ space ~ register: register ~ space ~ value: expression,
+        || Command::Set(register, value)
+    )
+);
+
+named!(
+    register<Register>,
+    map_res!(
+        chain!(
+            name: map_res!(alphanumeric, str::from_utf8) ~
+            field: opt!(complete!(preceded!(char!('.'), map_res!(alphanumeric, str::from_utf8)))),
+            || (name, field)
+        ),
+        |(name, field)| Register::from_name(name, field)
+    )
+);
+
+named!(
+    poke<Command>,
+    chain!(
+        tag!("poke") ~
+        size: opt!(complete!(
+            alt_complete!(
+                map!(tag!(".b"), |_| Size::Byte) |
+                map!(tag!(".h"), |_| Size::Halfword) |
+                map!(tag!(".w"), |_| Size::Word)
+            )
+        )) ~
+        space ~ addr: hex_u32_parser ~ space ~ value: expression,
+        || Command::Poke(size.unwrap_or(Size::Word), addr, value)
+    )
+);
+
+named!(
+    fill<Command>,
+    chain!(
+        tag!("fill") ~ space ~ addr: hex_u32_parser ~ space ~ len: hex_u32_parser ~ space ~
+        value: map_res!(hex_u32_parser, u8::try_from),
+        || Command::Fill(addr, len, value)
+    )
+);
+
+named!(
+    trace_on<Command>,
+    chain!(
+        tag!("trace") ~ space ~ tag!("on") ~ space ~ file_name: file_name ~
+        range: opt!(complete!(
+            chain!(
+                space ~ start: hex_u32_parser ~ space ~ end: hex_u32_parser,
+                || (start, end)
+            )
+        )),
+        || Command::TraceOn(file_name, range)
+    )
+);
+
+named!(
+    save_labels<Command>,
+    chain!(
+        tag!("savelabels") ~ space ~ file_name: file_name,
+        || Command::SaveLabels(file_name)
+    )
+);
+
+named!(
+    load_labels<Command>,
+    chain!(
+        tag!("loadlabels") ~ space ~ file_name: file_name,
+        || Command::LoadLabels(file_name)
+    )
+);
+
+named!(
+    rewind<Command>,
+    chain!(
+        tag!("rewind") ~ space ~ frames: usize_parser,
+        || Command::Rewind(frames as u64)
+    )
+);
+
+named!(
+    step_back<Command>,
+    chain!(
+        tag!("rstep") ~ count: opt!(preceded!(space, usize_parser)),
+        || Command::StepBack(count.unwrap_or(1) as u64)
+    )
+);
+
+named!(
+    pad<Command>,
+    chain!(
+        tag!("pad") ~
+        names: many0!(complete!(preceded!(space, map_res!(map_res!(alphanumeric, str::from_utf8), FromStr::from_str)))),
+        || Command::Pad(names)
+    )
+);
+
+named!(
+    record_on<Command>,
+    chain!(
+        tag!("record") ~ space ~ tag!("on") ~ space ~ file_name: file_name,
+        || Command::RecordOn(file_name)
+    )
+);
+
+named!(
+    record_off<Command>,
+    map!(
+        chain!(tag!("record") ~ space ~ tag!("off"), || ()),
+        |_| Command::RecordOff
+    )
+);
+
+named!(
+    savestate<Command>,
+    chain!(
+        tag!("savestate") ~ space ~ slot: file_name,
+        || Command::SaveState(slot)
+    )
+);
+
+named!(
+    loadstate<Command>,
+    chain!(
+        tag!("loadstate") ~ space ~ slot: file_name,
+        || Command::LoadState(slot)
+    )
+);
+
+named!(
+    file_name<String>,
+    map_res!(
+        map_res!(
+            is_not!(" \t"),
+            str::from_utf8
+        ),
+        FromStr::from_str
+    )
+);
+
+named!(
+    trace_off<Command>,
+    map!(
+        chain!(tag!("trace") ~ space ~ tag!("off"), || ()),
+        |_| Command::TraceOff
+    )
+);
+
+named!(
+    location<Location>,
+    alt_complete!(
+        map!(label_name, Location::Label) |
+        map!(hex_u32_parser, Location::Addr)
+    )
+);
+
 named!(
     label_name<String>,
     preceded!(
         char!('.'),
         map_res!(
             map_res!(
-                alphanumeric, str::from_utf8
+                take_while1!(is_label_char), str::from_utf8
             ),
             FromStr::from_str
         )
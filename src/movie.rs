@@ -0,0 +1,300 @@
+use interconnect::*;
+use nvc::*;
+use savestate::*;
+use rewind::*;
+use crc32::*;
+use error::*;
+use avb::*;
+
+use std::path::Path;
+use std::fs::File;
+use std::io::{self, Read, Write, BufWriter, Error, ErrorKind};
+
+const MAGIC: &[u8] = b"AVBMOVIE";
+const MOVIE_VERSION: u32 = 1;
+
+// Frames between state hashes; once a second.
+const CHECKPOINT_INTERVAL: u32 = 50;
+
+pub struct Checkpoint {
+    pub frame: u32,
+    pub hash: u32,
+}
+
+// The state recording started from and the buttons latched at each frame
+// boundary after it. Given both, emulation is deterministic, so the
+// periodic state hashes pin down where a replay first goes wrong.
+pub struct Movie {
+    pub rom_crc32: u32,
+    pub initial_state: Vec<u8>,
+    pub inputs: Vec<u16>,
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+impl Movie {
+    pub fn load<P: AsRef<Path>>(file_name: P) -> io::Result<Movie> {
+        let mut bytes = Vec::new();
+        File::open(file_name)?.read_to_end(&mut bytes)?;
+
+        let mut reader = Reader { bytes: &bytes, pos: 0 };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid_data("Not a movie file."));
+        }
+
+        let version = reader.word()?;
+        if version > MOVIE_VERSION {
+            return Err(invalid_data(&format!("Movie version {} is newer than this build supports.", version)));
+        }
+
+        let rom_crc32 = reader.word()?;
+
+        let state_len = reader.word()? as usize;
+        let initial_state = reader.take(state_len)?.to_vec();
+
+        let frame_count = reader.word()?;
+        let mut inputs = Vec::new();
+        for _ in 0..frame_count {
+            let bytes = reader.take(2)?;
+            inputs.push((bytes[0] as u16) | ((bytes[1] as u16) << 8));
+        }
+
+        let checkpoint_count = reader.word()?;
+        let mut checkpoints = Vec::new();
+        for _ in 0..checkpoint_count {
+            checkpoints.push(Checkpoint { frame: reader.word()?, hash: reader.word()? });
+        }
+
+        Ok(Movie { rom_crc32, initial_state, inputs, checkpoints })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, file_name: P) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(file_name)?);
+
+        out.write_all(MAGIC)?;
+        out.write_all(&MOVIE_VERSION.to_le_bytes())?;
+        out.write_all(&self.rom_crc32.to_le_bytes())?;
+
+        out.write_all(&(self.initial_state.len() as u32).to_le_bytes())?;
+        out.write_all(&self.initial_state)?;
+
+        out.write_all(&(self.inputs.len() as u32).to_le_bytes())?;
+        for buttons in self.inputs.iter() {
+            out.write_all(&buttons.to_le_bytes())?;
+        }
+
+        out.write_all(&(self.checkpoints.len() as u32).to_le_bytes())?;
+        for checkpoint in self.checkpoints.iter() {
+            out.write_all(&checkpoint.frame.to_le_bytes())?;
+            out.write_all(&checkpoint.hash.to_le_bytes())?;
+        }
+
+        out.flush()
+    }
+
+    // Runs the whole movie from its initial state, holding each frame's
+    // buttons so they're latched at the frame boundary as recording did,
+    // and stops at the first checkpoint whose hash differs. Returns how many
+    // checkpoints matched.
+    pub fn play(&self, avb: &mut AVB) -> Result<usize, String> {
+        let rom_crc32 = avb.interconnect.rom().crc32();
+        if self.rom_crc32 != rom_crc32 {
+            return Err(format!("Movie was recorded with a different ROM (CRC-32 {:08x}, loaded ROM is {:08x})",
+                self.rom_crc32, rom_crc32));
+        }
+
+        load_state(&self.initial_state, &mut avb.cpu, &mut avb.interconnect).map_err(|e| format!("Bad initial state: {}", e))?;
+
+        let mut checkpoints = self.checkpoints.iter().peekable();
+        let mut verified = 0;
+
+        for (index, &buttons) in self.inputs.iter().enumerate() {
+            avb.pad_input = buttons;
+
+            loop {
+                let frame = avb.interconnect.cycle_count() / CYCLES_PER_FRAME;
+                match avb.step() {
+                    // Recording ran through the same faults; the state
+                    // hashes catch any difference.
+                    Ok(()) | Err(AvbError::BusFault { .. }) => (),
+                    Err(e) => return Err(format!("Playback stopped at frame {}: {}", index + 1, e)),
+                }
+                if avb.interconnect.cycle_count() / CYCLES_PER_FRAME != frame {
+                    break;
+                }
+            }
+
+            let frame = index as u32 + 1;
+            if let Some(checkpoint) = checkpoints.next_if(|checkpoint| checkpoint.frame == frame) {
+                let hash = state_hash(&avb.cpu, &avb.interconnect);
+                if hash != checkpoint.hash {
+                    return Err(format!("Desync at frame {}: state hash is {:08x}, movie has {:08x}", frame, hash, checkpoint.hash));
+                }
+                verified += 1;
+            }
+        }
+
+        Ok(verified)
+    }
+}
+
+pub struct MovieRecorder {
+    movie: Movie,
+    last_hash: u32,
+}
+
+impl MovieRecorder {
+    pub fn start(cpu: &Nvc, interconnect: &Interconnect) -> MovieRecorder {
+        MovieRecorder {
+            movie: Movie {
+                rom_crc32: interconnect.rom().crc32(),
+                initial_state: save_state(cpu, interconnect),
+                inputs: Vec::new(),
+                checkpoints: Vec::new(),
+            },
+            last_hash: 0,
+        }
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.movie.inputs.len()
+    }
+
+    // Called at each frame boundary, once the new buttons are latched.
+    pub fn frame(&mut self, cpu: &Nvc, interconnect: &Interconnect) {
+        self.movie.inputs.push(interconnect.game_pad());
+
+        let frame = self.movie.inputs.len() as u32;
+        self.last_hash = state_hash(cpu, interconnect);
+        if frame.is_multiple_of(CHECKPOINT_INTERVAL) {
+            self.movie.checkpoints.push(Checkpoint { frame, hash: self.last_hash });
+        }
+    }
+
+    // The last frame always gets a checkpoint, so even a short movie is
+    // verified.
+    pub fn finish(mut self) -> Movie {
+        let frame = self.movie.inputs.len() as u32;
+        if frame > 0 && !frame.is_multiple_of(CHECKPOINT_INTERVAL) {
+            self.movie.checkpoints.push(Checkpoint { frame, hash: self.last_hash });
+        }
+
+        self.movie
+    }
+}
+
+fn state_hash(cpu: &Nvc, interconnect: &Interconnect) -> u32 {
+    crc32(&save_state(cpu, interconnect))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self.bytes.get(self.pos..self.pos + len).ok_or_else(|| invalid_data("Truncated movie file."))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn word(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok((bytes[0] as u32) | ((bytes[1] as u32) << 8) | ((bytes[2] as u32) << 16) | ((bytes[3] as u32) << 24))
+    }
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rom::*;
+    use assembler::*;
+    use game_pad::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    const FRAMES: usize = 3;
+
+    // Copies the low byte of the game pad into work RAM forever, so the
+    // buttons held show up in the state.
+    const PROGRAM: &str = "
+        start:
+            movhi 0x0200, r0, r1
+            movhi 0x0500, r0, r3
+        loop:
+            ld.b 0x10[r1], r2
+            st.b r2, 0[r3]
+            jr loop
+    ";
+
+    fn avb() -> AVB {
+        AVB::new(Rom::from_bytes(assemble(PROGRAM).unwrap()).unwrap())
+    }
+
+    fn run_frames(avb: &mut AVB, inputs: &[u16]) {
+        for &buttons in inputs.iter() {
+            avb.pad_input = buttons;
+            let frame = avb.interconnect.cycle_count() / CYCLES_PER_FRAME;
+            while avb.interconnect.cycle_count() / CYCLES_PER_FRAME == frame {
+                avb.step().unwrap();
+            }
+        }
+    }
+
+    fn record(inputs: &[u16]) -> Movie {
+        let mut avb = avb();
+        avb.recorder = Some(MovieRecorder::start(&avb.cpu, &avb.interconnect));
+        run_frames(&mut avb, inputs);
+        avb.recorder.take().unwrap().finish()
+    }
+
+    #[test]
+    fn recorded_movies_play_back() {
+        let movie = record(&[0x0000, 0x0004, 0x0010]);
+        assert_eq!(movie.inputs, [0x0000, 0x0004, 0x0010]);
+        assert_eq!(movie.checkpoints.len(), 1);
+        assert_eq!(movie.checkpoints[0].frame, FRAMES as u32);
+
+        let path = env::temp_dir().join(format!("avb-movie-test-{}.mov", process::id()));
+        movie.save(&path).unwrap();
+        let loaded = Movie::load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.rom_crc32, movie.rom_crc32);
+        assert_eq!(loaded.initial_state, movie.initial_state);
+        assert_eq!(loaded.inputs, movie.inputs);
+
+        let mut avb = avb();
+        assert_eq!(loaded.play(&mut avb), Ok(1));
+        // Each frame's buttons are latched as it ends, so the program has
+        // only seen up to the second frame's.
+        assert_eq!(avb.interconnect.game_pad(), 0x0010);
+        assert_eq!(avb.interconnect.read_byte(0x05000000), (0x0004 | PAD_SIGNATURE) as u8);
+    }
+
+    #[test]
+    fn changed_input_desyncs() {
+        let mut movie = record(&[0x0000, 0x0004, 0x0010]);
+        movie.inputs[FRAMES - 1] = 0x0020;
+
+        let error = movie.play(&mut avb()).unwrap_err();
+        assert!(error.starts_with(&format!("Desync at frame {}:", FRAMES)), "{}", error);
+    }
+
+    #[test]
+    fn movies_for_another_rom_are_refused() {
+        let mut movie = record(&[0x0000]);
+        movie.rom_crc32 ^= 1;
+
+        let error = movie.play(&mut avb()).unwrap_err();
+        assert!(error.contains("different ROM"), "{}", error);
+    }
+}
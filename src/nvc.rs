@@ -1,10 +1,115 @@
 use instruction::*;
 use interconnect::*;
+use error::*;
+
+// Processor ID and cache control values the VB's NVC reports.
+const PIR: u32 = 0x00005346;
+const TKCW: u32 = 0x000000e0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PswFlag {
+    Z,
+    S,
+    Ov,
+    Cy,
+    Fpr,
+    Fud,
+    Fov,
+    Fzd,
+    Fiv,
+    Fro,
+    Id,
+    Ae,
+    Ep,
+    Np,
+    I,
+}
+
+const PSW_FLAGS: [PswFlag; 15] = [
+    PswFlag::Z, PswFlag::S, PswFlag::Ov, PswFlag::Cy, PswFlag::Fpr, PswFlag::Fud,
+    PswFlag::Fov, PswFlag::Fzd, PswFlag::Fiv, PswFlag::Fro, PswFlag::Id, PswFlag::Ae,
+    PswFlag::Ep, PswFlag::Np, PswFlag::I,
+];
+
+impl PswFlag {
+    pub fn from_name(name: &str) -> Option<PswFlag> {
+        PSW_FLAGS.iter().cloned().find(|flag| flag.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PswFlag::Z => "z",
+            PswFlag::S => "s",
+            PswFlag::Ov => "ov",
+            PswFlag::Cy => "cy",
+            PswFlag::Fpr => "fpr",
+            PswFlag::Fud => "fud",
+            PswFlag::Fov => "fov",
+            PswFlag::Fzd => "fzd",
+            PswFlag::Fiv => "fiv",
+            PswFlag::Fro => "fro",
+            PswFlag::Id => "id",
+            PswFlag::Ae => "ae",
+            PswFlag::Ep => "ep",
+            PswFlag::Np => "np",
+            PswFlag::I => "i",
+        }
+    }
+
+    // Bits of the flag within psw; only the interrupt level is wider than one.
+    pub fn mask(self) -> u32 {
+        match self {
+            PswFlag::Z => 1 << 0,
+            PswFlag::S => 1 << 1,
+            PswFlag::Ov => 1 << 2,
+            PswFlag::Cy => 1 << 3,
+            PswFlag::Fpr => 1 << 4,
+            PswFlag::Fud => 1 << 5,
+            PswFlag::Fov => 1 << 6,
+            PswFlag::Fzd => 1 << 7,
+            PswFlag::Fiv => 1 << 8,
+            PswFlag::Fro => 1 << 9,
+            PswFlag::Id => 1 << 12,
+            PswFlag::Ae => 1 << 13,
+            PswFlag::Ep => 1 << 14,
+            PswFlag::Np => 1 << 15,
+            PswFlag::I => 0xf << 16,
+        }
+    }
+}
+
+// Deeper than any real game nests; code using jal as a plain jump would
+// otherwise grow the shadow stack forever.
+const MAX_CALL_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Call,
+    Trap(u32),
+}
+
+// An entry on the shadow call stack: where control was transferred from and
+// to, and where it will come back to.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub call_site: u32,
+    pub target: u32,
+    pub return_addr: u32,
+}
 
 pub struct Nvc {
     reg_pc: u32, 
     reg_gpr: [u32; 31],
 
+    reg_eipc: u32,
+    reg_eipsw: u32,
+    reg_fepc: u32,
+    reg_fepsw: u32,
+    reg_ecr: u32,
+    reg_chcw: u32,
+    reg_adtre: u32,
+
     psw_zero: bool,
     psw_sign: bool,
     psw_overflow: bool,
@@ -20,6 +125,16 @@ pub struct Nvc {
     psw_exception_pending: bool,
     psw_nmi_pending: bool,
     psw_interrupt_mask_level: usize,
+
+    // Tracked as instructions execute rather than unwound from memory, so it
+    // survives r31 being spilled.
+    call_stack: Vec<Frame>,
+}
+
+impl Default for Nvc {
+    fn default() -> Nvc {
+        Nvc::new()
+    }
 }
 
 impl Nvc {
@@ -28,6 +143,14 @@ impl Nvc {
             reg_pc: 0xfffffff0,
             reg_gpr: [0; 31],
 
+            reg_eipc: 0,
+            reg_eipsw: 0,
+            reg_fepc: 0,
+            reg_fepsw: 0,
+            reg_ecr: 0x0000fff0,
+            reg_chcw: 0,
+            reg_adtre: 0,
+
             psw_zero: false,
             psw_sign: false,
             psw_overflow: false,
@@ -43,6 +166,8 @@ impl Nvc {
             psw_exception_pending: false,
             psw_nmi_pending: true,
             psw_interrupt_mask_level: 0,
+
+            call_stack: Vec::new(),
         }
     }
 
@@ -58,7 +183,12 @@ impl Nvc {
         }
     }
 
-    fn set_reg_gpr(&mut self, index: usize, value: u32) {
+    pub fn set_reg_pc(&mut self, value: u32) {
+        // Instructions are halfword aligned.
+        self.reg_pc = value & 0xfffffffe;
+    }
+
+    pub fn set_reg_gpr(&mut self, index: usize, value: u32) {
         if index != 0 {
             self.reg_gpr[index - 1] = value;
         }
@@ -82,32 +212,124 @@ impl Nvc {
         (self.psw_interrupt_mask_level as u32) << 16
     }
 
-    pub fn step(&mut self, interconnect: &mut Interconnect) {
-        let first_halfword = interconnect.read_halfword(self.reg_pc);
-        self.reg_pc = self.reg_pc.wrapping_add(2);
+    pub fn set_reg_psw(&mut self, value: u32) {
+        self.psw_zero = (value & (1 << 0)) != 0;
+        self.psw_sign = (value & (1 << 1)) != 0;
+        self.psw_overflow = (value & (1 << 2)) != 0;
+        self.psw_carry = (value & (1 << 3)) != 0;
+        self.psw_fp_precision_degredation = (value & (1 << 4)) != 0;
+        self.psw_fp_underflow = (value & (1 << 5)) != 0;
+        self.psw_fp_overflow = (value & (1 << 6)) != 0;
+        self.psw_fp_zero_division = (value & (1 << 7)) != 0;
+        self.psw_fp_invalid_operation = (value & (1 << 8)) != 0;
+        self.psw_fp_reserved_operand = (value & (1 << 9)) != 0;
+        self.psw_interrupt_disable = (value & (1 << 12)) != 0;
+        self.psw_address_trap_enable = (value & (1 << 13)) != 0;
+        self.psw_exception_pending = (value & (1 << 14)) != 0;
+        self.psw_nmi_pending = (value & (1 << 15)) != 0;
+        self.psw_interrupt_mask_level = ((value >> 16) & 0x0f) as usize;
+    }
 
-        let opcode = Opcode::from_halfword(first_halfword);
-        let instruction_format = opcode.instruction_format();
+    pub fn psw_flag(&self, flag: PswFlag) -> u32 {
+        let mask = flag.mask();
+        (self.reg_psw() & mask) >> mask.trailing_zeros()
+    }
 
-        let second_halfword = if instruction_format.has_second_halfword() {
-        let second_halfword = interconnect.read_halfword(self.reg_pc);
+    pub fn set_psw_flag(&mut self, flag: PswFlag, value: u32) {
+        let mask = flag.mask();
+        let psw = (self.reg_psw() & !mask) | ((value << mask.trailing_zeros()) & mask);
+        self.set_reg_psw(psw);
+    }
 
-        self.reg_pc = self.reg_pc.wrapping_add(2);
-        second_halfword
-        } else {
-            print!("      ");
-            0
-        };
+    pub fn reg_system(&self, index: usize) -> u32 {
+        match index {
+            0 => self.reg_eipc,
+            1 => self.reg_eipsw,
+            2 => self.reg_fepc,
+            3 => self.reg_fepsw,
+            4 => self.reg_ecr,
+            5 => self.reg_psw(),
+            6 => PIR,
+            7 => TKCW,
+            24 => self.reg_chcw,
+            25 => self.reg_adtre,
+            _ => 0,
+        }
+    }
+
+    // PIR and TKCW are fixed by the chip, so writes to them (and to the
+    // unassigned registers) are ignored as they are by ldsr.
+    pub fn set_reg_system(&mut self, index: usize, value: u32) {
+        match index {
+            0 => self.reg_eipc = value,
+            1 => self.reg_eipsw = value,
+            2 => self.reg_fepc = value,
+            3 => self.reg_fepsw = value,
+            4 => self.reg_ecr = value,
+            5 => self.set_reg_psw(value),
+            24 => self.reg_chcw = value,
+            25 => self.reg_adtre = value,
+            _ => (),
+        }
+    }
+
+    // Instructions that can't be decoded or aren't implemented yet are left
+    // unexecuted. A bus fault doesn't stop the instruction; the access reads
+    // zero or is dropped, and the fault is reported once it's done.
+    pub fn step(&mut self, interconnect: &mut Interconnect) -> Result<(), AvbError> {
+        // Left over from the debugger peeking at unmapped memory.
+        interconnect.take_bus_fault();
 
-        match opcode {
-            Opcode::Jmp => format_i(|reg1, _| {
+        let instruction = decode(self.reg_pc, interconnect)?;
+        self.reg_pc = instruction.next_addr();
+
+        match (instruction.opcode, instruction.operands) {
+            (Opcode::Jmp, Operands::I { reg1, .. }) => {
                 self.reg_pc = self.reg_gpr(reg1);
-            }, first_halfword),
-            Opcode::MovImm => format_ii(|imm5, reg2| {
+
+                if reg1 == 31 {
+                    self.pop_call();
+                }
+            }
+            (Opcode::Jr, Operands::IV { disp26 }) => {
+                self.reg_pc = instruction.addr.wrapping_add(disp26 as u32);
+            }
+            (Opcode::Jal, Operands::IV { disp26 }) => {
+                self.set_reg_gpr(31, instruction.next_addr());
+                self.reg_pc = instruction.addr.wrapping_add(disp26 as u32);
+
+                self.push_frame(FrameKind::Call, instruction.addr, instruction.next_addr());
+            }
+            (Opcode::Trap, Operands::II { imm5, .. }) => {
+                let vector = imm5 as u32;
+                let handler = if vector < 0x10 { 0xffffffa0 } else { 0xffffffb0 };
+                if let Err(e) = self.enter_exception(instruction.addr, 0xffa0 + vector, handler, instruction.next_addr()) {
+                    self.reg_pc = instruction.addr;
+                    return Err(e);
+                }
+
+                self.push_frame(FrameKind::Trap(vector), instruction.addr, instruction.next_addr());
+            }
+            (Opcode::Reti, _) => {
+                // Returning from the duplexed (NMI/fatal) handler uses the
+                // fe* registers, anything else the ei* ones.
+                if self.psw_nmi_pending {
+                    self.reg_pc = self.reg_fepc;
+                    let psw = self.reg_fepsw;
+                    self.set_reg_psw(psw);
+                } else {
+                    self.reg_pc = self.reg_eipc;
+                    let psw = self.reg_eipsw;
+                    self.set_reg_psw(psw);
+                }
+
+                self.pop_exception();
+            }
+            (Opcode::MovImm, Operands::II { imm5, reg2 }) => {
                 let value = sign_extend_imm5(imm5);
                 self.set_reg_gpr(reg2, value);
-            }, first_halfword),
-            Opcode::Sub => format_i(|reg1, reg2| {
+            }
+            (Opcode::Sub, Operands::I { reg1, reg2 }) => {
                 let lhs = self.reg_gpr(reg2);
                 let rhs = self.reg_gpr(reg1);
 
@@ -117,28 +339,145 @@ impl Nvc {
                 self.set_zero_sign_flags(res);
                 self.psw_overflow = (((lhs ^ rhs) & !(rhs ^ res)) & 0x80000000) != 0;
                 self.psw_carry = carry;
-            }, first_halfword),
-            Opcode::Movea => format_v(|reg1, reg2, imm16| {
+            }
+            (Opcode::Movea, Operands::V { reg1, reg2, imm16 }) => {
                 let res = self.reg_gpr(reg1).wrapping_add((imm16 as i16) as u32);
                 self.set_reg_gpr(reg2, res);
-            }, first_halfword, second_halfword),
-            Opcode::Movhi => format_v(|reg1, reg2, imm16| {
+            }
+            (Opcode::Movhi, Operands::V { reg1, reg2, imm16 }) => {
                 let res = self.reg_gpr(reg1).wrapping_add((imm16 as u32) << 16);
                 self.set_reg_gpr(reg2, res);
-            }, first_halfword, second_halfword),
-            Opcode::Stb => format_vi(|reg1, reg2, disp16| {
+            }
+            (Opcode::Ldb, Operands::VI { reg1, reg2, disp16 }) => {
+                let addr = self.reg_gpr(reg1).wrapping_add(disp16 as u32);
+                let value = (interconnect.read_byte(addr) as i8) as u32;
+                self.set_reg_gpr(reg2, value);
+            }
+            (Opcode::Ldh, Operands::VI { reg1, reg2, disp16 }) => {
+                let addr = self.reg_gpr(reg1).wrapping_add(disp16 as u32);
+                let value = (interconnect.read_halfword(addr) as i16) as u32;
+                self.set_reg_gpr(reg2, value);
+            }
+            (Opcode::Ldw, Operands::VI { reg1, reg2, disp16 }) |
+            (Opcode::Inw, Operands::VI { reg1, reg2, disp16 }) => {
+                let addr = self.reg_gpr(reg1).wrapping_add(disp16 as u32);
+                let value = interconnect.read_word(addr);
+                self.set_reg_gpr(reg2, value);
+            }
+            (Opcode::Inb, Operands::VI { reg1, reg2, disp16 }) => {
+                let addr = self.reg_gpr(reg1).wrapping_add(disp16 as u32);
+                let value = interconnect.read_byte(addr) as u32;
+                self.set_reg_gpr(reg2, value);
+            }
+            (Opcode::Inh, Operands::VI { reg1, reg2, disp16 }) => {
+                let addr = self.reg_gpr(reg1).wrapping_add(disp16 as u32);
+                let value = interconnect.read_halfword(addr) as u32;
+                self.set_reg_gpr(reg2, value);
+            }
+            (Opcode::Stb, Operands::VI { reg1, reg2, disp16 }) |
+            (Opcode::Outb, Operands::VI { reg1, reg2, disp16 }) => {
                 let addr = self.reg_gpr(reg1).wrapping_add(disp16 as u32);
                 let value = self.reg_gpr(reg2) as u8;
                 interconnect.write_byte(addr, value);
-            }, first_halfword, second_halfword),
-            Opcode::Outw => format_vi(|reg1, reg2, disp16| {
+            }
+            (Opcode::Sth, Operands::VI { reg1, reg2, disp16 }) |
+            (Opcode::Outh, Operands::VI { reg1, reg2, disp16 }) => {
+                let addr = self.reg_gpr(reg1).wrapping_add(disp16 as u32);
+                let value = self.reg_gpr(reg2) as u16;
+                interconnect.write_halfword(addr, value);
+            }
+            (Opcode::Stw, Operands::VI { reg1, reg2, disp16 }) |
+            (Opcode::Outw, Operands::VI { reg1, reg2, disp16 }) => {
                 let addr = self.reg_gpr(reg1).wrapping_add(disp16 as u32);
                 let value = self.reg_gpr(reg2);
                 interconnect.write_word(addr, value);
-            }, first_halfword, second_halfword),
+            }
+            _ => {
+                self.reg_pc = instruction.addr;
+                return Err(AvbError::Unimplemented(instruction));
+            }
         }
 
-        interconnect.cycles(opcode.num_cycles());
+        interconnect.cycles(instruction.opcode.num_cycles());
+
+        match interconnect.take_bus_fault() {
+            Some(addr) => Err(AvbError::BusFault { pc: instruction.addr, addr }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.call_stack
+    }
+
+    pub fn set_call_stack(&mut self, call_stack: Vec<Frame>) {
+        self.call_stack = call_stack;
+    }
+
+    // A second exception raised while handling one is duplexed through the
+    // fe* registers and the fixed handler at 0xffffffd0. One raised while NP
+    // is set, during the duplexed handler or from reset until the program
+    // clears it, is fatal: the CPU would halt, so it's reported instead with
+    // the machine left as it was.
+    fn enter_exception(&mut self, pc: u32, code: u32, handler: u32, return_addr: u32) -> Result<(), AvbError> {
+        if self.psw_nmi_pending {
+            return Err(AvbError::FatalException { pc, code });
+        }
+
+        let psw = self.reg_psw();
+
+        if self.psw_exception_pending {
+            self.reg_fepc = return_addr;
+            self.reg_fepsw = psw;
+            self.reg_ecr = (self.reg_ecr & 0x0000ffff) | (code << 16);
+            self.psw_nmi_pending = true;
+            self.reg_pc = 0xffffffd0;
+        } else {
+            self.reg_eipc = return_addr;
+            self.reg_eipsw = psw;
+            self.reg_ecr = (self.reg_ecr & 0xffff0000) | code;
+            self.psw_exception_pending = true;
+            self.reg_pc = handler;
+        }
+
+        self.psw_interrupt_disable = true;
+        self.psw_address_trap_enable = false;
+
+        Ok(())
+    }
+
+    fn push_frame(&mut self, kind: FrameKind, call_site: u32, return_addr: u32) {
+        if self.call_stack.len() == MAX_CALL_DEPTH {
+            self.call_stack.remove(0);
+        }
+
+        let target = self.reg_pc;
+        self.call_stack.push(Frame { kind, call_site, target, return_addr });
+    }
+
+    // Returning to an outer frame's return address (a longjmp, or a
+    // subroutine that returned on behalf of its callee) unwinds everything
+    // above it; otherwise only the innermost call is popped.
+    fn pop_call(&mut self) {
+        let pc = self.reg_pc;
+        let index = self.call_stack.iter()
+            .rposition(|frame| frame.kind == FrameKind::Call && frame.return_addr == pc);
+
+        match index {
+            Some(index) => self.call_stack.truncate(index),
+            None => {
+                if self.call_stack.last().is_some_and(|frame| frame.kind == FrameKind::Call) {
+                    self.call_stack.pop();
+                }
+            }
+        }
+    }
+
+    // Calls made inside a handler that never returned go with it.
+    fn pop_exception(&mut self) {
+        if let Some(index) = self.call_stack.iter().rposition(|frame| frame.kind != FrameKind::Call) {
+            self.call_stack.truncate(index);
+        }
     }
 
     fn set_zero_sign_flags(&mut self, value: u32) {
@@ -147,33 +486,87 @@ impl Nvc {
     }
 }
 
-fn format_i<F: FnOnce(usize, usize)>(f: F, first_halfword: u16) {
-    let reg1 = (first_halfword & 0x1f) as usize;
-    let reg2 = ((first_halfword >> 5) & 0x1f) as usize;
-    f(reg1, reg2);
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-fn format_ii<F: FnOnce(usize, usize)>(f: F, first_halfword: u16) {
-    let imm5 = (first_halfword & 0x1f) as usize;
-    let reg2 = ((first_halfword >> 5) & 0x1f) as usize;
-    f(imm5, reg2);
-}
+    const ROM_SIZE: usize = 1024;
+    const RESET: u32 = 0xfffffff0;
+    const TRAP_HANDLER: u32 = 0xffffffa0;
+    const DUPLEXED_HANDLER: u32 = 0xffffffd0;
 
-fn format_v<F: FnOnce(usize, usize, u16)>(f: F, first_halfword: u16, second_halfword: u16) {
-    let reg1 = (first_halfword & 0x1f) as usize;
-    let reg2 = ((first_halfword >> 5) & 0x1f) as usize;
-    let imm16 = second_halfword;
-    f(reg1, reg2, imm16);
-}
+    fn put(rom: &mut [u8], addr: u32, opcode: Opcode, operands: Operands) {
+        let offset = (addr as usize) & (ROM_SIZE - 1);
+        let (first_halfword, _) = encode(opcode, operands);
+        rom[offset..offset + 2].copy_from_slice(&first_halfword.to_le_bytes());
+    }
 
-fn format_vi<F: FnOnce(usize, usize, i16)>(f: F, first_halfword: u16, second_halfword: u16) {
-    let reg1 = (first_halfword & 0x1f) as usize;
-    let reg2 = ((first_halfword >> 5) & 0x1f) as usize;
-    let disp16 = second_halfword as i16;
-    f(reg1, reg2, disp16);
-}
+    // trap 5 at reset and again in its handler, which otherwise sets r6 and
+    // returns; the duplexed handler just returns.
+    fn interconnect() -> Interconnect {
+        let mut rom = vec![0xff; ROM_SIZE];
+        put(&mut rom, RESET, Opcode::Trap, Operands::II { imm5: 5, reg2: 0 });
+        put(&mut rom, TRAP_HANDLER, Opcode::MovImm, Operands::II { imm5: 7, reg2: 6 });
+        put(&mut rom, TRAP_HANDLER + 2, Opcode::Reti, Operands::II { imm5: 0, reg2: 0 });
+        put(&mut rom, TRAP_HANDLER + 4, Opcode::Trap, Operands::II { imm5: 5, reg2: 0 });
+        put(&mut rom, DUPLEXED_HANDLER, Opcode::Reti, Operands::II { imm5: 0, reg2: 0 });
+        Interconnect::with_rom_bytes(rom)
+    }
+
+    // Reset leaves NP set, and ldsr isn't implemented to clear it.
+    fn cpu_with_np_clear() -> Nvc {
+        let mut cpu = Nvc::new();
+        cpu.set_reg_psw(0);
+        cpu
+    }
+
+    #[test]
+    fn trap_and_reti_round_trip() {
+        let mut interconnect = interconnect();
+        let mut cpu = cpu_with_np_clear();
+
+        cpu.step(&mut interconnect).unwrap();
+        assert_eq!(cpu.reg_pc(), TRAP_HANDLER);
+        assert_eq!(cpu.reg_system(4) & 0xffff, 0xffa5);
+        assert_eq!(cpu.call_stack().len(), 1);
+        assert_eq!(cpu.call_stack()[0].kind, FrameKind::Trap(5));
+
+        cpu.step(&mut interconnect).unwrap();
+        cpu.step(&mut interconnect).unwrap();
+        assert_eq!(cpu.reg_pc(), RESET + 2);
+        assert_eq!(cpu.reg_gpr(6), 7);
+        assert_eq!(cpu.reg_psw(), 0);
+        assert!(cpu.call_stack().is_empty());
+    }
+
+    #[test]
+    fn trap_in_a_handler_is_duplexed() {
+        let mut interconnect = interconnect();
+        let mut cpu = cpu_with_np_clear();
 
-fn sign_extend_imm5(imm5: usize) -> u32 {
-    let imm5 = imm5 | (if imm5 & 0x10 == 0 { 0x00 } else { 0xe0 });
-    (imm5 as i8) as u32
+        cpu.step(&mut interconnect).unwrap();
+        cpu.set_reg_pc(TRAP_HANDLER + 4);
+        cpu.step(&mut interconnect).unwrap();
+        assert_eq!(cpu.reg_pc(), DUPLEXED_HANDLER);
+        assert_eq!(cpu.call_stack().len(), 2);
+
+        cpu.step(&mut interconnect).unwrap();
+        assert_eq!(cpu.reg_pc(), TRAP_HANDLER + 6);
+        assert_eq!(cpu.call_stack().len(), 1);
+    }
+
+    #[test]
+    fn trap_with_np_set_is_fatal() {
+        let mut interconnect = interconnect();
+        let mut cpu = Nvc::new();
+        let psw = cpu.reg_psw();
+
+        match cpu.step(&mut interconnect) {
+            Err(AvbError::FatalException { pc, code }) => assert_eq!((pc, code), (RESET, 0xffa5)),
+            result => panic!("{:?}", result),
+        }
+        assert_eq!(cpu.reg_pc(), RESET);
+        assert_eq!(cpu.reg_psw(), psw);
+        assert!(cpu.call_stack().is_empty());
+    }
 }
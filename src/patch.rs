@@ -0,0 +1,461 @@
+use crc32::*;
+
+use std::path::Path;
+use std::fs::File;
+use std::fmt;
+use std::error;
+use std::io::{self, Read};
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+// Source, target and patch CRC-32s.
+const BPS_FOOTER_SIZE: usize = 12;
+
+const BPS_SOURCE_READ: u64 = 0;
+const BPS_TARGET_READ: u64 = 1;
+const BPS_SOURCE_COPY: u64 = 2;
+
+#[derive(Debug)]
+pub enum PatchError {
+    Io(io::Error),
+    UnknownFormat,
+    Truncated,
+    Malformed,
+    CopyOutOfRange(usize),
+    SourceSizeMismatch { expected: usize, actual: usize },
+    SourceCrcMismatch { expected: u32, actual: u32 },
+    TargetCrcMismatch { expected: u32, actual: u32 },
+    PatchCrcMismatch { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatchError::Io(ref e) => write!(f, "{}", e),
+            PatchError::UnknownFormat => write!(f, "Not an IPS or BPS patch"),
+            PatchError::Truncated => write!(f, "Patch is truncated"),
+            PatchError::Malformed => write!(f, "Patch is malformed"),
+            PatchError::CopyOutOfRange(offset) => write!(f, "Patch copies from outside the ROM at offset 0x{:x}", offset),
+            PatchError::SourceSizeMismatch { expected, actual } => {
+                write!(f, "Patch is for a {} byte ROM, this one is {} bytes", expected, actual)
+            }
+            PatchError::SourceCrcMismatch { expected, actual } => {
+                write!(f, "Patch is for a ROM with CRC-32 {:08x}, this one is {:08x}", expected, actual)
+            }
+            PatchError::TargetCrcMismatch { expected, actual } => {
+                write!(f, "Patched ROM has CRC-32 {:08x}, the patch expects {:08x}", actual, expected)
+            }
+            PatchError::PatchCrcMismatch { expected, actual } => {
+                write!(f, "Patch is corrupt (CRC-32 {:08x}, should be {:08x})", actual, expected)
+            }
+        }
+    }
+}
+
+impl error::Error for PatchError {}
+
+impl From<io::Error> for PatchError {
+    fn from(e: io::Error) -> PatchError {
+        PatchError::Io(e)
+    }
+}
+
+pub fn load_patch<P: AsRef<Path>>(file_name: P) -> Result<Vec<u8>, PatchError> {
+    let mut patch = Vec::new();
+    File::open(file_name)?.read_to_end(&mut patch)?;
+    Ok(patch)
+}
+
+// The format is told by the patch's magic rather than its file name.
+pub fn apply_patch(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(source, &patch[IPS_MAGIC.len()..])
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(source, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+// IPS is a list of records, each a 24-bit big endian offset and a 16-bit
+// length followed by that many bytes, or by a 16-bit count and a byte to
+// repeat when the length is zero. Records may write past the end, growing
+// the ROM. After the EOF marker there may be a 24-bit size to truncate to.
+fn apply_ips(source: &[u8], records: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut target = source.to_vec();
+    let mut reader = Reader { bytes: records, pos: 0 };
+
+    loop {
+        let offset = reader.take(3)?;
+        if offset == IPS_EOF {
+            break;
+        }
+        let offset = big_endian(offset);
+
+        let len = big_endian(reader.take(2)?);
+        let (len, data) = if len == 0 {
+            let count = big_endian(reader.take(2)?);
+            (count, None)
+        } else {
+            (len, Some(reader.take(len)?))
+        };
+
+        if target.len() < offset + len {
+            target.resize(offset + len, 0);
+        }
+
+        match data {
+            Some(data) => target[offset..offset + len].copy_from_slice(data),
+            None => {
+                let value = reader.take(1)?[0];
+                target[offset..offset + len].iter_mut().for_each(|byte| *byte = value);
+            }
+        }
+    }
+
+    if let Ok(size) = reader.take(3) {
+        target.truncate(big_endian(size));
+    }
+
+    Ok(target)
+}
+
+// BPS builds the target front to back from runs copied from the source at
+// the same offset, literal bytes in the patch, or bytes copied from
+// anywhere in the source or the target built so far. CRC-32s of the source,
+// target and the patch itself make sure it's applied to the right ROM.
+fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.len() < BPS_MAGIC.len() + BPS_FOOTER_SIZE {
+        return Err(PatchError::Truncated);
+    }
+
+    let footer_start = patch.len() - BPS_FOOTER_SIZE;
+    let mut footer = Reader { bytes: &patch[footer_start..], pos: 0 };
+    let source_crc32 = footer.word()?;
+    let target_crc32 = footer.word()?;
+    let patch_crc32 = footer.word()?;
+
+    let actual = crc32(&patch[..patch.len() - 4]);
+    if actual != patch_crc32 {
+        return Err(PatchError::PatchCrcMismatch { expected: patch_crc32, actual });
+    }
+
+    let mut reader = Reader { bytes: &patch[..footer_start], pos: BPS_MAGIC.len() };
+
+    let source_size = reader.number()? as usize;
+    if source_size != source.len() {
+        return Err(PatchError::SourceSizeMismatch { expected: source_size, actual: source.len() });
+    }
+
+    let actual = crc32(source);
+    if actual != source_crc32 {
+        return Err(PatchError::SourceCrcMismatch { expected: source_crc32, actual });
+    }
+
+    let target_size = reader.number()? as usize;
+    let metadata_size = reader.number()? as usize;
+    reader.take(metadata_size)?;
+
+    let mut target = Vec::new();
+    let mut source_offset = 0usize;
+    let mut target_offset = 0usize;
+
+    while !reader.is_empty() {
+        let action = reader.number()?;
+        let len = (action >> 2) as usize + 1;
+        if target.len().saturating_add(len) > target_size {
+            return Err(PatchError::Malformed);
+        }
+
+        match action & 3 {
+            BPS_SOURCE_READ => {
+                let offset = target.len();
+                let bytes = source.get(offset..offset.saturating_add(len)).ok_or(PatchError::CopyOutOfRange(offset))?;
+                target.extend_from_slice(bytes);
+            }
+            BPS_TARGET_READ => target.extend_from_slice(reader.take(len)?),
+            BPS_SOURCE_COPY => {
+                source_offset = reader.relative_offset(source_offset)?;
+                let bytes = source.get(source_offset..source_offset.saturating_add(len)).ok_or(PatchError::CopyOutOfRange(source_offset))?;
+                target.extend_from_slice(bytes);
+                source_offset += len;
+            }
+            _ => {
+                // The copy can overlap what it's producing, repeating a
+                // pattern, so it has to go a byte at a time.
+                target_offset = reader.relative_offset(target_offset)?;
+                if target_offset >= target.len() {
+                    return Err(PatchError::CopyOutOfRange(target_offset));
+                }
+                for _ in 0..len {
+                    let byte = target[target_offset];
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err(PatchError::Truncated);
+    }
+
+    let actual = crc32(&target);
+    if actual != target_crc32 {
+        return Err(PatchError::TargetCrcMismatch { expected: target_crc32, actual });
+    }
+
+    Ok(target)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let bytes = self.pos.checked_add(len).and_then(|end| self.bytes.get(self.pos..end)).ok_or(PatchError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn word(&mut self) -> Result<u32, PatchError> {
+        let bytes = self.take(4)?;
+        Ok((bytes[0] as u32) | ((bytes[1] as u32) << 8) | ((bytes[2] as u32) << 16) | ((bytes[3] as u32) << 24))
+    }
+
+    // BPS numbers are seven bits a byte, least significant first, with the
+    // last byte's top bit set. Each continuation also adds one, so every
+    // value has a single encoding.
+    fn number(&mut self) -> Result<u64, PatchError> {
+        let mut value = 0u64;
+        let mut shift = 1u64;
+
+        loop {
+            let byte = self.take(1)?[0];
+            value = (byte as u64 & 0x7f).checked_mul(shift).and_then(|bits| value.checked_add(bits)).ok_or(PatchError::Malformed)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or(PatchError::Malformed)?;
+            value = value.checked_add(shift).ok_or(PatchError::Malformed)?;
+        }
+    }
+
+    // A signed step from the previous copy offset: the low bit is the sign.
+    fn relative_offset(&mut self, offset: usize) -> Result<usize, PatchError> {
+        let step = self.number()?;
+        let distance = (step >> 1) as usize;
+
+        let new_offset = if step & 1 != 0 { offset.checked_sub(distance) } else { offset.checked_add(distance) };
+        new_offset.ok_or(PatchError::CopyOutOfRange(offset))
+    }
+}
+
+fn big_endian(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |value, &byte| (value << 8) | byte as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BPS_TARGET_COPY: u64 = 3;
+
+    fn bps_number(mut value: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let bits = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(bits | 0x80);
+                return bytes;
+            }
+            bytes.push(bits);
+            value -= 1;
+        }
+    }
+
+    fn bps_action(kind: u64, len: usize) -> Vec<u8> {
+        bps_number(((len as u64 - 1) << 2) | kind)
+    }
+
+    fn bps_relative_offset(step: isize) -> Vec<u8> {
+        bps_number(((step.unsigned_abs() as u64) << 1) | (step < 0) as u64)
+    }
+
+    fn word(value: u32) -> [u8; 4] {
+        [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+    }
+
+    // A patch from source to a target_size byte target built by actions,
+    // with the footer's CRC-32s given.
+    fn bps_with_crcs(source: &[u8], target_size: usize, actions: &[u8], source_crc32: u32, target_crc32: u32) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend_from_slice(&bps_number(source.len() as u64));
+        patch.extend_from_slice(&bps_number(target_size as u64));
+        patch.extend_from_slice(&bps_number(3));
+        patch.extend_from_slice(b"xml");
+        patch.extend_from_slice(actions);
+        patch.extend_from_slice(&word(source_crc32));
+        patch.extend_from_slice(&word(target_crc32));
+        let patch_crc32 = crc32(&patch);
+        patch.extend_from_slice(&word(patch_crc32));
+        patch
+    }
+
+    fn bps(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+        bps_with_crcs(source, target.len(), actions, crc32(source), crc32(target))
+    }
+
+    #[test]
+    fn ips_records_overwrite_and_grow() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xaa, 0xbb]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x02, 0xcc, 0xdd]);
+        patch.extend_from_slice(IPS_EOF);
+
+        let target = apply_patch(&[0; 4], &patch).unwrap();
+        assert_eq!(target, [0x00, 0xaa, 0xbb, 0x00, 0x00, 0x00, 0xcc, 0xdd]);
+    }
+
+    #[test]
+    fn ips_rle_records_repeat_a_byte() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x05, 0x7f]);
+        patch.extend_from_slice(IPS_EOF);
+
+        let target = apply_patch(&[1; 8], &patch).unwrap();
+        assert_eq!(target, [1, 1, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 1]);
+    }
+
+    #[test]
+    fn ips_truncates_to_the_size_after_eof() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x01, 0x42]);
+        patch.extend_from_slice(IPS_EOF);
+        patch.extend_from_slice(&[0x00, 0x00, 0x03]);
+
+        let target = apply_patch(&[0; 8], &patch).unwrap();
+        assert_eq!(target, [0x42, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn truncated_ips_records_are_rejected() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x04, 0x42]);
+
+        match apply_patch(&[0; 8], &patch) {
+            Err(PatchError::Truncated) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn unknown_formats_are_rejected() {
+        match apply_patch(&[0; 8], b"UPS1") {
+            Err(PatchError::UnknownFormat) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn bps_builds_the_target_from_every_kind_of_action() {
+        let source = b"0123456789";
+        let target = b"01xyxyxyx789";
+
+        let mut actions = bps_action(BPS_SOURCE_READ, 2);
+        actions.extend_from_slice(&bps_action(BPS_TARGET_READ, 2));
+        actions.extend_from_slice(b"xy");
+        // Copies from 2 while writing 4, so it repeats what it copies.
+        actions.extend_from_slice(&bps_action(BPS_TARGET_COPY, 5));
+        actions.extend_from_slice(&bps_relative_offset(2));
+        actions.extend_from_slice(&bps_action(BPS_SOURCE_COPY, 3));
+        actions.extend_from_slice(&bps_relative_offset(7));
+
+        assert_eq!(apply_patch(source, &bps(source, target, &actions)).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_offsets_are_relative_to_the_last_copy() {
+        let source = b"abcdef";
+        let target = b"defabc";
+
+        let mut actions = bps_action(BPS_SOURCE_COPY, 3);
+        actions.extend_from_slice(&bps_relative_offset(3));
+        actions.extend_from_slice(&bps_action(BPS_SOURCE_COPY, 3));
+        actions.extend_from_slice(&bps_relative_offset(-6));
+
+        assert_eq!(apply_patch(source, &bps(source, target, &actions)).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_crc_mismatches_are_rejected() {
+        let source = b"source";
+        let target = b"target";
+        let mut actions = bps_action(BPS_TARGET_READ, 6);
+        actions.extend_from_slice(target);
+
+        let mut corrupt = bps(source, target, &actions);
+        let last_action = corrupt.len() - BPS_FOOTER_SIZE - 1;
+        corrupt[last_action] ^= 1;
+        match apply_patch(source, &corrupt) {
+            Err(PatchError::PatchCrcMismatch { expected, actual }) => assert_ne!(expected, actual),
+            result => panic!("{:?}", result),
+        }
+
+        match apply_patch(b"SOURCE", &bps(source, target, &actions)) {
+            Err(PatchError::SourceCrcMismatch { expected, actual }) => {
+                assert_eq!(expected, crc32(source));
+                assert_eq!(actual, crc32(b"SOURCE"));
+            }
+            result => panic!("{:?}", result),
+        }
+
+        match apply_patch(source, &bps_with_crcs(source, target.len(), &actions, crc32(source), crc32(b"TARGET"))) {
+            Err(PatchError::TargetCrcMismatch { expected, actual }) => {
+                assert_eq!(expected, crc32(b"TARGET"));
+                assert_eq!(actual, crc32(target));
+            }
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn bps_for_another_size_of_rom_is_rejected() {
+        let source = b"source";
+        let patch = bps(source, b"", &[]);
+
+        match apply_patch(b"longer source", &patch) {
+            Err(PatchError::SourceSizeMismatch { expected, actual }) => assert_eq!((expected, actual), (6, 13)),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn bps_copies_out_of_range_are_rejected() {
+        let source = b"abc";
+        let mut actions = bps_action(BPS_SOURCE_COPY, 2);
+        actions.extend_from_slice(&bps_relative_offset(2));
+
+        match apply_patch(source, &bps(source, b"c?", &actions)) {
+            Err(PatchError::CopyOutOfRange(2)) => (),
+            result => panic!("{:?}", result),
+        }
+
+        let mut actions = bps_action(BPS_TARGET_COPY, 1);
+        actions.extend_from_slice(&bps_relative_offset(0));
+
+        match apply_patch(source, &bps(source, b"?", &actions)) {
+            Err(PatchError::CopyOutOfRange(0)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+}
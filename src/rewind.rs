@@ -0,0 +1,293 @@
+use std::collections::VecDeque;
+
+// The NVC runs at 20 MHz and the display at 50 Hz.
+pub const CYCLES_PER_FRAME: u64 = 400000;
+
+pub struct Snapshot {
+    pub instructions: u64,
+    pub cycles: u64,
+
+    // Empty for the newest snapshot, whose full state is kept alongside;
+    // every other one is stored as the difference from the next newer.
+    delta: Vec<u8>,
+}
+
+// A ring of save states taken every few frames. Consecutive states differ
+// in little more than the registers and a few RAM bytes, so only the newest
+// is kept whole and each older one as a run length encoded XOR against its
+// successor. Dropping the oldest is then free, and going back n snapshots
+// costs n deltas.
+pub struct RewindBuffer {
+    capacity: usize,
+    interval_cycles: u64,
+    next_capture: u64,
+    snapshots: VecDeque<Snapshot>,
+    newest_state: Vec<u8>,
+}
+
+impl RewindBuffer {
+    pub fn new(capacity: usize, interval_frames: u64) -> RewindBuffer {
+        RewindBuffer {
+            capacity: capacity.max(1),
+            interval_cycles: interval_frames.max(1) * CYCLES_PER_FRAME,
+            next_capture: 0,
+            snapshots: VecDeque::new(),
+            newest_state: Vec::new(),
+        }
+    }
+
+    pub fn is_due(&self, cycles: u64) -> bool {
+        cycles >= self.next_capture
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.newest_state.clear();
+        self.next_capture = 0;
+    }
+
+    // A snapshot at the same instruction as the newest one (the debugger
+    // having edited registers or memory in between) replaces it.
+    pub fn push(&mut self, instructions: u64, cycles: u64, state: Vec<u8>) {
+        if self.snapshots.back().is_some_and(|snapshot| snapshot.instructions == instructions) {
+            match self.snapshots.len() {
+                1 => self.clear(),
+                len => {
+                    self.truncate(len - 2);
+                }
+            }
+        }
+
+        if let Some(newest) = self.snapshots.back_mut() {
+            newest.delta = encode_delta(&self.newest_state, &state);
+        }
+
+        self.snapshots.push_back(Snapshot { instructions, cycles, delta: Vec::new() });
+        self.newest_state = state;
+
+        if self.snapshots.len() > self.capacity {
+            self.snapshots.pop_front();
+        }
+
+        self.next_capture = (cycles / self.interval_cycles + 1) * self.interval_cycles;
+    }
+
+    pub fn snapshots(&self) -> &VecDeque<Snapshot> {
+        &self.snapshots
+    }
+
+    // Index of the newest snapshot satisfying f.
+    pub fn latest<F: Fn(&Snapshot) -> bool>(&self, f: F) -> Option<usize> {
+        self.snapshots.iter().rposition(f)
+    }
+
+    // Drops every snapshot newer than index, returning its state, which
+    // becomes the newest.
+    pub fn truncate(&mut self, index: usize) -> Vec<u8> {
+        let mut state = self.newest_state.clone();
+        for snapshot in self.snapshots.iter().skip(index).rev().skip(1) {
+            state = apply_delta(&state, &snapshot.delta);
+        }
+
+        self.snapshots.truncate(index + 1);
+        if let Some(newest) = self.snapshots.back_mut() {
+            newest.delta.clear();
+            self.next_capture = (newest.cycles / self.interval_cycles + 1) * self.interval_cycles;
+        }
+        self.newest_state = state.clone();
+
+        state
+    }
+}
+
+// Encodes older as XOR runs against newer: the length of older, then pairs
+// of a count of unchanged bytes and a count of changed bytes followed by
+// those bytes XORed with newer's.
+fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let xor = |index: usize| older[index] ^ newer.get(index).cloned().unwrap_or(0);
+
+    let mut delta = Vec::new();
+    push_varint(&mut delta, older.len() as u64);
+
+    let mut pos = 0;
+    while pos < older.len() {
+        let run_start = pos;
+        while pos < older.len() && xor(pos) == 0 {
+            pos += 1;
+        }
+        let literal_start = pos;
+        while pos < older.len() && xor(pos) != 0 {
+            pos += 1;
+        }
+
+        push_varint(&mut delta, (literal_start - run_start) as u64);
+        push_varint(&mut delta, (pos - literal_start) as u64);
+        delta.extend((literal_start..pos).map(xor));
+    }
+
+    delta
+}
+
+fn apply_delta(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos) as usize;
+
+    let base = |index: usize| newer.get(index).cloned().unwrap_or(0);
+    let mut older = Vec::with_capacity(len);
+
+    while older.len() < len {
+        let unchanged = read_varint(delta, &mut pos) as usize;
+        let changed = read_varint(delta, &mut pos) as usize;
+
+        for _ in 0..unchanged {
+            older.push(base(older.len()));
+        }
+        for &byte in &delta[pos..pos + changed] {
+            older.push(base(older.len()) ^ byte);
+        }
+        pos += changed;
+    }
+
+    older
+}
+
+fn push_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = bytes[*pos];
+        *pos += 1;
+
+        value |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A save state sized stand in whose bytes differ a little with n, the
+    // way consecutive states do.
+    fn state(n: u8) -> Vec<u8> {
+        let mut state = vec![0x55; 1000];
+        state[0] = n;
+        state[500..504].copy_from_slice(&[n, n, n, n]);
+        state[999] = n.wrapping_mul(3);
+        state
+    }
+
+    fn buffer_with_states(count: u8) -> RewindBuffer {
+        let mut buffer = RewindBuffer::new(10, 1);
+        for n in 0..count {
+            buffer.push(n as u64 * 100, n as u64 * CYCLES_PER_FRAME, state(n));
+        }
+        buffer
+    }
+
+    #[test]
+    fn deltas_round_trip() {
+        let cases = [
+            (state(1), state(2)),
+            (state(1), state(1)),
+            (vec![], state(1)),
+            (state(1), vec![]),
+            (vec![1, 2, 3, 4, 5, 6], vec![1, 2, 3]),
+            (vec![1, 2, 3], vec![1, 2, 3, 4, 5, 6]),
+            // Runs longer than a single byte varint.
+            ((0..1000).map(|n| (n / 300) as u8).collect(), vec![0; 1000]),
+        ];
+
+        for (older, newer) in cases.iter() {
+            assert_eq!(&apply_delta(newer, &encode_delta(older, newer)), older);
+        }
+    }
+
+    #[test]
+    fn deltas_of_similar_states_are_small() {
+        assert!(encode_delta(&state(1), &state(2)).len() < 20);
+    }
+
+    #[test]
+    fn truncate_restores_each_snapshot() {
+        for index in 0..5 {
+            let mut buffer = buffer_with_states(5);
+            assert_eq!(buffer.truncate(index), state(index as u8));
+            assert_eq!(buffer.snapshots().len(), index + 1);
+
+            // The restored state is the newest now, and building on it
+            // still decodes the older ones.
+            buffer.push(1000, 10 * CYCLES_PER_FRAME, state(9));
+            assert_eq!(buffer.truncate(index), state(index as u8));
+            if index > 0 {
+                assert_eq!(buffer.truncate(index - 1), state(index as u8 - 1));
+            }
+        }
+    }
+
+    #[test]
+    fn oldest_snapshots_are_dropped_past_capacity() {
+        let mut buffer = RewindBuffer::new(3, 1);
+        for n in 0..5 {
+            buffer.push(n as u64, n as u64 * CYCLES_PER_FRAME, state(n));
+        }
+
+        assert_eq!(buffer.snapshots().len(), 3);
+        assert_eq!(buffer.snapshots()[0].instructions, 2);
+        assert_eq!(buffer.truncate(0), state(2));
+    }
+
+    #[test]
+    fn snapshot_at_the_same_instruction_replaces_the_newest() {
+        let mut buffer = buffer_with_states(3);
+        buffer.push(200, 2 * CYCLES_PER_FRAME, state(7));
+
+        assert_eq!(buffer.snapshots().len(), 3);
+        assert_eq!(buffer.truncate(2), state(7));
+        assert_eq!(buffer.truncate(1), state(1));
+    }
+
+    #[test]
+    fn replacing_the_only_snapshot() {
+        let mut buffer = buffer_with_states(1);
+        buffer.push(0, 0, state(7));
+
+        assert_eq!(buffer.snapshots().len(), 1);
+        assert_eq!(buffer.truncate(0), state(7));
+    }
+
+    #[test]
+    fn snapshots_fall_due_each_interval() {
+        let mut buffer = RewindBuffer::new(10, 5);
+        assert!(buffer.is_due(0));
+
+        buffer.push(0, 3 * CYCLES_PER_FRAME, state(0));
+        assert!(!buffer.is_due(5 * CYCLES_PER_FRAME - 1));
+        assert!(buffer.is_due(5 * CYCLES_PER_FRAME));
+
+        buffer.push(1, 7 * CYCLES_PER_FRAME, state(1));
+        buffer.truncate(0);
+        assert!(!buffer.is_due(5 * CYCLES_PER_FRAME - 1));
+        assert!(buffer.is_due(5 * CYCLES_PER_FRAME));
+    }
+
+    #[test]
+    fn latest_finds_the_newest_match() {
+        let buffer = buffer_with_states(5);
+        assert_eq!(buffer.latest(|snapshot| snapshot.instructions <= 250), Some(2));
+        assert_eq!(buffer.latest(|snapshot| snapshot.cycles > 10 * CYCLES_PER_FRAME), None);
+    }
+}
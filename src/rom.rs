@@ -1,40 +1,239 @@
+use elf::*;
+use crc32::*;
+use patch::*;
+use archive::*;
+
 use encoding::DecoderTrap;
 use encoding::all::WINDOWS_31J;
 use encoding::types::EncodingRef;
 
 use std::path::Path;
 use std::fs::File;
+use std::fmt;
+use std::error;
 
-use std::io::{self, Read, Error, ErrorKind};
+use std::io::{self, Read};
 
-use std::borrow::Cow;
+const MIN_ROM_SIZE: usize = 1024; // 1 Kb, the header and vectors with room to spare.
+const MAX_ROM_SIZE: usize = 16777216; // 16 Mb.
 
-use std::string::FromUtf8Error;
+// The header sits just below the interrupt vectors at the top of ROM.
+const HEADER_AREA_SIZE: usize = 0x220;
 
-const MAX_ROM_SIZE: usize = 16777216; // 16 Mb.
+const TITLE_LEN: usize = 0x14;
+const RESERVED_OFFSET: usize = 0x14;
+const MAKER_CODE_OFFSET: usize = 0x19;
+const GAME_CODE_OFFSET: usize = 0x1b;
+const VERSION_OFFSET: usize = 0x1f;
+
+const VECTOR_SIZE: usize = 16;
+
+const VECTORS: [(&str, u32); 13] = [
+    ("game pad", 0xfffffe00),
+    ("timer", 0xfffffe10),
+    ("cartridge", 0xfffffe20),
+    ("link", 0xfffffe30),
+    ("vip", 0xfffffe40),
+    ("float exception", 0xffffff60),
+    ("zero division", 0xffffff80),
+    ("illegal opcode", 0xffffff90),
+    ("trap 0-15", 0xffffffa0),
+    ("trap 16-31", 0xffffffb0),
+    ("address trap", 0xffffffc0),
+    ("duplexed exception", 0xffffffd0),
+    ("reset", 0xfffffff0),
+];
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    Elf(io::Error),
+    Archive(io::Error),
+    NoRomInArchive,
+    EntryNotFound(String),
+    TooSmall(usize),
+    TooLarge(usize),
+    SizeNotPowerOfTwo(usize),
+    ReservedBytesSet([u8; 5]),
+    InvalidMakerCode([u8; 2]),
+    InvalidGameCode([u8; 4]),
+    Patch(PatchError),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomError::Io(ref e) => write!(f, "{}", e),
+            RomError::Elf(ref e) => write!(f, "Invalid ELF file: {}", e),
+            RomError::Archive(ref e) => write!(f, "Invalid archive: {}", e),
+            RomError::NoRomInArchive => write!(f, "Archive has no .vb file"),
+            RomError::EntryNotFound(ref name) => write!(f, "Archive has no entry '{}'", name),
+            RomError::TooSmall(size) => write!(f, "ROM is {} bytes, smaller than the minimum of {}", size, MIN_ROM_SIZE),
+            RomError::TooLarge(size) => write!(f, "ROM is {} bytes, larger than the maximum of {}", size, MAX_ROM_SIZE),
+            RomError::SizeNotPowerOfTwo(size) => write!(f, "ROM size {} isn't a power of two", size),
+            RomError::ReservedBytesSet(ref bytes) => write!(f, "Reserved header bytes aren't zero: {:02x?}", bytes),
+            RomError::InvalidMakerCode(ref bytes) => write!(f, "Maker code isn't printable ASCII: {:02x?}", bytes),
+            RomError::InvalidGameCode(ref bytes) => write!(f, "Game code isn't printable ASCII: {:02x?}", bytes),
+            RomError::Patch(ref e) => write!(f, "Unable to apply patch: {}", e),
+        }
+    }
+}
+
+impl error::Error for RomError {}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> RomError {
+        RomError::Io(e)
+    }
+}
+
+// An interrupt or exception handler slot at the top of the address space.
+pub struct Vector {
+    pub name: &'static str,
+    pub addr: u32,
+    pub bytes: [u8; VECTOR_SIZE],
+}
+
+pub struct RomHeader {
+    pub title: String,
+    pub reserved: [u8; 5],
+    pub maker_code: [u8; 2],
+    pub game_code: [u8; 4],
+    pub version: u8,
+    pub vectors: Vec<Vector>,
+}
+
+impl RomHeader {
+    // Never fails: the title is decoded leniently, with any bytes that
+    // aren't Shift-JIS replaced, so a damaged header can still be shown.
+    // check() says whether it's sane.
+    fn parse(bytes: &[u8]) -> RomHeader {
+        let header = &bytes[bytes.len() - HEADER_AREA_SIZE..];
+
+        let encoding = WINDOWS_31J as EncodingRef;
+        let title = encoding.decode(&header[..TITLE_LEN], DecoderTrap::Replace)
+            .unwrap_or_default()
+            .trim_end_matches([' ', '\0'])
+            .to_string();
+
+        let mut reserved = [0; 5];
+        reserved.copy_from_slice(&header[RESERVED_OFFSET..MAKER_CODE_OFFSET]);
+        let mut maker_code = [0; 2];
+        maker_code.copy_from_slice(&header[MAKER_CODE_OFFSET..GAME_CODE_OFFSET]);
+        let mut game_code = [0; 4];
+        game_code.copy_from_slice(&header[GAME_CODE_OFFSET..VERSION_OFFSET]);
+
+        let rom_mask = (bytes.len() - 1) as u32;
+        let vectors = VECTORS.iter().map(|&(name, addr)| {
+            let offset = (addr & rom_mask) as usize;
+            let mut vector_bytes = [0; VECTOR_SIZE];
+            vector_bytes.copy_from_slice(&bytes[offset..offset + VECTOR_SIZE]);
+            Vector { name, addr, bytes: vector_bytes }
+        }).collect();
+
+        RomHeader {
+            title,
+            reserved,
+            maker_code,
+            game_code,
+            version: header[VERSION_OFFSET],
+            vectors,
+        }
+    }
+
+    pub fn check(&self) -> Result<(), RomError> {
+        if self.reserved.iter().any(|&byte| byte != 0) {
+            return Err(RomError::ReservedBytesSet(self.reserved));
+        }
+        if !self.maker_code.iter().all(|c| c.is_ascii_graphic() || *c == b' ') {
+            return Err(RomError::InvalidMakerCode(self.maker_code));
+        }
+        if !self.game_code.iter().all(|c| c.is_ascii_graphic() || *c == b' ') {
+            return Err(RomError::InvalidGameCode(self.game_code));
+        }
+
+        Ok(())
+    }
+
+    pub fn maker_code(&self) -> String {
+        String::from_utf8_lossy(&self.maker_code).into_owned()
+    }
+
+    pub fn game_code(&self) -> String {
+        String::from_utf8_lossy(&self.game_code).into_owned()
+    }
+}
 
 pub struct Rom {
-    bytes: Box<[u8]>
+    bytes: Box<[u8]>,
+    crc32: u32,
+    header: RomHeader,
 }
 
 impl Rom {
-    pub fn load<P: AsRef<Path>>(rom_file_name: P) -> io::Result<Rom> {
+    pub fn load<P: AsRef<Path>>(rom_file_name: P) -> Result<Rom, RomError> {
+        Rom::load_entry(rom_file_name, None)
+    }
+
+    // Zip and gzip archives are unpacked first. From a zip, the named entry
+    // is loaded, or without one the first .vb file.
+    pub fn load_entry<P: AsRef<Path>>(rom_file_name: P, entry_name: Option<&str>) -> Result<Rom, RomError> {
         let mut rom_buf = Vec::new();
         let mut rom_file = File::open(&rom_file_name)?;
 
         rom_file.read_to_end(&mut rom_buf)?;
 
-        let rom_size = rom_buf.len();
+        if is_gzip(&rom_buf) {
+            rom_buf = gunzip(&rom_buf, MAX_ROM_SIZE).map_err(RomError::Archive)?;
+        } else if is_zip(&rom_buf) {
+            rom_buf = match unzip(&rom_buf, entry_name, MAX_ROM_SIZE).map_err(RomError::Archive)? {
+                Some(bytes) => bytes,
+                None => match entry_name {
+                    Some(name) => return Err(RomError::EntryNotFound(name.to_string())),
+                    None => return Err(RomError::NoRomInArchive),
+                },
+            };
+        }
+
+        // ELF files from the toolchain are turned into the image a
+        // cartridge would hold.
+        if is_elf(&rom_buf) {
+            rom_buf = Elf::parse(rom_buf).and_then(|elf| elf.rom_image()).map_err(RomError::Elf)?;
+        }
+
+        Rom::from_bytes(rom_buf)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Rom, RomError> {
+        let size = bytes.len();
 
-        if rom_size > MAX_ROM_SIZE {
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid ROM size."));
+        if size < MIN_ROM_SIZE {
+            return Err(RomError::TooSmall(size));
+        }
+        if size > MAX_ROM_SIZE {
+            return Err(RomError::TooLarge(size));
+        }
+        if !size.is_power_of_two() {
+            return Err(RomError::SizeNotPowerOfTwo(size));
         }
 
         Ok(Rom {
-            bytes: rom_buf.into_boxed_slice()
+            crc32: crc32(&bytes),
+            header: RomHeader::parse(&bytes),
+            bytes: bytes.into_boxed_slice(),
         })
     }
 
+    // The patched image goes through the same checks as a loaded one, since
+    // an IPS patch can change the size.
+    pub fn patch<P: AsRef<Path>>(&self, patch_file_name: P) -> Result<Rom, RomError> {
+        let bytes = load_patch(patch_file_name)
+            .and_then(|patch| apply_patch(&self.bytes, &patch))
+            .map_err(RomError::Patch)?;
+
+        Rom::from_bytes(bytes)
+    }
+
     pub fn size(&self) -> usize {
         self.bytes.len()
     }
@@ -43,42 +242,104 @@ impl Rom {
         &self.bytes
     }
 
-    fn header_offset(&self) -> usize {
-        self.size() - 544
+    pub fn crc32(&self) -> u32 {
+        self.crc32
     }
 
-    pub fn name(&self) -> Result<String, Cow<'static, str>> {
-        let name_offset = self.header_offset();
-        let name_bytes = &self.bytes[name_offset..name_offset + 0x14];
-        let encoding = WINDOWS_31J as EncodingRef;
-        
-        encoding.decode(name_bytes, DecoderTrap::Strict)
+    pub fn header(&self) -> &RomHeader {
+        &self.header
     }
+}
 
-    pub fn maker_code(&self) -> Result<String, FromUtf8Error> {
-        let name_offset = self.header_offset();
-        let maker_code_offset = name_offset + 0x19;
-        let maker_code_bytes = &self.bytes[maker_code_offset..maker_code_offset + 2];
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let mut maker_code_vec = Vec::new();
-        maker_code_vec.extend_from_slice(maker_code_bytes);
+    // A blank ROM with a header written just below the vectors.
+    fn rom_with_header(size: usize, title: &[u8], reserved: [u8; 5], maker_code: &[u8; 2], game_code: &[u8; 4]) -> Vec<u8> {
+        let mut bytes = vec![0xff; size];
+        let header = size - HEADER_AREA_SIZE;
+        bytes[header..header + TITLE_LEN].iter_mut().for_each(|byte| *byte = b' ');
+        bytes[header..header + title.len()].copy_from_slice(title);
+        bytes[header + RESERVED_OFFSET..header + MAKER_CODE_OFFSET].copy_from_slice(&reserved);
+        bytes[header + MAKER_CODE_OFFSET..header + GAME_CODE_OFFSET].copy_from_slice(maker_code);
+        bytes[header + GAME_CODE_OFFSET..header + VERSION_OFFSET].copy_from_slice(game_code);
+        bytes[header + VERSION_OFFSET] = 1;
+        bytes
+    }
 
-        String::from_utf8(maker_code_vec)
+    fn valid_rom(size: usize) -> Vec<u8> {
+        rom_with_header(size, b"AVB TEST", [0; 5], b"01", b"VAVE")
     }
 
-    pub fn game_code(&self) -> Result<String, FromUtf8Error> {
-        let name_offset = self.header_offset();
-        let game_code_offset = name_offset + 0x1b;
-        let game_code_bytes = &self.bytes[game_code_offset..game_code_offset + 2];
-        
-        let mut game_code_vec = Vec::new();
-        game_code_vec.extend_from_slice(game_code_bytes);
+    #[test]
+    fn sizes_are_checked() {
+        match Rom::from_bytes(vec![0; MIN_ROM_SIZE / 2]) {
+            Err(RomError::TooSmall(size)) => assert_eq!(size, MIN_ROM_SIZE / 2),
+            _ => panic!("a half size ROM loaded"),
+        }
+        match Rom::from_bytes(vec![0; MAX_ROM_SIZE * 2]) {
+            Err(RomError::TooLarge(size)) => assert_eq!(size, MAX_ROM_SIZE * 2),
+            _ => panic!("a double size ROM loaded"),
+        }
+        match Rom::from_bytes(vec![0; MIN_ROM_SIZE * 3]) {
+            Err(RomError::SizeNotPowerOfTwo(size)) => assert_eq!(size, MIN_ROM_SIZE * 3),
+            _ => panic!("a ROM that isn't a power of two in size loaded"),
+        }
+
+        assert_eq!(Rom::from_bytes(valid_rom(MIN_ROM_SIZE)).unwrap().size(), MIN_ROM_SIZE);
+        assert_eq!(Rom::from_bytes(valid_rom(MAX_ROM_SIZE)).unwrap().size(), MAX_ROM_SIZE);
+    }
+
+    #[test]
+    fn header_is_parsed() {
+        let rom = Rom::from_bytes(valid_rom(0x10000)).unwrap();
+        let header = rom.header();
+
+        assert_eq!(header.title, "AVB TEST");
+        assert_eq!(header.maker_code(), "01");
+        assert_eq!(header.game_code(), "VAVE");
+        assert_eq!(header.version, 1);
+        assert!(header.check().is_ok());
+    }
 
-        String::from_utf8(game_code_vec)
+    #[test]
+    fn shift_jis_titles_are_decoded() {
+        // "テスト" in Shift-JIS.
+        let bytes = rom_with_header(MIN_ROM_SIZE, b"\x83\x65\x83\x58\x83\x67", [0; 5], b"01", b"VAVE");
+        assert_eq!(Rom::from_bytes(bytes).unwrap().header().title, "テスト");
     }
 
-    pub fn game_version(&self) -> u8 {
-        let name_offset = self.header_offset();
-        self.bytes[name_offset + 0x1f]
+    #[test]
+    fn vectors_are_read_from_the_top_of_rom() {
+        let mut bytes = valid_rom(0x10000);
+        bytes[0xfff0..0xfff4].copy_from_slice(&[0x00, 0xa8, 0x00, 0x00]);
+        let rom = Rom::from_bytes(bytes).unwrap();
+
+        let reset = rom.header().vectors.iter().find(|vector| vector.name == "reset").unwrap();
+        assert_eq!(reset.addr, 0xfffffff0);
+        assert_eq!(&reset.bytes[..4], &[0x00, 0xa8, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn check_rejects_damaged_headers() {
+        let header = |reserved, maker_code, game_code| {
+            Rom::from_bytes(rom_with_header(MIN_ROM_SIZE, b"TITLE", reserved, maker_code, game_code)).unwrap().header().check()
+        };
+
+        match header([0, 0, 1, 0, 0], b"01", b"VAVE") {
+            Err(RomError::ReservedBytesSet(bytes)) => assert_eq!(bytes, [0, 0, 1, 0, 0]),
+            _ => panic!("set reserved bytes weren't caught"),
+        }
+        match header([0; 5], b"0\x00", b"VAVE") {
+            Err(RomError::InvalidMakerCode(bytes)) => assert_eq!(&bytes, b"0\x00"),
+            _ => panic!("a bad maker code wasn't caught"),
+        }
+        match header([0; 5], b"01", b"VA\xffE") {
+            Err(RomError::InvalidGameCode(bytes)) => assert_eq!(&bytes, b"VA\xffE"),
+            _ => panic!("a bad game code wasn't caught"),
+        }
+
+        assert!(header([0; 5], b"  ", b"    ").is_ok());
     }
 }
@@ -0,0 +1,74 @@
+// A commercial release, identified by the CRC-32 of the whole ROM image.
+// Headers are often padded oddly or damaged in dumps, so the title here is
+// the one to show. The SRAM size is that of the battery backed RAM on the
+// cartridge, 0 for games without saves. Quirks describe any behaviour the
+// emulator has to special case for the game; none are known to be needed
+// by what's emulated so far.
+pub struct KnownRom {
+    pub crc32: u32,
+    pub title: &'static str,
+    pub region: &'static str,
+    pub sram_size: usize,
+    pub quirks: &'static [&'static str],
+}
+
+const fn known_rom(crc32: u32, title: &'static str, region: &'static str, sram_size: usize) -> KnownRom {
+    KnownRom { crc32, title, region, sram_size, quirks: &[] }
+}
+
+static KNOWN_ROMS: [KnownRom; 28] = [
+    known_rom(0xbb71b522, "3-D Tetris", "USA", 0),
+    known_rom(0xc9710a36, "Galactic Pinball", "Japan, USA", 0),
+    known_rom(0x2199af41, "Golf", "USA", 8192),
+    known_rom(0x3f2a5d3d, "T&E Virtual Golf", "Japan", 8192),
+    known_rom(0x83cb6a00, "Innsmouth no Yakata", "Japan", 0),
+    known_rom(0xa44de03c, "Jack Bros.", "USA", 0),
+    known_rom(0xcab61e8b, "Jack Bros. no Meiro de Hiihoo!", "Japan", 0),
+    known_rom(0xa47de78c, "Mario Clash", "Japan, USA", 0),
+    known_rom(0x7ce7460d, "Mario's Tennis", "Japan, USA", 0),
+    known_rom(0xdf4d56b4, "Nester's Funky Bowling", "USA", 8192),
+    known_rom(0x19bb2dfb, "Panic Bomber", "USA", 0),
+    known_rom(0x40498f5e, "Tobidase! Panibomb", "Japan", 0),
+    known_rom(0xaa10a7b4, "Red Alarm", "USA", 0),
+    known_rom(0x7e85c45d, "Red Alarm", "Japan", 0),
+    known_rom(0x44788197, "SD Gundam Dimension War", "Japan", 0),
+    known_rom(0xfa44402d, "Space Invaders Virtual Collection", "Japan", 8192),
+    known_rom(0x60895693, "Space Squash", "Japan", 0),
+    known_rom(0x36103000, "Teleroboxer", "Japan, USA", 0),
+    known_rom(0x3ccb67ae, "V-Tetris", "Japan", 0),
+    known_rom(0x4c32ba5e, "Vertical Force", "USA", 0),
+    known_rom(0x9e9b8b92, "Vertical Force", "Japan", 0),
+    known_rom(0x133e9372, "Virtual Boy Wario Land", "Japan, USA", 8192),
+    known_rom(0x20688279, "Virtual Bowling", "Japan", 8192),
+    known_rom(0x526cc969, "Virtual Fishing", "Japan", 8192),
+    known_rom(0x8989fe0a, "Virtual Lab", "Japan", 0),
+    known_rom(0x736b40d6, "Virtual League Baseball", "USA", 0),
+    known_rom(0x9ba8bb5e, "Virtual Pro Yakyuu '95", "Japan", 0),
+    known_rom(0x82a95e51, "Waterworld", "USA", 0),
+];
+
+pub fn identify(crc32: u32) -> Option<&'static KnownRom> {
+    KNOWN_ROMS.iter().find(|rom| rom.crc32 == crc32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_crcs_are_identified() {
+        let rom = identify(0x133e9372).unwrap();
+        assert_eq!(rom.title, "Virtual Boy Wario Land");
+        assert_eq!(rom.sram_size, 8192);
+
+        assert!(identify(0x00000000).is_none());
+        assert!(identify(0xdeadbeef).is_none());
+    }
+
+    #[test]
+    fn crcs_are_unique() {
+        for (i, rom) in KNOWN_ROMS.iter().enumerate() {
+            assert!(KNOWN_ROMS[i + 1..].iter().all(|other| other.crc32 != rom.crc32), "{} is listed twice", rom.title);
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use interconnect::*;
+use nvc::*;
+
+use std::path::Path;
+use std::fs::File;
+use std::io::{self, Read, Write, Error, ErrorKind};
+
+const MAGIC: &[u8] = b"AVBSTATE";
+
+// Bumped whenever a chunk's layout changes; older states are still read,
+// newer ones are refused.
+pub const SAVE_STATE_VERSION: u32 = 1;
+
+const CHUNK_NVC: &[u8; 4] = b"NVC ";
+const CHUNK_WRAM: &[u8; 4] = b"WRAM";
+const CHUNK_CYCLES: &[u8; 4] = b"CYCL";
+const CHUNK_GAME_PAD: &[u8; 4] = b"PAD ";
+
+const NUM_GPRS: usize = 32;
+const NUM_SYSTEM_REGISTERS: usize = 32;
+
+const FRAME_CALL: u8 = 0;
+const FRAME_TRAP: u8 = 1;
+
+// A state is the magic, version and the CRC-32 of the ROM it was taken
+// with, followed by a chunk (a four byte tag, a length and the data) per
+// component. Chunks a reader doesn't know are skipped, so components can be
+// added as they're emulated without breaking older states.
+//
+// Only what's emulated so far is saved: the CPU, work RAM, the cycle count
+// and the game pad. Cartridge SRAM, the VIP, the VSU and the timer aren't,
+// so loading a state leaves them as they were. Their chunks will be tagged
+// "SRAM", "VIP ", "VSU " and "TIMR" once they're added; those tags are
+// reserved.
+pub fn save_state(cpu: &Nvc, interconnect: &Interconnect) -> Vec<u8> {
+    let mut state = Vec::new();
+    state.extend_from_slice(MAGIC);
+    push_word(&mut state, SAVE_STATE_VERSION);
+    push_word(&mut state, interconnect.rom().crc32());
+
+    let mut nvc = Vec::new();
+    push_word(&mut nvc, cpu.reg_pc());
+    for index in 0..NUM_GPRS {
+        push_word(&mut nvc, cpu.reg_gpr(index));
+    }
+    for index in 0..NUM_SYSTEM_REGISTERS {
+        push_word(&mut nvc, cpu.reg_system(index));
+    }
+    push_word(&mut nvc, cpu.call_stack().len() as u32);
+    for frame in cpu.call_stack() {
+        let (kind, value) = match frame.kind {
+            FrameKind::Call => (FRAME_CALL, 0),
+            FrameKind::Trap(vector) => (FRAME_TRAP, vector),
+        };
+        nvc.push(kind);
+        push_word(&mut nvc, value);
+        push_word(&mut nvc, frame.call_site);
+        push_word(&mut nvc, frame.target);
+        push_word(&mut nvc, frame.return_addr);
+    }
+    push_chunk(&mut state, CHUNK_NVC, &nvc);
+
+    push_chunk(&mut state, CHUNK_WRAM, interconnect.wram());
+
+    push_chunk(&mut state, CHUNK_CYCLES, &interconnect.cycle_count().to_le_bytes());
+
+    push_chunk(&mut state, CHUNK_GAME_PAD, &interconnect.game_pad().to_le_bytes());
+
+    state
+}
+
+// The whole state is checked before anything is restored, so a bad one
+// leaves the machine as it was.
+pub fn load_state(state: &[u8], cpu: &mut Nvc, interconnect: &mut Interconnect) -> io::Result<()> {
+    let mut reader = Reader { bytes: state, pos: 0 };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(invalid_data("Not a save state."));
+    }
+
+    let version = reader.word()?;
+    if version > SAVE_STATE_VERSION {
+        return Err(invalid_data(&format!("Save state version {} is newer than this build supports.", version)));
+    }
+
+    let rom_crc32 = reader.word()?;
+    if rom_crc32 != interconnect.rom().crc32() {
+        let message = format!("Save state is for a different ROM (CRC-32 {:08x}, loaded ROM is {:08x}).",
+            rom_crc32, interconnect.rom().crc32());
+        return Err(invalid_data(&message));
+    }
+
+    let mut registers = None;
+    let mut wram = None;
+    let mut cycles = None;
+    let mut game_pad = None;
+
+    while !reader.is_empty() {
+        let tag = reader.take(4)?;
+        let len = reader.word()? as usize;
+        let mut chunk = Reader { bytes: reader.take(len)?, pos: 0 };
+
+        match tag {
+            tag if tag == CHUNK_NVC => registers = Some(read_nvc(&mut chunk)?),
+            tag if tag == CHUNK_WRAM => {
+                if len != interconnect.wram().len() {
+                    return Err(invalid_data("Save state has the wrong work RAM size."));
+                }
+                wram = Some(chunk.bytes);
+            }
+            tag if tag == CHUNK_CYCLES => {
+                let low = chunk.word()? as u64;
+                let high = chunk.word()? as u64;
+                cycles = Some(low | (high << 32));
+            }
+            tag if tag == CHUNK_GAME_PAD => {
+                let bytes = chunk.take(2)?;
+                game_pad = Some((bytes[0] as u16) | ((bytes[1] as u16) << 8));
+            }
+            _ => (),
+        }
+    }
+
+    let (pc, gprs, system_registers, call_stack) = registers.ok_or_else(|| invalid_data("Save state has no CPU state."))?;
+    let wram = wram.ok_or_else(|| invalid_data("Save state has no work RAM."))?;
+
+    cpu.set_reg_pc(pc);
+    for (index, &value) in gprs.iter().enumerate() {
+        cpu.set_reg_gpr(index, value);
+    }
+    for (index, &value) in system_registers.iter().enumerate() {
+        cpu.set_reg_system(index, value);
+    }
+    cpu.set_call_stack(call_stack);
+
+    interconnect.set_wram(wram);
+    if let Some(cycles) = cycles {
+        interconnect.set_cycle_count(cycles);
+    }
+    if let Some(game_pad) = game_pad {
+        interconnect.set_game_pad(game_pad);
+    }
+
+    Ok(())
+}
+
+pub fn save_state_file<P: AsRef<Path>>(file_name: P, cpu: &Nvc, interconnect: &Interconnect) -> io::Result<()> {
+    File::create(file_name)?.write_all(&save_state(cpu, interconnect))
+}
+
+pub fn load_state_file<P: AsRef<Path>>(file_name: P, cpu: &mut Nvc, interconnect: &mut Interconnect) -> io::Result<()> {
+    let mut state = Vec::new();
+    File::open(file_name)?.read_to_end(&mut state)?;
+
+    load_state(&state, cpu, interconnect)
+}
+
+type NvcState = (u32, Vec<u32>, Vec<u32>, Vec<Frame>);
+
+fn read_nvc(chunk: &mut Reader) -> io::Result<NvcState> {
+    let pc = chunk.word()?;
+    let gprs = (0..NUM_GPRS).map(|_| chunk.word()).collect::<io::Result<Vec<_>>>()?;
+    let system_registers = (0..NUM_SYSTEM_REGISTERS).map(|_| chunk.word()).collect::<io::Result<Vec<_>>>()?;
+
+    let frame_count = chunk.word()?;
+    let mut call_stack = Vec::new();
+    for _ in 0..frame_count {
+        let kind = chunk.take(1)?[0];
+        let value = chunk.word()?;
+        let kind = match kind {
+            FRAME_CALL => FrameKind::Call,
+            FRAME_TRAP => FrameKind::Trap(value),
+            _ => return Err(invalid_data("Save state has a bad call stack frame.")),
+        };
+
+        call_stack.push(Frame {
+            kind,
+            call_site: chunk.word()?,
+            target: chunk.word()?,
+            return_addr: chunk.word()?,
+        });
+    }
+
+    Ok((pc, gprs, system_registers, call_stack))
+}
+
+fn push_chunk(state: &mut Vec<u8>, tag: &[u8; 4], data: &[u8]) {
+    state.extend_from_slice(tag);
+    push_word(state, data.len() as u32);
+    state.extend_from_slice(data);
+}
+
+fn push_word(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self.bytes.get(self.pos..self.pos + len).ok_or_else(|| invalid_data("Truncated save state."))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn word(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok((bytes[0] as u32) | ((bytes[1] as u32) << 8) | ((bytes[2] as u32) << 16) | ((bytes[3] as u32) << 24))
+    }
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSW: usize = 5;
+
+    fn machine(rom_fill: u8) -> (Nvc, Interconnect) {
+        (Nvc::new(), Interconnect::with_rom_bytes(vec![rom_fill; 1024]))
+    }
+
+    fn busy_machine() -> (Nvc, Interconnect) {
+        let (mut cpu, mut interconnect) = machine(0);
+
+        cpu.set_reg_pc(0x07000010);
+        for index in 1..NUM_GPRS {
+            cpu.set_reg_gpr(index, index as u32 * 0x01010101);
+        }
+        cpu.set_reg_system(PSW, 0x00000005);
+        cpu.set_call_stack(vec![
+            Frame { kind: FrameKind::Call, call_site: 0x07000000, target: 0x07000100, return_addr: 0x07000004 },
+            Frame { kind: FrameKind::Trap(0xffffffa0), call_site: 0x07000104, target: 0xffffffa0, return_addr: 0x07000106 },
+        ]);
+
+        interconnect.write_word(0x05000000, 0xdeadbeef);
+        interconnect.write_byte(0x0500ffff, 0x42);
+        interconnect.set_cycle_count(0x0000_0001_2345_6789);
+        interconnect.set_game_pad(0x1234);
+
+        (cpu, interconnect)
+    }
+
+    fn assert_same_call_stack(actual: &[Frame], expected: &[Frame]) {
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert_eq!(actual.kind, expected.kind);
+            assert_eq!((actual.call_site, actual.target, actual.return_addr),
+                       (expected.call_site, expected.target, expected.return_addr));
+        }
+    }
+
+    #[test]
+    fn states_round_trip() {
+        let (cpu, interconnect) = busy_machine();
+        let state = save_state(&cpu, &interconnect);
+
+        let (mut loaded_cpu, mut loaded_interconnect) = machine(0);
+        load_state(&state, &mut loaded_cpu, &mut loaded_interconnect).unwrap();
+
+        assert_eq!(loaded_cpu.reg_pc(), 0x07000010);
+        for index in 0..NUM_GPRS {
+            assert_eq!(loaded_cpu.reg_gpr(index), cpu.reg_gpr(index));
+        }
+        for index in 0..NUM_SYSTEM_REGISTERS {
+            assert_eq!(loaded_cpu.reg_system(index), cpu.reg_system(index));
+        }
+        assert_same_call_stack(loaded_cpu.call_stack(), cpu.call_stack());
+
+        assert_eq!(loaded_interconnect.read_word(0x05000000), 0xdeadbeef);
+        assert_eq!(loaded_interconnect.read_byte(0x0500ffff), 0x42);
+        assert_eq!(loaded_interconnect.wram(), interconnect.wram());
+        assert_eq!(loaded_interconnect.cycle_count(), 0x0000_0001_2345_6789);
+        assert_eq!(loaded_interconnect.game_pad(), 0x1234);
+
+        assert_eq!(save_state(&loaded_cpu, &loaded_interconnect), state);
+    }
+
+    #[test]
+    fn unknown_chunks_are_skipped() {
+        let (cpu, interconnect) = busy_machine();
+        let mut state = save_state(&cpu, &interconnect);
+        // As a newer build would save the VIP.
+        push_chunk(&mut state, b"VIP ", &[1, 2, 3]);
+
+        let (mut loaded_cpu, mut loaded_interconnect) = machine(0);
+        load_state(&state, &mut loaded_cpu, &mut loaded_interconnect).unwrap();
+        assert_eq!(loaded_cpu.reg_pc(), 0x07000010);
+    }
+
+    #[test]
+    fn states_for_another_rom_are_rejected() {
+        let (cpu, interconnect) = busy_machine();
+        let state = save_state(&cpu, &interconnect);
+
+        let (mut other_cpu, mut other_interconnect) = machine(0xff);
+        let error = load_state(&state, &mut other_cpu, &mut other_interconnect).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().contains("different ROM"), "{}", error);
+
+        // Nothing is restored from a rejected state.
+        assert_eq!(other_cpu.reg_pc(), Nvc::new().reg_pc());
+        assert_eq!(other_interconnect.read_word(0x05000000), 0);
+    }
+
+    #[test]
+    fn bad_states_leave_the_machine_alone() {
+        let (cpu, interconnect) = busy_machine();
+        let state = save_state(&cpu, &interconnect);
+
+        let (mut loaded_cpu, mut loaded_interconnect) = machine(0);
+        assert!(load_state(&state[..state.len() - 1], &mut loaded_cpu, &mut loaded_interconnect).is_err());
+        assert!(load_state(b"NOTSTATE", &mut loaded_cpu, &mut loaded_interconnect).is_err());
+
+        let mut newer = state.clone();
+        newer[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(SAVE_STATE_VERSION + 1).to_le_bytes());
+        assert!(load_state(&newer, &mut loaded_cpu, &mut loaded_interconnect).is_err());
+
+        assert_eq!(loaded_interconnect.read_word(0x05000000), 0);
+        assert!(loaded_cpu.call_stack().is_empty());
+    }
+}
@@ -0,0 +1,107 @@
+use elf::*;
+
+use std::path::Path;
+use std::fs::File;
+use std::io::{self, Read, Write, BufWriter, Error, ErrorKind};
+use std::collections::HashMap;
+
+// Reads labels from either an ELF file's symbol table or a text file with a
+// `name address` pair per line. Addresses are hex, with or without 0x; a
+// leading '.' on names is optional and blank lines and lines starting with
+// '#' are skipped.
+pub fn load_symbols<P: AsRef<Path>>(file_name: P) -> io::Result<HashMap<String, u32>> {
+    let mut bytes = Vec::new();
+    File::open(file_name)?.read_to_end(&mut bytes)?;
+
+    if is_elf(&bytes) {
+        let elf = Elf::parse(bytes)?;
+        return Ok(elf.symbols()?.into_iter().map(|symbol| (symbol.name, symbol.value)).collect());
+    }
+
+    let text = String::from_utf8(bytes).map_err(|_| Error::new(ErrorKind::InvalidData, "Symbol file isn't text."))?;
+    let mut symbols = HashMap::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let parsed = match (fields.next(), fields.next(), fields.next()) {
+            (Some(name), Some(addr), None) => {
+                let name = name.trim_start_matches('.');
+                let addr = addr.trim_start_matches("0x").trim_start_matches('$');
+                u32::from_str_radix(addr, 16).ok().map(|addr| (name.to_string(), addr))
+            }
+            _ => None,
+        };
+
+        match parsed {
+            Some((name, addr)) => {
+                symbols.insert(name, addr);
+            }
+            None => {
+                let message = format!("Line {}: expected a name and an address: {}", index + 1, line);
+                return Err(Error::new(ErrorKind::InvalidData, message));
+            }
+        }
+    }
+
+    Ok(symbols)
+}
+
+// Writes the text format, sorted by address so the file diffs nicely.
+pub fn save_symbols<P: AsRef<Path>>(file_name: P, symbols: &HashMap<String, u32>) -> io::Result<()> {
+    let mut sorted = symbols.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|&(name, &addr)| (addr, name));
+
+    let mut out = BufWriter::new(File::create(file_name)?);
+    for (name, addr) in sorted {
+        writeln!(out, "{} 0x{:08x}", name, addr)?;
+    }
+
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn saved_symbols_load_back() {
+        let mut symbols = HashMap::new();
+        symbols.insert("start".to_string(), 0x07000000);
+        symbols.insert("vblank".to_string(), 0x07000120);
+        symbols.insert("buffer".to_string(), 0x05000000);
+
+        let path = env::temp_dir().join(format!("avb-symbols-test-{}.sym", process::id()));
+        save_symbols(&path, &symbols).unwrap();
+        let text = fs::read_to_string(&path);
+        let loaded = load_symbols(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(text.unwrap(), "buffer 0x05000000\nstart 0x07000000\nvblank 0x07000120\n");
+        assert_eq!(loaded.unwrap(), symbols);
+    }
+
+    #[test]
+    fn hand_written_symbols_are_parsed() {
+        let path = env::temp_dir().join(format!("avb-symbols-parse-test-{}.sym", process::id()));
+        fs::write(&path, "# Labels\n\n.start 7000000\n  loop $07000010  \n").unwrap();
+        let loaded = load_symbols(&path);
+        fs::write(&path, "start\n").unwrap();
+        let bad = load_symbols(&path);
+        fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded["start"], 0x07000000);
+        assert_eq!(loaded["loop"], 0x07000010);
+        assert!(bad.is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use instruction::*;
+use nvc::*;
+use disassembler::*;
+
+use std::fmt;
+use std::path::Path;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// Register state before an instruction runs, so the trace can list only what
+// the instruction changed.
+pub struct RegisterSnapshot {
+    gpr: [u32; 32],
+    psw: u32,
+}
+
+impl RegisterSnapshot {
+    pub fn capture(cpu: &Nvc) -> RegisterSnapshot {
+        let mut gpr = [0; 32];
+        for (index, reg) in gpr.iter_mut().enumerate() {
+            *reg = cpu.reg_gpr(index);
+        }
+
+        RegisterSnapshot {
+            gpr,
+            psw: cpu.reg_psw(),
+        }
+    }
+}
+
+// Writes one line per executed instruction in the disassembler's format,
+// followed by the registers it changed:
+//
+//   0x07000004 21bc0507      movhi 0x705, r1, r1 ; r1=0x07050000
+pub struct Tracer {
+    out: BufWriter<File>,
+    range: Option<(u32, u32)>,
+}
+
+impl Tracer {
+    pub fn create<P: AsRef<Path>>(path: P, range: Option<(u32, u32)>) -> io::Result<Tracer> {
+        let file = File::create(path)?;
+
+        Ok(Tracer {
+            out: BufWriter::new(file),
+            range,
+        })
+    }
+
+    pub fn is_traced(&self, addr: u32) -> bool {
+        self.range.is_none_or(|(start, end)| addr >= start && addr <= end)
+    }
+
+    pub fn trace(&mut self, instruction: &Instruction, before: &RegisterSnapshot, cpu: &Nvc) -> io::Result<()> {
+        let mut line = format_instruction(instruction, None);
+        let mut separator = " ; ";
+
+        for (index, &old_value) in before.gpr.iter().enumerate() {
+            let value = cpu.reg_gpr(index);
+            if value != old_value {
+                line.push_str(&format!("{}r{}=0x{:08x}", separator, index, value));
+                separator = " ";
+            }
+        }
+
+        if cpu.reg_psw() != before.psw {
+            line.push_str(&format!("{}psw=0x{:08x}", separator, cpu.reg_psw()));
+        }
+
+        writeln!(self.out, "{}", line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceRegister {
+    Gpr(usize),
+    Psw,
+}
+
+impl fmt::Display for TraceRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TraceRegister::Gpr(index) => write!(f, "r{}", index),
+            TraceRegister::Psw => write!(f, "psw"),
+        }
+    }
+}
+
+// One line of a trace: the pc of the instruction and register values after
+// it ran. Both our own traces and reference logs use the same layout: the
+// pc in hex first, then `name=value` for any of r0-r31 and psw, with values
+// in hex (0x optional). Anything else on the line, like disassembly, is
+// ignored, as are blank lines and lines starting with '#'.
+#[derive(Debug, Clone)]
+pub struct TraceEntry {
+    pub pc: u32,
+    pub registers: Vec<(TraceRegister, u32)>,
+}
+
+impl TraceEntry {
+    pub fn parse(line: &str) -> Result<Option<TraceEntry>, String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let mut tokens = line.split_whitespace();
+
+        let pc = tokens.next().and_then(parse_hex).ok_or_else(|| format!("Expected a pc: {}", line))?;
+
+        let mut registers = Vec::new();
+        for token in tokens {
+            if let Some((name, value)) = token.split_once('=') {
+                let register = match name.to_lowercase().as_str() {
+                    "psw" => TraceRegister::Psw,
+                    name => match name.strip_prefix('r').and_then(|n| n.parse::<usize>().ok()) {
+                        Some(index) if index < 32 => TraceRegister::Gpr(index),
+                        _ => return Err(format!("Unknown register {}: {}", name, line)),
+                    },
+                };
+                let value = parse_hex(value).ok_or_else(|| format!("Invalid value for {}: {}", name, line))?;
+
+                registers.push((register, value));
+            }
+        }
+
+        Ok(Some(TraceEntry { pc, registers }))
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(digits, 16).ok()
+}
+
+// Register values accumulated over a trace. Ours only logs changes, so a
+// register stays unknown until the first instruction that writes it.
+#[derive(Clone, Default)]
+pub struct TraceState {
+    gpr: [Option<u32>; 32],
+    psw: Option<u32>,
+}
+
+impl TraceState {
+    pub fn apply(&mut self, entry: &TraceEntry) {
+        for &(register, value) in entry.registers.iter() {
+            match register {
+                TraceRegister::Gpr(index) => self.gpr[index] = Some(value),
+                TraceRegister::Psw => self.psw = Some(value),
+            }
+        }
+    }
+
+    pub fn get(&self, register: TraceRegister) -> Option<u32> {
+        match register {
+            TraceRegister::Gpr(index) => self.gpr[index],
+            TraceRegister::Psw => self.psw,
+        }
+    }
+
+    // Registers known in both states whose values differ.
+    pub fn diff(&self, other: &TraceState) -> Vec<(TraceRegister, u32, u32)> {
+        (0..32).map(TraceRegister::Gpr)
+            .chain(Some(TraceRegister::Psw))
+            .filter_map(|register| match (self.get(register), other.get(register)) {
+                (Some(lhs), Some(rhs)) if lhs != rhs => Some((register, lhs, rhs)),
+                _ => None,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Access => "access",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+// Watches the inclusive address range start..=end; when value is set, only
+// accesses reading or writing exactly that value trigger it.
+#[derive(Debug, Clone)]
+pub struct Watchpoint {
+    pub start: u32,
+    pub end: u32,
+    pub kind: WatchKind,
+    pub value: Option<u32>,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, access: AccessKind, addr: u32, size: u32, value: u32) -> bool {
+        let kind_matches = matches!(
+            (self.kind, access),
+            (WatchKind::Access, _) | (WatchKind::Read, AccessKind::Read) | (WatchKind::Write, AccessKind::Write));
+
+        let start = self.start & 0x07ffffff;
+        let end = self.end & 0x07ffffff;
+        let addr = addr & 0x07ffffff;
+        let last = addr + size - 1;
+
+        kind_matches && addr <= end && last >= start && self.value.is_none_or(|v| v == value)
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} 0x{:08x}", self.kind, self.start)?;
+
+        if self.end != self.start {
+            write!(f, "-0x{:08x}", self.end)?;
+        }
+
+        if let Some(value) = self.value {
+            write!(f, " == 0x{:x}", value)?;
+        }
+
+        Ok(())
+    }
+}
+
+// For reads old_value and new_value are both the value read.
+#[derive(Debug, Clone, Copy)]
+pub struct WatchHit {
+    pub index: usize,
+    pub access: AccessKind,
+    pub addr: u32,
+    pub size: u32,
+    pub old_value: u32,
+    pub new_value: u32,
+}